@@ -37,6 +37,7 @@ pub fn config_services(cfg: &mut web::ServiceConfig) {
                     .service(
                         web::resource("")
                             .route(web::post().to(chat_room_controller::create_room))
+                            .route(web::put().to(chat_room_controller::update_room))
                     )
                     .service(
                         web::resource("")
@@ -46,6 +47,10 @@ pub fn config_services(cfg: &mut web::ServiceConfig) {
                         web::resource("/add-user")
                             .route(web::post().to(chat_room_controller::add_room_user))
                     )
+                    .service(
+                        web::resource("/remove-user")
+                            .route(web::post().to(chat_room_controller::remove_room_user))
+                    )
                     .service(
                         web::resource("/list/{app_id}")
                             .route(web::get().to(chat_room_controller::find_by_app_id))
@@ -73,10 +78,94 @@ pub fn config_services(cfg: &mut web::ServiceConfig) {
                         web::resource("")
                             .route(web::delete().to(message_controller::delete))
                     )
+                    .service(
+                        web::resource("/read")
+                            .route(web::post().to(message_controller::mark_read))
+                    )
+                    .service(
+                        web::resource("/threads/read")
+                            .route(web::post().to(message_controller::mark_thread_read))
+                    )
+                    .service(
+                        web::resource("/threads/unread")
+                            .route(web::get().to(message_controller::find_unread_threads))
+                    )
+                    .service(
+                        web::resource("/mentions")
+                            .route(web::get().to(message_controller::find_mentions))
+                    )
+                    .service(
+                        web::resource("/{msg_id}/reactions")
+                            .route(web::post().to(message_controller::add_reaction))
+                            .route(web::delete().to(message_controller::remove_reaction))
+                    )
+                    .service(
+                        web::resource("/{msg_id}/pin")
+                            .route(web::post().to(message_controller::pin))
+                            .route(web::delete().to(message_controller::unpin))
+                    )
+                    .service(
+                        web::resource("/{room_id}/pins")
+                            .route(web::get().to(message_controller::find_pins))
+                    )
+                    .service(
+                        web::resource("/{room_id}/threads/{msg_id}")
+                            .route(web::get().to(message_controller::find_thread))
+                    )
                     .service(
                         web::resource("/{room_id}")
                             .route(web::get().to(message_controller::find_by_room_id))
+                            .route(web::patch().to(message_controller::edit))
+                    )
+            )
+            .service(
+                web::scope("/attachments")
+                    .service(
+                        web::resource("")
+                            .route(web::post().to(attachment_controller::upload))
                     )
+                    .service(
+                        web::resource("/policies")
+                            .route(web::put().to(attachment_controller::save_policy))
+                    )
+                    .service(
+                        web::resource("/policies/{app_id}")
+                            .route(web::get().to(attachment_controller::find_policy))
+                    )
+                    .service(
+                        web::resource("/{attachment_id}/url")
+                            .route(web::get().to(attachment_controller::sign_url))
+                    )
+            )
+            .service(
+                web::scope("/retention")
+                    .service(
+                        web::resource("/policies")
+                            .route(web::put().to(retention_controller::save_policy))
+                    )
+                    .service(
+                        web::resource("/policies/{app_id}")
+                            .route(web::get().to(retention_controller::find_policy))
+                    )
+            )
+            .service(
+                web::scope("/search")
+                    .service(
+                        web::resource("/messages")
+                            .route(web::get().to(search_controller::search_messages))
+                    )
+            )
+            .service(
+                web::resource("/files/{attachment_id}")
+                    .route(web::get().to(attachment_controller::download))
+            )
+            .service(
+                web::resource("/files/{attachment_id}/thumbnail")
+                    .route(web::get().to(attachment_controller::download_thumbnail))
+            )
+            .service(
+                web::resource("/events")
+                    .route(web::get().to(event_controller::stream))
             )
     );
 }
\ No newline at end of file
@@ -18,12 +18,14 @@ pub const MESSAGE_TOKEN_MISSING: &str = "Token is missing";
 
 // Headers
 pub const AUTHORIZATION: &str = "Authorization";
+pub const LAST_EVENT_ID: &str = "Last-Event-ID";
 
 // Misc
 pub const EMPTY: &str = "";
 
 // ignore routes
-pub const IGNORE_ROUTES: [&str; 3] = ["/api/ping", "/api/auth/signup", "/api/auth/login"];
+// /api/files is authorized by the signed token in its query
+pub const IGNORE_ROUTES: [&str; 4] = ["/api/ping", "/api/auth/signup", "/api/auth/login", "/api/files"];
 
 // app user
 pub const MESSAGE_APP_USER_CREATED_SUCCESS: &str = "User created successfully";
@@ -32,8 +34,45 @@ pub const MESSAGE_APP_USER_NOT_CREATED: &str = "Can not created a user";
 
 pub const MESSAGE_MSG_CREATED_SUCCESS: &str = "Message created successfully";
 pub const MESSAGE_MSG_NOT_CREATED: &str = "Can not created a message";
+pub const MESSAGE_MSG_ALREADY_EXISTS: &str = "Message already exists";
 pub const MESSAGE_MSG_DELETED_SUCCESS: &str = "Message deleted successfully";
 pub const MESSAGE_MSG_NOT_DELETED: &str = "Can not deleted message";
+pub const MESSAGE_MSG_UPDATED_SUCCESS: &str = "Message updated successfully";
+pub const MESSAGE_MSG_NOT_UPDATED: &str = "Can not updated message";
+pub const MESSAGE_MSG_NOT_FOUND: &str = "Message not found";
+pub const MESSAGE_MSG_PARENT_NOT_FOUND: &str = "Replied message not found in this room";
+pub const MESSAGE_MSG_VOICE_ATTACHMENT_REQUIRED: &str = "Voice message needs an uploaded Ogg Opus attachment";
+pub const MESSAGE_MSG_EDIT_FORBIDDEN: &str = "Only the owner can edit a message";
+pub const MESSAGE_MSG_SYSTEM_NOT_EDITABLE: &str = "System messages can not be edited";
+pub const MESSAGE_MSG_EDIT_WINDOW_EXPIRED: &str = "Message can no longer be edited";
+pub const MESSAGE_REACTION_INVALID: &str = "Reaction must be a single emoji";
+pub const MESSAGE_REACTION_FORBIDDEN: &str = "Only room members can react to messages";
+pub const MESSAGE_REACTION_LIMIT_REACHED: &str = "Message has reached the maximum number of distinct reactions";
+pub const MESSAGE_PIN_FORBIDDEN: &str = "Only the room owner can pin messages";
+pub const MESSAGE_PIN_LIMIT_REACHED: &str = "Room has reached the maximum number of pinned messages";
+pub const MESSAGE_MSG_DELETE_FORBIDDEN: &str = "Only the owner or the room owner can delete a message for everyone";
+pub const MESSAGE_SEARCH_ROOM_FORBIDDEN: &str = "Only room members can search a room";
+
+// attachment
+pub const MESSAGE_ATTACHMENT_CREATED_SUCCESS: &str = "Attachment uploaded successfully";
+pub const MESSAGE_ATTACHMENT_NOT_CREATED: &str = "Can not upload attachment";
+pub const MESSAGE_ATTACHMENT_NOT_FOUND: &str = "Attachment not found";
+pub const MESSAGE_ATTACHMENT_MISSING_FIELDS: &str = "app_id, room_id, user_id and file are required";
+pub const MESSAGE_ATTACHMENT_FORBIDDEN: &str = "Only room members can access attachments of a room";
+pub const MESSAGE_ATTACHMENT_TOO_LARGE: &str = "Attachment is larger than the app allows";
+pub const MESSAGE_ATTACHMENT_FIELD_TOO_LARGE: &str = "Upload fields other than file are limited to 1KB";
+pub const MESSAGE_ATTACHMENT_TYPE_NOT_ALLOWED: &str = "Attachment type is not allowed for this app";
+pub const MESSAGE_ATTACHMENT_INVALID_IMAGE: &str = "Image could not be read";
+pub const MESSAGE_ATTACHMENT_INVALID_VOICE: &str = "Voice message must be Ogg Opus audio of at most 15 minutes";
+pub const MESSAGE_ATTACHMENT_INVALID_TOKEN: &str = "Download link is invalid or expired";
+pub const MESSAGE_RETENTION_POLICY_INVALID: &str = "Retention days and disappearing timers must be positive";
 
 pub const CHAT_ROOM_UPDATED_SUCCESS: &str = "Chat room updated successfully";
 pub const CHAT_ROOM_NOT_UPDATED: &str = "Can not update chat room";
+pub const CHAT_ROOM_NOT_FOUND: &str = "Chat room not found";
+pub const CHAT_ROOM_USER_NOT_FOUND: &str = "User is not a member of the chat room";
+pub const CHAT_ROOM_UPDATE_FORBIDDEN: &str = "Only the room owner can change the room";
+pub const CHAT_ROOM_REMOVE_USER_FORBIDDEN: &str = "Only the room owner can remove other users";
+pub const CHAT_ROOM_ADD_USER_FORBIDDEN: &str = "Only members of the room can add users";
+pub const CHAT_ROOM_OWNER_NOT_REMOVABLE: &str = "The room owner can not be removed";
+pub const CHAT_ROOM_DIRECT_NOT_RENAMABLE: &str = "Direct rooms can not be renamed";
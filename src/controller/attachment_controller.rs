@@ -0,0 +1,120 @@
+use crate::{constants, error::ServiceError, models::{
+    attachment::{AttachmentPolicy, DownloadQuery, NewAttachment, SignUrlQuery, ATTACHMENT_FIELD_MAX_SIZE, ATTACHMENT_UPLOAD_HARD_LIMIT},
+    response::ResponseBody,
+}, services::attachment_service, utils::media, AppState};
+use actix_multipart::Multipart;
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+// POST api/attachments, multipart fields app_id, room_id, user_id and file
+pub async fn upload(mut payload: Multipart, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let blob_store = data.lock().unwrap().blob_store.clone();
+    let mut fields: HashMap<String, String> = HashMap::new();
+    let mut file: Option<(Option<String>, String, web::BytesMut)> = None;
+    // across all fields, so repeated fields can't add up past the limit
+    let mut total: usize = 0;
+    while let Some(item) = payload.next().await {
+        let mut field = item?;
+        let disposition = field.content_disposition();
+        let name = disposition.as_ref().and_then(|disposition| disposition.get_name()).unwrap_or("").to_string();
+        let file_name = disposition.as_ref().and_then(|disposition| disposition.get_filename()).map(|file_name| file_name.to_string());
+        // without parameters such as charset
+        let content_type = format!("{}/{}", field.content_type().type_(), field.content_type().subtype());
+        let mut bytes = web::BytesMut::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk?;
+            total += chunk.len();
+            if total > ATTACHMENT_UPLOAD_HARD_LIMIT {
+                return Ok(ServiceError::new(StatusCode::PAYLOAD_TOO_LARGE, constants::MESSAGE_ATTACHMENT_TOO_LARGE.to_string()).response())
+            }
+            if name != "file" && bytes.len() + chunk.len() > ATTACHMENT_FIELD_MAX_SIZE {
+                return Ok(ServiceError::new(StatusCode::PAYLOAD_TOO_LARGE, constants::MESSAGE_ATTACHMENT_FIELD_TOO_LARGE.to_string()).response())
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        if name == "file" {
+            file = Some((file_name, content_type, bytes));
+        } else {
+            fields.insert(name, String::from_utf8_lossy(&bytes).to_string());
+        }
+    }
+    let app_id = fields.get("app_id").and_then(|app_id| app_id.parse::<i64>().ok());
+    let new = match (app_id, fields.remove("room_id"), fields.remove("user_id"), file) {
+        (Some(app_id), Some(room_id), Some(uploader), Some((file_name, content_type, bytes))) => NewAttachment {
+            app_id,
+            room_id,
+            uploader,
+            file_name,
+            content_type,
+            data: bytes.freeze(),
+        },
+        _ => return Ok(ServiceError::new(StatusCode::BAD_REQUEST, constants::MESSAGE_ATTACHMENT_MISSING_FIELDS.to_string()).response()),
+    };
+    match attachment_service::upload(new, &pool, &redis_pool, blob_store.as_ref()).await {
+        Ok(attachment) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_ATTACHMENT_CREATED_SUCCESS, attachment))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// GET api/attachments/{attachment_id}/url?user_id=
+pub async fn sign_url(attachment_id: web::Path<Uuid>, query: web::Query<SignUrlQuery>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    match attachment_service::sign_url(attachment_id.into_inner(), query.into_inner().user_id, &pool, &redis_pool).await {
+        Ok(signed_url) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, signed_url))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// GET api/files/{attachment_id}?token=
+pub async fn download(attachment_id: web::Path<Uuid>, query: web::Query<DownloadQuery>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let blob_store = data.lock().unwrap().blob_store.clone();
+    match attachment_service::download(attachment_id.into_inner(), query.into_inner().token, false, &pool, &redis_pool, blob_store.as_ref()).await {
+        Ok((attachment, bytes)) => Ok(HttpResponse::Ok()
+            .content_type(attachment.content_type.as_str())
+            .header("Cache-Control", "private, max-age=3600")
+            .header("ETag", format!("\"{}\"", &attachment.sha256))
+            .body(bytes)),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// GET api/files/{attachment_id}/thumbnail?token=
+pub async fn download_thumbnail(attachment_id: web::Path<Uuid>, query: web::Query<DownloadQuery>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let blob_store = data.lock().unwrap().blob_store.clone();
+    match attachment_service::download(attachment_id.into_inner(), query.into_inner().token, true, &pool, &redis_pool, blob_store.as_ref()).await {
+        Ok((attachment, bytes)) => Ok(HttpResponse::Ok()
+            .content_type(media::THUMBNAIL_CONTENT_TYPE)
+            .header("Cache-Control", "private, max-age=3600")
+            .header("ETag", format!("\"{}_thumb\"", &attachment.sha256))
+            .body(bytes)),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// GET api/attachments/policies/{app_id}
+pub async fn find_policy(app_id: web::Path<i64>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    match attachment_service::find_policy(app_id.into_inner(), &pool).await {
+        Ok(policy) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, policy))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// PUT api/attachments/policies
+pub async fn save_policy(policy: web::Json<AttachmentPolicy>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    match attachment_service::save_policy(policy.0, &pool).await {
+        Ok(policy) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, policy))),
+        Err(err) => Ok(err.response()),
+    }
+}
@@ -1,34 +1,59 @@
 use crate::{constants, models::{
-    chat_room::{CreateChatRoom, ADDChatRoomUser, DeleteRoom},
+    chat_room::{CreateChatRoom, ADDChatRoomUser, RemoveChatRoomUser, UpdateChatRoom, DeleteRoom},
     response::ResponseBody,
 }, services::chat_rooms_service, AppState};
 use actix_web::{web, Error, HttpResponse};
 use std::sync::Mutex;
 use r2d2_redis::redis::Commands;
 use crate::models::chat_room::{RedisRoom, RoomUser};
+use crate::models::event::{RoomEvent, EVENT_UNREAD_COUNT};
 use std::collections::HashMap;
 
 // POST api/chat-rooms
 pub async fn create_room(chat_room: web::Json<CreateChatRoom>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
     let pool = data.lock().unwrap().db.clone();
     let redis_pool = data.lock().unwrap().redis_db.clone();
-    match chat_rooms_service::create_room(chat_room.0, &pool, &redis_pool).await {
+    let kafka_producer = data.lock().unwrap().kafka_producer.clone();
+    match chat_rooms_service::create_room(chat_room.0, &pool, &redis_pool, &kafka_producer).await {
         Ok(room_id) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, room_id))),
         Err(err) => Ok(err.response()),
     }
 }
 
 
-// POST api/chat-rooms/add-user
+// POST api/chat-rooms/add-user, added_by has to be the room owner or a member
 pub async fn add_room_user(chat_room: web::Json<ADDChatRoomUser>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
     let pool = data.lock().unwrap().db.clone();
     let redis_pool = data.lock().unwrap().redis_db.clone();
-    match chat_rooms_service::add_room_user(chat_room.0, &pool, &redis_pool).await {
+    let kafka_producer = data.lock().unwrap().kafka_producer.clone();
+    match chat_rooms_service::add_room_user(chat_room.0, &pool, &redis_pool, &kafka_producer).await {
         Ok(room_id) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, room_id))),
         Err(err) => Ok(err.response()),
     }
 }
 
+// POST api/chat-rooms/remove-user
+pub async fn remove_room_user(room_user: web::Json<RemoveChatRoomUser>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let kafka_producer = data.lock().unwrap().kafka_producer.clone();
+    match chat_rooms_service::remove_room_user(room_user.0, &pool, &redis_pool, &kafka_producer).await {
+        Ok(room_id) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, room_id))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// PUT api/chat-rooms
+pub async fn update_room(room: web::Json<UpdateChatRoom>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let kafka_producer = data.lock().unwrap().kafka_producer.clone();
+    match chat_rooms_service::update_room(room.0, &pool, &redis_pool, &kafka_producer).await {
+        Ok(room_id) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::CHAT_ROOM_UPDATED_SUCCESS, room_id))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
 // GET api/chat-rooms/list/{app_id}
 pub async fn find_by_app_id(app_id: web::Path<String>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
     let pool = data.lock().unwrap().db.clone();
@@ -67,7 +92,8 @@ pub async fn find_by_room_type(info: web::Path<(i64, String, i8)>, data: web::Da
 
 // GET api/chat-rooms/unread-count/{app_id}/{room_id}/{user_id}
 pub async fn update_unread_count(info: web::Path<(i64, String, String)>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
-    let mut redis_conn = data.lock().unwrap().redis_db.get().unwrap();
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let mut redis_conn = redis_pool.get().unwrap();
     let app_id = info.0.clone();
     let room_id = info.1.clone();
     let user_id = info.2.clone();
@@ -81,6 +107,7 @@ pub async fn update_unread_count(info: web::Path<(i64, String, String)>, data: w
                     let mut r_user = user.clone();
                     if user.user_id == user_id {
                         r_user.unread_msg = 0;
+                        r_user.unread_mentions = 0;
                         room_users.push(r_user);
                     } else {
                         room_users.push(r_user)
@@ -95,6 +122,7 @@ pub async fn update_unread_count(info: web::Path<(i64, String, String)>, data: w
                 let room_str = serde_json::to_string(&r_room).unwrap_or("error".to_string());
                 if room_str != "error".to_string() {
                     redis_conn.set::<String, String, String>(room_key, room_str);
+                    RoomEvent::publish(&redis_pool, app_id, &room_id, &[user_id.clone()], EVENT_UNREAD_COUNT, json!({ "unread_msg": 0, "unread_mentions": 0 }));
                 }
             },
             Err(msg) => {
@@ -109,7 +137,8 @@ pub async fn update_unread_count(info: web::Path<(i64, String, String)>, data: w
 // DELETE api/chat-rooms
 pub async fn delete(room: web::Json<DeleteRoom>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
     let pool = data.lock().unwrap().db.clone();
-    match chat_rooms_service::delete_room(room.0, &pool).await {
+    let kafka_producer = data.lock().unwrap().kafka_producer.clone();
+    match chat_rooms_service::delete_room(room.0, &pool, &kafka_producer).await {
         Ok(message) => Ok(HttpResponse::Ok().json(ResponseBody::new(&message, constants::EMPTY))),
         Err(err) => Ok(err.response()),
     }
@@ -0,0 +1,54 @@
+use crate::{constants, models::{
+    event::{EventQuery, RoomEvent, EVENT_POLL_INTERVAL_MS, EVENT_KEEP_ALIVE_POLLS},
+}, AppState};
+use actix_web::{web, web::Bytes, Error, HttpRequest, HttpResponse};
+use actix_rt::time::delay_for;
+use futures::stream;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// GET api/events?app_id={app_id}&user_id={user_id}
+pub async fn stream(req: HttpRequest, query: web::Query<EventQuery>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let app_id = query.app_id;
+    let user_id = query.user_id.clone();
+    // resume after the last event the client saw, otherwise only stream new events
+    let last_event_id = match req.headers().get(constants::LAST_EVENT_ID)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|id| id.parse::<i64>().ok()) {
+        Some(id) => id,
+        None => RoomEvent::last_id(&redis_pool, app_id, &user_id).unwrap_or(0),
+    };
+
+    let events = stream::unfold(last_event_id, move |last_id| {
+        let redis_pool = redis_pool.clone();
+        let user_id = user_id.clone();
+        async move {
+            let mut idle_polls: u32 = 0;
+            loop {
+                match RoomEvent::find_after(&redis_pool, app_id, &user_id, last_id) {
+                    Ok(events) => {
+                        if let Some(last_event) = events.last() {
+                            let next_id = last_event.id;
+                            let body: String = events.iter().map(|event| event.to_sse()).collect();
+                            return Some((Ok::<Bytes, Error>(Bytes::from(body)), next_id));
+                        }
+                    },
+                    Err(msg) => {
+                        println!("RoomEvent::find_after Error {:?}", msg);
+                    }
+                }
+                idle_polls += 1;
+                if idle_polls >= EVENT_KEEP_ALIVE_POLLS {
+                    return Some((Ok::<Bytes, Error>(Bytes::from(": ping\n\n")), last_id));
+                }
+                delay_for(Duration::from_millis(EVENT_POLL_INTERVAL_MS)).await;
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(events))
+}
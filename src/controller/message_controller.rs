@@ -1,15 +1,20 @@
 use crate::{constants, models::{
-    messages::{AddMessage, DeleteMessage},
+    mention::MentionQuery,
+    messages::{AddMessage, DeleteMessage, EditMessage, EditMessageBody, MessageQuery, ReadMessage},
+    pin::{PinBody, PinMessage},
+    reaction::{ReactionBody, ReactMessage},
+    thread::{ReadThread, ThreadQuery, UnreadThreadsQuery},
     response::ResponseBody,
 }, services::message_service, AppState};
 use actix_web::{web, Error, HttpResponse};
 use std::sync::Mutex;
+use uuid::Uuid;
 
-// GET api/messages/{room_id}
-pub async fn find_by_room_id(room_id: web::Path<String>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+// GET api/messages/{room_id}?before=&after=&limit=&cursor=
+pub async fn find_by_room_id(room_id: web::Path<String>, query: web::Query<MessageQuery>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
     let pool = data.lock().unwrap().db.clone();
     let id = room_id.into_inner().parse::<String>().unwrap();
-    match message_service::find_by_room_id(id, &pool).await {
+    match message_service::find_by_room_id(id, query.into_inner(), &pool).await {
         Ok(messages) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, messages))),
         Err(err) => Ok(err.response()),
     }
@@ -18,8 +23,39 @@ pub async fn find_by_room_id(room_id: web::Path<String>, data: web::Data<Mutex<A
 // POST api/messages
 pub async fn add(msg: web::Json<AddMessage>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
     let pool = data.lock().unwrap().db.clone();
-    match message_service::add_msg(msg.0, &pool).await {
-        Ok(message) => Ok(HttpResponse::Ok().json(ResponseBody::new(&message, constants::EMPTY))),
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let kafka_producer = data.lock().unwrap().kafka_producer.clone();
+    match message_service::add_msg(msg.0, &pool, &redis_pool, &kafka_producer).await {
+        Ok((message, res_message)) => Ok(HttpResponse::Ok().json(ResponseBody::new(&message, res_message))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// PATCH api/messages/{msg_id}
+pub async fn edit(msg_id: web::Path<Uuid>, msg: web::Json<EditMessageBody>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let kafka_producer = data.lock().unwrap().kafka_producer.clone();
+    let edit_window_secs = data.lock().unwrap().message_edit_window_secs;
+    let body = msg.into_inner();
+    let msg = EditMessage {
+        msg_id: msg_id.into_inner(),
+        room_id: body.room_id,
+        user_id: body.user_id,
+        content: body.content,
+    };
+    match message_service::edit_msg(msg, &pool, &redis_pool, &kafka_producer, edit_window_secs).await {
+        Ok(res_message) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_MSG_UPDATED_SUCCESS, res_message))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// POST api/messages/read
+pub async fn mark_read(msg: web::Json<ReadMessage>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    match message_service::mark_read(msg.0, &pool, &redis_pool).await {
+        Ok(receipt) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, receipt))),
         Err(err) => Ok(err.response()),
     }
 }
@@ -27,8 +63,121 @@ pub async fn add(msg: web::Json<AddMessage>, data: web::Data<Mutex<AppState>>) -
 // DELETE api/messages
 pub async fn delete(msg: web::Json<DeleteMessage>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
     let pool = data.lock().unwrap().db.clone();
-    match message_service::delete_msg(msg.0, &pool).await {
-        Ok(message) => Ok(HttpResponse::Ok().json(ResponseBody::new(&message, constants::EMPTY))),
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let kafka_producer = data.lock().unwrap().kafka_producer.clone();
+    match message_service::delete_msg(msg.0, &pool, &redis_pool, &kafka_producer).await {
+        Ok(res_message) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_MSG_DELETED_SUCCESS, res_message))),
+        Err(err) => Ok(err.response()),
+    }
+}
+// GET api/messages/{room_id}/threads/{msg_id}?before=&limit=&user_id=&cursor=
+pub async fn find_thread(path: web::Path<(String, Uuid)>, query: web::Query<ThreadQuery>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    let (room_id, msg_id) = path.into_inner();
+    match message_service::find_thread(room_id, msg_id, query.into_inner(), &pool).await {
+        Ok(thread) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, thread))),
         Err(err) => Ok(err.response()),
     }
-}
\ No newline at end of file
+}
+
+// POST api/messages/threads/read
+pub async fn mark_thread_read(read: web::Json<ReadThread>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    match message_service::mark_thread_read(read.0, &pool).await {
+        Ok(_) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, constants::EMPTY))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// GET api/messages/threads/unread?room_id=&user_id=
+pub async fn find_unread_threads(query: web::Query<UnreadThreadsQuery>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    match message_service::find_unread_threads(query.into_inner(), &pool).await {
+        Ok(threads) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, threads))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// GET api/messages/mentions?app_id=&user_id=&before=&limit=&cursor=
+pub async fn find_mentions(query: web::Query<MentionQuery>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    match message_service::find_mentions(query.into_inner(), &pool).await {
+        Ok(mentions) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, mentions))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// POST api/messages/{msg_id}/reactions
+pub async fn add_reaction(msg_id: web::Path<Uuid>, body: web::Json<ReactionBody>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let body = body.into_inner();
+    let msg = ReactMessage {
+        msg_id: msg_id.into_inner(),
+        room_id: body.room_id,
+        user_id: body.user_id,
+        emoji: body.emoji,
+    };
+    match message_service::add_reaction(msg, &pool, &redis_pool).await {
+        Ok(reactions) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, reactions))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// DELETE api/messages/{msg_id}/reactions
+pub async fn remove_reaction(msg_id: web::Path<Uuid>, body: web::Json<ReactionBody>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let body = body.into_inner();
+    let msg = ReactMessage {
+        msg_id: msg_id.into_inner(),
+        room_id: body.room_id,
+        user_id: body.user_id,
+        emoji: body.emoji,
+    };
+    match message_service::remove_reaction(msg, &pool, &redis_pool).await {
+        Ok(reactions) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, reactions))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// GET api/messages/{room_id}/pins
+pub async fn find_pins(room_id: web::Path<String>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    match message_service::find_pins(room_id.into_inner(), &pool).await {
+        Ok(pins) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, pins))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// POST api/messages/{msg_id}/pin
+pub async fn pin(msg_id: web::Path<Uuid>, body: web::Json<PinBody>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let body = body.into_inner();
+    let msg = PinMessage {
+        msg_id: msg_id.into_inner(),
+        room_id: body.room_id,
+        user_id: body.user_id,
+    };
+    match message_service::pin_msg(msg, &pool, &redis_pool).await {
+        Ok(pin) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, pin))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// DELETE api/messages/{msg_id}/pin
+pub async fn unpin(msg_id: web::Path<Uuid>, body: web::Json<PinBody>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    let redis_pool = data.lock().unwrap().redis_db.clone();
+    let body = body.into_inner();
+    let msg = PinMessage {
+        msg_id: msg_id.into_inner(),
+        room_id: body.room_id,
+        user_id: body.user_id,
+    };
+    match message_service::unpin_msg(msg, &pool, &redis_pool).await {
+        Ok(_) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, constants::EMPTY))),
+        Err(err) => Ok(err.response()),
+    }
+}
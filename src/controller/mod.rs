@@ -2,4 +2,8 @@ pub mod account_controller;
 pub mod ping_controller;
 pub mod app_user_controller;
 pub mod chat_room_controller;
-pub mod message_controller;
\ No newline at end of file
+pub mod message_controller;
+pub mod event_controller;
+pub mod attachment_controller;
+pub mod search_controller;
+pub mod retention_controller;
\ No newline at end of file
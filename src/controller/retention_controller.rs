@@ -0,0 +1,24 @@
+use crate::{constants, models::{
+    retention::{RetentionPolicy, RetentionPolicyQuery},
+    response::ResponseBody,
+}, services::retention_service, AppState};
+use actix_web::{web, Error, HttpResponse};
+use std::sync::Mutex;
+
+// GET api/retention/policies/{app_id}?room_id=, the policy the room's messages are kept under
+pub async fn find_policy(app_id: web::Path<i64>, query: web::Query<RetentionPolicyQuery>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    match retention_service::find_policy(app_id.into_inner(), query.into_inner().room_id, &pool).await {
+        Ok(policy) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, policy))),
+        Err(err) => Ok(err.response()),
+    }
+}
+
+// PUT api/retention/policies
+pub async fn save_policy(policy: web::Json<RetentionPolicy>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    match retention_service::save_policy(policy.0, &pool).await {
+        Ok(policy) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, policy))),
+        Err(err) => Ok(err.response()),
+    }
+}
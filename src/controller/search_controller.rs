@@ -0,0 +1,15 @@
+use crate::{constants, models::{
+    response::ResponseBody,
+    search::SearchQuery,
+}, services::search_service, AppState};
+use actix_web::{web, Error, HttpResponse};
+use std::sync::Mutex;
+
+// GET api/search/messages?app_id=&user_id=&q=&room_id=&sender=&from=&to=&limit=&offset=
+pub async fn search_messages(query: web::Query<SearchQuery>, data: web::Data<Mutex<AppState>>) -> Result<HttpResponse, Error> {
+    let pool = data.lock().unwrap().db.clone();
+    match search_service::search_messages(query.into_inner(), &pool).await {
+        Ok(page) => Ok(HttpResponse::Ok().json(ResponseBody::new(constants::MESSAGE_OK, page))),
+        Err(err) => Ok(err.response()),
+    }
+}
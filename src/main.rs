@@ -46,6 +46,9 @@ use r2d2_redis::redis::Commands;
 pub struct AppState {
     db: Arc<config::db::Connection>,
     redis_db: r_r2d2::Pool<RedisConnectionManager>,
+    kafka_producer: utils::kafka_producer::EventProducer,
+    message_edit_window_secs: i64,
+    blob_store: Arc<dyn utils::blob_store::BlobStore>,
 }
 
 #[actix_rt::main]
@@ -68,25 +71,108 @@ async fn main() -> io::Result<()> {
     let kafka_brokers = env::var("KAFKA_BROKERS").expect("KAFKA_BROKERS not found.");
     let kafka_group_id = env::var("KAFKA_GROUP_ID").expect("KAFKA_GROUP_ID not found.");
     let kafka_topic = env::var("KAFKA_TOPIC").expect("KAFKA_TOPIC not found.");
+    let kafka_events_topic = env::var("KAFKA_EVENTS_TOPIC").unwrap_or("chat_events".to_string());
+    let kafka_producer = utils::kafka_producer::EventProducer::new(&kafka_brokers, &kafka_events_topic);
+
+    let ingest_backend = env::var("INGEST_BACKEND").unwrap_or(utils::ingest::INGEST_BACKEND_KAFKA.to_string());
+    let ingest_retry = utils::ingest::RetryPolicy {
+        max_retries: env::var("INGEST_MAX_RETRIES").ok().and_then(|v| v.parse::<u32>().ok()).unwrap_or(5),
+        backoff_ms: env::var("INGEST_RETRY_BACKOFF_MS").ok().and_then(|v| v.parse::<u64>().ok()).unwrap_or(500),
+    };
+    let ingest_concurrency = utils::ingest::ConcurrencyPolicy {
+        workers: env::var("INGEST_WORKERS").ok().and_then(|v| v.parse::<usize>().ok()).unwrap_or(4),
+        max_in_flight: env::var("INGEST_MAX_IN_FLIGHT").ok().and_then(|v| v.parse::<usize>().ok()).unwrap_or(100),
+    };
+    let redis_stream = env::var("INGEST_REDIS_STREAM").unwrap_or(kafka_topic.clone());
+    let redis_stream_config = utils::redis_stream_consumer::RedisStreamConfig {
+        dead_letter_stream: env::var("INGEST_REDIS_DEAD_LETTER_STREAM").unwrap_or(format!("{}_dead_letter", &redis_stream)),
+        group: env::var("INGEST_REDIS_GROUP").unwrap_or(kafka_group_id.clone()),
+        consumer: env::var("INGEST_REDIS_CONSUMER").unwrap_or("chat-api".to_string()),
+        stream: redis_stream,
+    };
+    let kafka_consumer_config = utils::kafka_consumer::ConsumerConfig {
+        dead_letter_topic: env::var("KAFKA_DEAD_LETTER_TOPIC").unwrap_or(format!("{}_dead_letter", &kafka_topic)),
+        brokers: kafka_brokers,
+        group_id: kafka_group_id,
+        input_topic: kafka_topic,
+    };
+
+    let message_edit_window_secs = env::var("MESSAGE_EDIT_WINDOW_SECS").ok().and_then(|v| v.parse::<i64>().ok()).unwrap_or(900);
+    let blob_store = utils::blob_store::from_env();
+
+    // presence over MQTT is only started when MQTT_HOST is set
+    let mqtt_host = env::var("MQTT_HOST").ok();
+    let mqtt_port = env::var("MQTT_PORT").ok().map(|port| port.parse::<u16>().expect("MQTT_PORT is not a valid port.")).unwrap_or(1883);
+    let mqtt_client_id = env::var("MQTT_CLIENT_ID").unwrap_or("rust-chat-server".to_string());
+    let mqtt_presence_topic = env::var("MQTT_PRESENCE_TOPIC").unwrap_or("presence".to_string());
 
     let pool = config::db::connect_db(&db_url);
+    if env::args().nth(1) == Some("migrate-message-buckets".to_string()) {
+        match executor::block_on(models::messages::Message::migrate_to_buckets(&pool)) {
+            Ok(migrated) => println!("migrated {} messages to bucketed partitions", migrated),
+            Err(err) => println!("message bucket migration failed: {}", err),
+        }
+        return Ok(());
+    }
+    // run with the server stopped, the running server holds the search index lock
+    if env::args().nth(1) == Some("reindex-search".to_string()) {
+        match executor::block_on(models::messages::Message::reindex_search(&pool)) {
+            Ok(indexed) => println!("indexed {} messages for search", indexed),
+            Err(err) => println!("search reindex failed: {}", err),
+        }
+        return Ok(());
+    }
+    // after the one-off commands, its background commits would publish a half-built reindex
+    utils::search_index::SearchIndex::start();
+    let retention_sweep_interval_secs = env::var("RETENTION_SWEEP_INTERVAL_SECS").ok().and_then(|v| v.parse::<u64>().ok()).unwrap_or(3600);
+    models::retention::Retention::start_sweeper(pool.clone(), blob_store.clone(), retention_sweep_interval_secs);
     let kafka_db_pool = pool.clone();
+    let kafka_redis_pool = r_pool.clone();
+    let consumer_kafka_producer = kafka_producer.clone();
     thread::spawn( move || {
-        println!("this is kafka thread");
+        println!("this is ingest thread");
+        let source: Box<dyn utils::ingest::IngestSource> = match ingest_backend.as_str() {
+            utils::ingest::INGEST_BACKEND_REDIS => Box::new(utils::redis_stream_consumer::RedisStreamSource::new(kafka_redis_pool.clone(), redis_stream_config)),
+            utils::ingest::INGEST_BACKEND_MEMORY => Box::new(utils::ingest::MemorySource::new()),
+            _ => Box::new(utils::kafka_consumer::KafkaSource::new(kafka_consumer_config, consumer_kafka_producer.clone())),
+        };
         executor::block_on(async move {
-            utils::kafka_consumer::start(
-                kafka_brokers,
-                kafka_group_id,
-                kafka_topic,
-                kafka_db_pool
+            utils::ingest::run(
+                source,
+                utils::kafka_handlers::HandlerContext {
+                    pool: kafka_db_pool,
+                    redis_pool: kafka_redis_pool,
+                    kafka_producer: consumer_kafka_producer,
+                    message_edit_window_secs,
+                },
+                utils::kafka_handlers::HandlerRegistry::default(),
+                ingest_retry,
+                ingest_concurrency
             ).await;
         });
     });
 
+    if let Some(mqtt_host) = mqtt_host {
+        let presence_redis_pool = r_pool.clone();
+        thread::spawn( move || {
+            utils::mqtt_presence::start(
+                mqtt_host,
+                mqtt_port,
+                mqtt_client_id,
+                mqtt_presence_topic,
+                presence_redis_pool
+            );
+        });
+    } else {
+        println!("MQTT_HOST not set, presence over MQTT is disabled");
+    }
+
     let data = web::Data::new(Mutex::new(AppState {
         db: pool.clone(),
-        redis_db: r_pool.clone()
-        
+        redis_db: r_pool.clone(),
+        kafka_producer: kafka_producer.clone(),
+        message_edit_window_secs,
+        blob_store,
     }));
     
     let sys = HttpServer::new(move || {
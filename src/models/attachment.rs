@@ -0,0 +1,308 @@
+use crate::{
+    config::db::{Connection, RedisPool},
+    constants,
+    models::{
+        common::DbQuery,
+        chat_room::ChatRoom,
+        user_token::KEY,
+    },
+    utils::{
+        blob_store::{self, BlobStore},
+        media,
+    },
+};
+use actix_web::web::Bytes;
+use cdrs::{
+    query::*,
+    types::{
+        from_cdrs::FromCDRSByName,
+        prelude::*,
+    }
+};
+use jsonwebtoken::{Header, Validation};
+use std::collections::HashMap;
+use std::result::Result;
+use uuid::Uuid;
+
+// table names
+pub const ATTACHMENT_TABLE_NAME: &str = "chat_room_attachments";
+pub const POLICY_TABLE_NAME: &str = "chat_app_attachment_policies";
+
+// uploads are read into memory, no larger request is accepted whatever the app policy says
+pub const ATTACHMENT_UPLOAD_HARD_LIMIT: usize = 100 * 1024 * 1024;
+// multipart fields other than the file, e.g. app_id, room_id and user_id
+pub const ATTACHMENT_FIELD_MAX_SIZE: usize = 1024;
+// used for apps without a row in chat_app_attachment_policies
+pub const ATTACHMENT_DEFAULT_MAX_SIZE: i64 = 25 * 1024 * 1024;
+pub const ATTACHMENT_DEFAULT_TYPES: [&str; 8] = ["image/jpeg", "image/png", "image/gif", "image/webp", "audio/ogg", "video/mp4", "application/pdf", "text/plain"];
+pub const ATTACHMENT_URL_TTL_SECS: i64 = 60 * 60;
+
+#[derive(Clone, Debug, TryFromRow, Serialize, Deserialize)]
+pub struct Attachment {
+    pub attachment_id: Uuid,
+    pub app_id: i64,
+    pub room_id: String,
+    pub uploader: String,
+    pub file_name: Option<String>,
+    pub content_type: String,
+    pub size: i64,
+    pub sha256: String,
+    #[serde(skip_serializing)]
+    pub storage_key: String,
+    // images only, of the upright image
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub blurhash: Option<String>,
+    #[serde(skip_serializing)]
+    pub thumbnail_key: Option<String>,
+    // voice messages only
+    pub duration_ms: Option<i64>,
+    pub waveform: Option<Vec<i32>>,
+    // set when the message it was sent with is deleted for everyone, it can't be fetched after
+    #[serde(skip_serializing)]
+    pub deleted_at: Option<i64>,
+    pub created_at: i64,
+}
+// attachment of a message in history, the links are signed for the user_id of the query
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttachmentView {
+    #[serde(flatten)]
+    pub attachment: Attachment,
+    pub url: Option<String>,
+    pub thumbnail_url: Option<String>,
+}
+#[derive(Clone, Debug, TryFromRow, Serialize, Deserialize)]
+pub struct AttachmentPolicy {
+    pub app_id: i64,
+    pub max_size: i64,
+    pub allowed_types: Vec<String>,
+}
+// built by the upload controller from the multipart fields
+pub struct NewAttachment {
+    pub app_id: i64,
+    pub room_id: String,
+    pub uploader: String,
+    pub file_name: Option<String>,
+    pub content_type: String,
+    pub data: Bytes,
+}
+// GET api/attachments/{attachment_id}/url query
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignUrlQuery {
+    pub user_id: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedUrl {
+    pub url: String,
+    pub thumbnail_url: Option<String>,
+    pub expires_at: i64,
+}
+// GET api/files/{attachment_id} query
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadQuery {
+    pub token: String,
+}
+// claims of the download token, only valid for one attachment and one room member
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttachmentToken {
+    pub iat: i64,
+    pub exp: i64,
+    pub attachment_id: Uuid,
+    pub user_id: String,
+}
+
+impl AttachmentPolicy {
+    pub fn default_for(app_id: i64) -> AttachmentPolicy {
+        AttachmentPolicy {
+            app_id,
+            max_size: ATTACHMENT_DEFAULT_MAX_SIZE,
+            allowed_types: ATTACHMENT_DEFAULT_TYPES.iter().map(|content_type| content_type.to_string()).collect(),
+        }
+    }
+
+    pub async fn find_by_app_id(conn: &Connection, app_id: i64) -> Result<AttachmentPolicy, String> {
+        match DbQuery::get_row(&conn, POLICY_TABLE_NAME, "app_id, max_size, allowed_types", "app_id=?", query_values!(app_id)).await {
+            Ok(row) => AttachmentPolicy::try_from_row(row).map_err(|err| format!("{:?}", err)),
+            Err(_) => Ok(Self::default_for(app_id)),
+        }
+    }
+
+    pub async fn save(conn: &Connection, policy: AttachmentPolicy) -> Result<AttachmentPolicy, String> {
+        DbQuery::upsert(&conn, POLICY_TABLE_NAME, "app_id, max_size, allowed_types", "?, ?, ?", query_values!("app_id" => policy.app_id, "max_size" => policy.max_size, "allowed_types" => policy.allowed_types.clone())).await?;
+        Ok(policy)
+    }
+}
+
+fn is_room_member(redis_pool: &RedisPool, app_id: i64, room_id: &str, user_id: &str) -> bool {
+    ChatRoom::find_room_user_ids(&redis_pool, app_id, room_id).iter().any(|room_user_id| room_user_id == user_id)
+}
+
+impl Attachment {
+    pub async fn find_by_ids(conn: &Connection, attachment_ids: Vec<Uuid>) -> Result<Vec<Attachment>, String> {
+        if attachment_ids.is_empty() {
+            return Ok(vec![])
+        }
+        let rows = DbQuery::get_rows(&conn, ATTACHMENT_TABLE_NAME, "*", "attachment_id IN ?", query_values!(attachment_ids)).await?;
+        let mut attachments: Vec<Attachment> = vec![];
+        for row in rows {
+            attachments.push(Attachment::try_from_row(row).map_err(|err| format!("{:?}", err))?)
+        }
+        Ok(attachments)
+    }
+
+    // Links are only signed when user_id is given, membership is checked again on download.
+    pub async fn find_views(conn: &Connection, attachment_ids: Vec<Uuid>, user_id: Option<&str>) -> Result<HashMap<Uuid, AttachmentView>, String> {
+        let mut views: HashMap<Uuid, AttachmentView> = HashMap::new();
+        for attachment in Self::find_by_ids(&conn, attachment_ids).await?.into_iter().filter(|attachment| attachment.deleted_at.is_none()) {
+            let signed_url = match user_id {
+                Some(user_id) => Some(Self::sign(&attachment, user_id.to_string())?),
+                None => None,
+            };
+            views.insert(attachment.attachment_id, AttachmentView {
+                url: signed_url.as_ref().map(|signed_url| signed_url.url.clone()),
+                thumbnail_url: signed_url.and_then(|signed_url| signed_url.thumbnail_url),
+                attachment,
+            });
+        }
+        Ok(views)
+    }
+
+    // attachments of deleted messages are left out
+    pub async fn find_by_id(conn: &Connection, attachment_id: Uuid) -> Result<Option<Attachment>, String> {
+        match DbQuery::get_row(&conn, ATTACHMENT_TABLE_NAME, "*", "attachment_id=?", query_values!(attachment_id)).await {
+            Ok(row) => Ok(Some(Attachment::try_from_row(row).map_err(|err| format!("{:?}", err))?).filter(|attachment| attachment.deleted_at.is_none())),
+            Err(_) => Ok(None),
+        }
+    }
+
+    // Called when the message the attachment was sent with is deleted for everyone.
+    pub async fn mark_deleted(conn: &Connection, attachment_id: Uuid) -> Result<(), String> {
+        DbQuery::exec_update(&conn, ATTACHMENT_TABLE_NAME, "deleted_at=?", "attachment_id=?", query_values!(chrono::Utc::now().timestamp_millis(), attachment_id)).await
+    }
+
+    // Called when the message the attachment was sent with expires. The blob is shared by every
+    // upload of the same file in the app, it's only deleted with the last attachment still served.
+    pub async fn purge(conn: &Connection, attachment_id: Uuid, blob_store: &dyn BlobStore) -> Result<(), String> {
+        let attachment = match DbQuery::get_row(&conn, ATTACHMENT_TABLE_NAME, "*", "attachment_id=?", query_values!(attachment_id)).await {
+            Ok(row) => Attachment::try_from_row(row).map_err(|err| format!("{:?}", err))?,
+            Err(_) => return Ok(()),
+        };
+        DbQuery::exec_delete(&conn, ATTACHMENT_TABLE_NAME, "attachment_id=?", query_values!(attachment_id)).await?;
+        let rows = DbQuery::get_rows(&conn, ATTACHMENT_TABLE_NAME, "*", "storage_key=?", query_values!(attachment.storage_key.clone())).await?;
+        for row in rows {
+            if Attachment::try_from_row(row).map_err(|err| format!("{:?}", err))?.deleted_at.is_none() {
+                return Ok(())
+            }
+        }
+        blob_store.delete(&attachment.storage_key).await?;
+        if let Some(thumbnail_key) = attachment.thumbnail_key {
+            blob_store.delete(&thumbnail_key).await?;
+        }
+        Ok(())
+    }
+
+    // Stores the file under its content hash, so the same file uploaded twice in an app is
+    // kept once. Images are stored without their metadata, next to a thumbnail. Only room
+    // members can upload into a room.
+    pub async fn create(conn: &Connection, new: NewAttachment, redis_pool: &RedisPool, blob_store: &dyn BlobStore) -> Result<Attachment, String> {
+        if !is_room_member(&redis_pool, new.app_id, &new.room_id, &new.uploader) {
+            return Err(constants::MESSAGE_ATTACHMENT_FORBIDDEN.to_string())
+        }
+        let policy = AttachmentPolicy::find_by_app_id(&conn, new.app_id).await?;
+        if new.data.len() as i64 > policy.max_size {
+            return Err(constants::MESSAGE_ATTACHMENT_TOO_LARGE.to_string())
+        }
+        if !policy.allowed_types.iter().any(|content_type| content_type == &new.content_type) {
+            return Err(constants::MESSAGE_ATTACHMENT_TYPE_NOT_ALLOWED.to_string())
+        }
+        let (data, content_type, image) = match media::is_image(&new.content_type) {
+            true => {
+                let image = media::process_image(&new.content_type, new.data).map_err(|err| {
+                    println!("can't process image, error - {:?}", &err);
+                    constants::MESSAGE_ATTACHMENT_INVALID_IMAGE.to_string()
+                })?;
+                (image.data.clone(), image.content_type.clone(), Some(image))
+            },
+            false => (new.data, new.content_type, None),
+        };
+        let voice = match media::is_voice(&content_type) {
+            true => Some(media::process_voice(&data).map_err(|err| {
+                println!("can't process voice message, error - {:?}", &err);
+                constants::MESSAGE_ATTACHMENT_INVALID_VOICE.to_string()
+            })?),
+            false => None,
+        };
+        let sha256 = blob_store::sha256_hex(&data);
+        let app_id = new.app_id;
+        let attachment = Attachment {
+            attachment_id: Uuid::new_v4(),
+            app_id: new.app_id,
+            room_id: new.room_id,
+            uploader: new.uploader,
+            file_name: new.file_name,
+            content_type,
+            size: data.len() as i64,
+            storage_key: format!("{}/{}", new.app_id, &sha256),
+            width: image.as_ref().map(|image| image.width),
+            height: image.as_ref().map(|image| image.height),
+            blurhash: image.as_ref().map(|image| image.blurhash.clone()),
+            thumbnail_key: image.as_ref().map(|_| format!("{}/{}_thumb", app_id, &sha256)),
+            duration_ms: voice.as_ref().map(|voice| voice.duration_ms),
+            waveform: voice.map(|voice| voice.waveform),
+            deleted_at: None,
+            sha256,
+            created_at: chrono::Utc::now().timestamp_millis(),
+        };
+        blob_store.put(&attachment.storage_key, &attachment.content_type, data).await?;
+        if let (Some(image), Some(thumbnail_key)) = (image, attachment.thumbnail_key.as_ref()) {
+            blob_store.put(thumbnail_key, media::THUMBNAIL_CONTENT_TYPE, image.thumbnail).await?;
+        }
+        DbQuery::insert(&conn, ATTACHMENT_TABLE_NAME, "attachment_id, app_id, room_id, uploader, file_name, content_type, size, sha256, storage_key, width, height, blurhash, thumbnail_key, duration_ms, waveform, created_at", "?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?", query_values!("attachment_id" => attachment.attachment_id, "app_id" => attachment.app_id, "room_id" => attachment.room_id.clone(), "uploader" => attachment.uploader.clone(), "file_name" => attachment.file_name.clone(), "content_type" => attachment.content_type.clone(), "size" => attachment.size, "sha256" => attachment.sha256.clone(), "storage_key" => attachment.storage_key.clone(), "width" => attachment.width, "height" => attachment.height, "blurhash" => attachment.blurhash.clone(), "thumbnail_key" => attachment.thumbnail_key.clone(), "duration_ms" => attachment.duration_ms, "waveform" => attachment.waveform.clone(), "created_at" => attachment.created_at)).await?;
+        Ok(attachment)
+    }
+
+    pub async fn sign_url(conn: &Connection, attachment_id: Uuid, user_id: String, redis_pool: &RedisPool) -> Result<SignedUrl, String> {
+        let attachment = Self::find_by_id(&conn, attachment_id).await?.ok_or(constants::MESSAGE_ATTACHMENT_NOT_FOUND.to_string())?;
+        if !is_room_member(&redis_pool, attachment.app_id, &attachment.room_id, &user_id) {
+            return Err(constants::MESSAGE_ATTACHMENT_FORBIDDEN.to_string())
+        }
+        Self::sign(&attachment, user_id)
+    }
+
+    // one token covers the file and its thumbnail
+    fn sign(attachment: &Attachment, user_id: String) -> Result<SignedUrl, String> {
+        let now = time::get_time().sec;
+        let claims = AttachmentToken {
+            iat: now,
+            exp: now + ATTACHMENT_URL_TTL_SECS,
+            attachment_id: attachment.attachment_id,
+            user_id,
+        };
+        let token = jsonwebtoken::encode(&Header::default(), &claims, &KEY).map_err(|err| format!("{:?}", err))?;
+        Ok(SignedUrl {
+            url: format!("/api/files/{}?token={}", attachment.attachment_id, &token),
+            thumbnail_url: attachment.thumbnail_key.as_ref().map(|_| format!("/api/files/{}/thumbnail?token={}", attachment.attachment_id, &token)),
+            expires_at: claims.exp * 1000,
+        })
+    }
+
+    // The token has to be unexpired, issued for this attachment, and its user still has to
+    // be in the room.
+    pub async fn download(conn: &Connection, attachment_id: Uuid, token: String, thumbnail: bool, redis_pool: &RedisPool, blob_store: &dyn BlobStore) -> Result<(Attachment, Bytes), String> {
+        let claims = jsonwebtoken::decode::<AttachmentToken>(&token, &KEY, &Validation::default())
+            .map_err(|_| constants::MESSAGE_ATTACHMENT_INVALID_TOKEN.to_string())?
+            .claims;
+        if claims.attachment_id != attachment_id {
+            return Err(constants::MESSAGE_ATTACHMENT_INVALID_TOKEN.to_string())
+        }
+        let attachment = Self::find_by_id(&conn, attachment_id).await?.ok_or(constants::MESSAGE_ATTACHMENT_NOT_FOUND.to_string())?;
+        if !is_room_member(&redis_pool, attachment.app_id, &attachment.room_id, &claims.user_id) {
+            return Err(constants::MESSAGE_ATTACHMENT_FORBIDDEN.to_string())
+        }
+        let data = match thumbnail {
+            true => blob_store.get(attachment.thumbnail_key.as_ref().ok_or(constants::MESSAGE_ATTACHMENT_NOT_FOUND.to_string())?).await?,
+            false => blob_store.get(&attachment.storage_key).await?,
+        };
+        Ok((attachment, data))
+    }
+}
@@ -1,7 +1,12 @@
 use crate::{
     config::db::Connection,
     constants,
-    models::common::{DbQuery},
+    models::{
+        common::{DbQuery},
+        event::{RoomEvent, EVENT_MEMBER_ADDED, EVENT_MEMBER_REMOVED, EVENT_ROOM_UPDATED},
+        messages::Message,
+        system_message::{SystemMessage, TEMPLATE_BANNER_CHANGED, TEMPLATE_BANNER_REMOVED, TEMPLATE_ROOM_CREATED, TEMPLATE_ROOM_RENAMED, TEMPLATE_USERS_ADDED, TEMPLATE_USER_LEFT, TEMPLATE_USER_REMOVED},
+    },
 };
 use cdrs::{
     query::*,
@@ -18,7 +23,7 @@ use cdrs::{
 use std::result::Result;
 use uuid::Uuid;
 use std::thread;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::config::db::RedisPool;
 use r2d2_redis::redis::Commands;
 
@@ -54,6 +59,8 @@ pub struct ResChatRoom {
     pub banner: Option<String>,
     pub about: Option<String>,
     pub unread_msg: Option<i32>,
+    // unread messages mentioning the user, counted separately from unread_msg
+    pub unread_mentions: Option<i32>,
     pub last_msg: Option<HashMap<String, String>>,
     pub users: Option<Vec<RoomUser>>,
     pub room_type: i8, // 1 = privet, 2 = group
@@ -84,7 +91,34 @@ pub struct CreateChatRoom {
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ADDChatRoomUser {
     pub room_id: String,
-    pub users: Vec<User>
+    pub users: Vec<User>,
+    // the room owner or a member, named in the users added system message
+    pub added_by: String
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RemoveChatRoomUser {
+    pub room_id: String,
+    pub user_id: String,
+    // the room owner, or user_id itself to leave the room
+    pub removed_by: String
+}
+
+// PUT api/chat-rooms body, fields left out keep their value, a null banner or about clears it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UpdateChatRoom {
+    pub room_id: String,
+    pub user_id: String,
+    pub room_name: Option<String>,
+    #[serde(default, deserialize_with = "clearable")]
+    pub banner: Option<Option<String>>,
+    #[serde(default, deserialize_with = "clearable")]
+    pub about: Option<Option<String>>
+}
+
+// Some(None) for an explicit null, a left out field stays None through #[serde(default)]
+fn clearable<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Option<String>>, D::Error> {
+    <Option<String> as serde::Deserialize>::deserialize(deserializer).map(Some)
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -92,9 +126,17 @@ pub struct LastMessage {
     pub msg_owner: String,
     pub content: String
 }
+#[derive(Clone, Debug, TryFromRow)]
+pub struct RoomOwner {
+    pub room_owner: String
+}
+#[derive(Clone, Debug, TryFromRow)]
+pub struct RoomId {
+    pub room_id: String
+}
 #[derive(Clone, Serialize, Deserialize)]
 pub struct RoomID {
-    room_id: String
+    pub room_id: String
 }
 #[derive(Clone, Serialize, Deserialize)]
 pub struct DeleteRoom {
@@ -105,6 +147,9 @@ pub struct DeleteRoom {
 pub struct RoomUser {
     pub user_id: String,
     pub unread_msg: i32,
+    // missing in rooms stored before mentions existed
+    #[serde(default)]
+    pub unread_mentions: i32,
 }
 #[derive(Clone, Serialize, Deserialize)]
 pub struct RedisRoom {
@@ -160,6 +205,7 @@ impl ChatRoom {
                 banner: new_row.banner,
                 about: new_row.about,
                 unread_msg: None,
+                unread_mentions: None,
                 last_msg: None,
                 users: None,
                 room_type: new_row.room_type,
@@ -179,7 +225,8 @@ impl ChatRoom {
                         res_chat_room.last_msg = Some(last_msg);
                         for user in &roomData.users {
                             if user.user_id == user_id {
-                                res_chat_room.unread_msg = Some(user.unread_msg)
+                                res_chat_room.unread_msg = Some(user.unread_msg);
+                                res_chat_room.unread_mentions = Some(user.unread_mentions)
                             }
                         }
                         res_chat_room.users = Some(roomData.users);
@@ -210,6 +257,7 @@ impl ChatRoom {
                 banner: new_row.banner,
                 about: new_row.about,
                 unread_msg: None,
+                unread_mentions: None,
                 last_msg: None,
                 users: None,
                 room_type: new_row.room_type,
@@ -229,7 +277,8 @@ impl ChatRoom {
                         res_chat_room.last_msg = Some(last_msg);
                         for user in &roomData.users {
                             if user.user_id == user_id {
-                                res_chat_room.unread_msg = Some(user.unread_msg)
+                                res_chat_room.unread_msg = Some(user.unread_msg);
+                                res_chat_room.unread_mentions = Some(user.unread_mentions)
                             }
                         }
                         res_chat_room.users = Some(roomData.users);
@@ -261,7 +310,8 @@ impl ChatRoom {
             let thread_session = conn.clone();
             let room_user = RoomUser {
                 user_id: user.user_id.clone(),
-                unread_msg: 0
+                unread_msg: 0,
+                unread_mentions: 0
             };
             room_users.push(room_user);
             DbQuery::insert(&thread_session, TABLE_NAME, "app_id, room_id, app_user_id, room_name, room_owner, room_user_id, banner, about, room_type, is_private, status, updated_at, created_at", "?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 1, toTimestamp(now()), toTimestamp(now())", values).await.expect("thread error");
@@ -276,67 +326,187 @@ impl ChatRoom {
         let room_str = serde_json::to_string(&r_room).unwrap_or("error".to_string());
         // redis_conn.set(room_key, room_str).unwrap();
         redis_conn.set::<String, String, String>(room_key, room_str);
+        // direct rooms are named after the other member, there is nothing to announce
+        if chat_room.room_type == 2 {
+            let mut system = SystemMessage::new(TEMPLATE_ROOM_CREATED, &chat_room.room_owner).with("room_name", &chat_room.room_name);
+            if let Some(owner) = chat_room.users.iter().find(|user| user.user_id == chat_room.room_owner) {
+                system = system.with("actor_name", &owner.user_name);
+            }
+            if let Err(err) = Message::add_system_msg(&conn, chat_room.app_id, &room_id, system, &redis_pool).await {
+                println!("can't add room created system message, error - {:?}", &err);
+            }
+        }
         Ok(RoomID{room_id})
     }
 
-    pub async fn add_room_user(conn: &Connection, chat_room_user: ADDChatRoomUser, redis_pool: &RedisPool) -> Result<RoomID, String> {
-        let mut redis_conn = redis_pool.get().unwrap();
-        let mut room_new_users: Vec<RoomUser> = vec![];
+    // The one adding has to be the owner or a member. Returns the app of the room, its id and the
+    // users that weren't in the room yet, only they get the system message and events.
+    pub async fn add_room_user(conn: &Connection, chat_room_user: ADDChatRoomUser, redis_pool: &RedisPool) -> Result<(i64, RoomID, Vec<String>), String> {
         let room_id = chat_room_user.room_id.clone();
-        let row = DbQuery::get_row(&conn, TABLE_NAME, "*", "room_id=?", query_values!(chat_room_user.room_id)).await.expect("get user");
-        match ChatRoom::try_from_row(row) {
-            Ok(chat_room) => {
-                let add_user: CreateChatRoom = CreateChatRoom {
-                    app_id: chat_room.app_id,
-                    room_name: chat_room.room_name,
-                    room_owner: chat_room.room_owner,
-                    users: chat_room_user.users,
-                    banner: chat_room.banner,
-                    about: chat_room.about,
-                    room_type: chat_room.room_type,
-                    is_private: chat_room.is_private,
-                };
-                for user in &add_user.users {
-                    let tmp_chat_room = add_user.clone();
-                    let values = tmp_chat_room.into_query_values(room_id.clone(), user.user_id.clone());
-                    let thread_session = conn.clone();
-                    let room_user = RoomUser {
-                        user_id: user.user_id.clone(),
-                        unread_msg: 0
+        let row = match DbQuery::get_row(&conn, TABLE_NAME, "*", "room_id=?", query_values!(room_id.clone())).await {
+            Ok(row) => row,
+            Err(_) => return Err(constants::CHAT_ROOM_NOT_FOUND.to_string()),
+        };
+        let chat_room = ChatRoom::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+        let added_by = chat_room_user.added_by.clone();
+        if added_by != chat_room.room_owner {
+            let member = DbQuery::get_count(&conn, TABLE_NAME, "app_id=? AND room_user_id=?", query_values!(chat_room.app_id, format!("{}_{}", room_id, added_by))).await?;
+            if member == 0 {
+                return Err(constants::CHAT_ROOM_ADD_USER_FORBIDDEN.to_string())
+            }
+        }
+        let add_user: CreateChatRoom = CreateChatRoom {
+            app_id: chat_room.app_id,
+            room_name: chat_room.room_name,
+            room_owner: chat_room.room_owner,
+            users: chat_room_user.users,
+            banner: chat_room.banner,
+            about: chat_room.about,
+            room_type: chat_room.room_type,
+            is_private: chat_room.is_private,
+        };
+        let mut inserted_users: Vec<User> = vec![];
+        for user in &add_user.users {
+            let values = add_user.clone().into_query_values(room_id.clone(), user.user_id.clone());
+            // false when the user is already a member
+            if DbQuery::insert(&conn, TABLE_NAME, "app_id, room_id, app_user_id, room_name, room_owner, room_user_id, banner, about, room_type, is_private, unread_msg, status, updated_at, created_at", "?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 0, 1, toTimestamp(now()), toTimestamp(now())", values).await? {
+                inserted_users.push(user.clone());
+            }
+        }
+        let added_users: Vec<String> = inserted_users.iter().map(|user| user.user_id.clone()).collect();
+        if added_users.is_empty() {
+            return Ok((add_user.app_id, RoomID{room_id}, added_users))
+        }
+        let mut redis_conn = redis_pool.get().unwrap();
+        let room_key = format!("room_{:?}_{}", &chat_room.app_id, &room_id);
+        let room: String = redis_conn.get(room_key.clone()).unwrap_or("ROOM_NOT_FOUND".to_string());
+        if room != "ROOM_NOT_FOUND".to_string() {
+            match serde_json::from_str::<RedisRoom>(&room) {
+                Ok(roomData) => {
+                    let mut room_users: Vec<RoomUser> = roomData.users;
+                    for user_id in &added_users {
+                        room_users.push(RoomUser {
+                            user_id: user_id.clone(),
+                            unread_msg: 0,
+                            unread_mentions: 0
+                        });
+                    }
+                    let user_ids: Vec<String> = room_users.iter().map(|user| user.user_id.clone()).collect();
+                    let r_room = RedisRoom {
+                        last_msg: roomData.last_msg,
+                        send_at: roomData.send_at,
+                        app_id: roomData.app_id,
+                        users: room_users
                     };
-                    room_new_users.push(room_user);
-                    DbQuery::insert(&thread_session, TABLE_NAME, "app_id, room_id, app_user_id, room_name, room_owner, room_user_id, banner, about, room_type, is_private, unread_msg, status, updated_at, created_at", "?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 0, 1, toTimestamp(now()), toTimestamp(now())", values).await.expect("thread error");
+                    let room_str = serde_json::to_string(&r_room).unwrap_or("error".to_string());
+                    redis_conn.set::<String, String, String>(room_key, room_str);
+                    RoomEvent::publish(&redis_pool, chat_room.app_id, &room_id, &user_ids, EVENT_MEMBER_ADDED, json!({ "users": added_users }));
+                },
+                Err(msg) => {
+                    println!("serde_json::from_str::<Value> Error {:?}", msg);
                 }
-                let room_key = format!("room_{:?}_{}", &chat_room.app_id, &room_id);
-                let room: String = redis_conn.get(room_key.clone()).unwrap_or("ROOM_NOT_FOUND".to_string());
-                if room != "ROOM_NOT_FOUND".to_string() {
-                    match serde_json::from_str::<RedisRoom>(&room) {
-                        Ok(roomData) => {
-                            for user in roomData.users {
-                                room_new_users.push(user);
-                            }
-                            let r_room = RedisRoom {
-                                last_msg: roomData.last_msg,
-                                send_at: roomData.send_at,
-                                app_id: roomData.app_id,
-                                users: room_new_users
-                            };
-                            let room_str = serde_json::to_string(&r_room).unwrap_or("error".to_string());
-                            redis_conn.set::<String, String, String>(room_key, room_str);
-                        },
-                        Err(msg) => {
-                            println!("serde_json::from_str::<Value> Error {:?}", msg);
-                        }
+            }
+        }
+        let user_names: Vec<String> = inserted_users.iter().map(|user| user.user_name.clone()).collect();
+        let system = SystemMessage::new(TEMPLATE_USERS_ADDED, &added_by)
+            .with("user_ids", &added_users.join(","))
+            .with("user_names", &user_names.join(", "));
+        if let Err(err) = Message::add_system_msg(&conn, add_user.app_id, &room_id, system, &redis_pool).await {
+            println!("can't add users added system message, error - {:?}", &err);
+        }
+        Ok((add_user.app_id, RoomID{room_id}, added_users))
+    }
+    // The owner can remove anyone but themselves, other members only themselves.
+    pub async fn remove_room_user(conn: &Connection, room_user: RemoveChatRoomUser, redis_pool: &RedisPool) -> Result<(i64, RoomID), String> {
+        let row = match DbQuery::get_row(&conn, TABLE_NAME, "*", "room_id=?", query_values!(room_user.room_id.clone())).await {
+            Ok(row) => row,
+            Err(_) => return Err(constants::CHAT_ROOM_NOT_FOUND.to_string()),
+        };
+        let chat_room = ChatRoom::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+        if room_user.user_id == chat_room.room_owner {
+            return Err(constants::CHAT_ROOM_OWNER_NOT_REMOVABLE.to_string())
+        }
+        if room_user.removed_by != room_user.user_id && room_user.removed_by != chat_room.room_owner {
+            return Err(constants::CHAT_ROOM_REMOVE_USER_FORBIDDEN.to_string())
+        }
+        let room_user_id = format!("{}_{}", room_user.room_id, room_user.user_id);
+        if !DbQuery::delete(&conn, TABLE_NAME, "app_id=? AND room_user_id=? IF EXISTS", query_values!(chat_room.app_id, room_user_id)).await? {
+            return Err(constants::CHAT_ROOM_USER_NOT_FOUND.to_string())
+        }
+        // posted before the redis room drops the user, so they still get it
+        let system = if room_user.removed_by == room_user.user_id {
+            SystemMessage::new(TEMPLATE_USER_LEFT, &room_user.user_id)
+        } else {
+            SystemMessage::new(TEMPLATE_USER_REMOVED, &room_user.removed_by).with("user_id", &room_user.user_id)
+        };
+        if let Err(err) = Message::add_system_msg(&conn, chat_room.app_id, &room_user.room_id, system, &redis_pool).await {
+            println!("can't add user removed system message, error - {:?}", &err);
+        }
+        let user_ids = Self::find_room_user_ids(&redis_pool, chat_room.app_id, &room_user.room_id);
+        let mut redis_conn = redis_pool.get().unwrap();
+        let room_key = format!("room_{:?}_{}", &chat_room.app_id, &room_user.room_id);
+        let room: String = redis_conn.get(room_key.clone()).unwrap_or("ROOM_NOT_FOUND".to_string());
+        if room != "ROOM_NOT_FOUND".to_string() {
+            match serde_json::from_str::<RedisRoom>(&room) {
+                Ok(mut roomData) => {
+                    roomData.users.retain(|user| user.user_id != room_user.user_id);
+                    let room_str = serde_json::to_string(&roomData).unwrap_or("error".to_string());
+                    if room_str != "error".to_string() {
+                        redis_conn.set::<String, String, String>(room_key, room_str);
                     }
+                },
+                Err(msg) => {
+                    println!("serde_json::from_str::<Value> Error {:?}", msg);
                 }
-                return Ok(RoomID{room_id})
-            },
-            Err(ref err) => {
-                println!("can't insert, error - {:?}", &err);
-                return  Err(constants::CHAT_ROOM_NOT_UPDATED.to_string())
-            },
+            }
         }
-        Ok(RoomID{room_id})
+        RoomEvent::publish(&redis_pool, chat_room.app_id, &room_user.room_id, &user_ids, EVENT_MEMBER_REMOVED, json!({ "user_id": room_user.user_id, "removed_by": room_user.removed_by }));
+        Ok((chat_room.app_id, RoomID{room_id: room_user.room_id}))
+    }
+    // Only the room owner can change the room, direct rooms keep their per-member names.
+    pub async fn update_room(conn: &Connection, room: UpdateChatRoom, redis_pool: &RedisPool) -> Result<(i64, RoomID), String> {
+        let rows = DbQuery::get_rows(&conn, TABLE_NAME, "*", "room_id=?", query_values!(room.room_id.clone())).await?;
+        let mut members: Vec<ChatRoom> = vec![];
+        for row in rows {
+            members.push(ChatRoom::try_from_row(row).map_err(|err| format!("{:?}", err))?);
+        }
+        let chat_room = match members.first() {
+            Some(chat_room) => chat_room.clone(),
+            None => return Err(constants::CHAT_ROOM_NOT_FOUND.to_string()),
+        };
+        if chat_room.room_owner != room.user_id {
+            return Err(constants::CHAT_ROOM_UPDATE_FORBIDDEN.to_string())
+        }
+        if room.room_name.is_some() && chat_room.room_type == 1 {
+            return Err(constants::CHAT_ROOM_DIRECT_NOT_RENAMABLE.to_string())
+        }
+        let room_name = room.room_name.clone().unwrap_or(chat_room.room_name.clone());
+        let banner = room.banner.clone().unwrap_or(chat_room.banner.clone());
+        let about = room.about.clone().unwrap_or(chat_room.about.clone());
+        let updated_at = chrono::Utc::now().timestamp_millis();
+        for member in &members {
+            DbQuery::update(&conn, TABLE_NAME, "room_name=?, banner=?, about=?, updated_at=?", "app_id=? AND room_user_id=? IF EXISTS", query_values!(room_name.clone(), banner.clone(), about.clone(), updated_at, member.app_id, member.room_user_id.clone())).await?;
+        }
+        let user_ids = Self::find_room_user_ids(&redis_pool, chat_room.app_id, &room.room_id);
+        RoomEvent::publish(&redis_pool, chat_room.app_id, &room.room_id, &user_ids, EVENT_ROOM_UPDATED, json!({ "room_name": room_name, "banner": banner, "about": about }));
+        if room_name != chat_room.room_name {
+            let system = SystemMessage::new(TEMPLATE_ROOM_RENAMED, &room.user_id)
+                .with("old_room_name", &chat_room.room_name)
+                .with("room_name", &room_name);
+            if let Err(err) = Message::add_system_msg(&conn, chat_room.app_id, &room.room_id, system, &redis_pool).await {
+                println!("can't add room renamed system message, error - {:?}", &err);
+            }
+        }
+        if banner != chat_room.banner {
+            let system = match &banner {
+                Some(banner) => SystemMessage::new(TEMPLATE_BANNER_CHANGED, &room.user_id).with("banner", banner),
+                None => SystemMessage::new(TEMPLATE_BANNER_REMOVED, &room.user_id),
+            };
+            if let Err(err) = Message::add_system_msg(&conn, chat_room.app_id, &room.room_id, system, &redis_pool).await {
+                println!("can't add banner changed system message, error - {:?}", &err);
+            }
+        }
+        Ok((chat_room.app_id, RoomID{room_id: room.room_id}))
     }
     pub async fn delete_room(conn: &Connection, room: DeleteRoom) -> Result<String, String> {
         let rows = DbQuery::get_rows(&conn, TABLE_NAME, "*", "app_id=? AND room_id=?", query_values!("app_id" => room.app_id, "room_id" => room.room_id)).await.expect("get user");
@@ -348,6 +518,77 @@ impl ChatRoom {
 
         Ok(constants::MESSAGE_MSG_DELETED_SUCCESS.to_string())
     }
+    // rooms the user is a member of, without the redis room details
+    pub async fn find_room_ids(conn: &Connection, app_id: i64, user_id: &str) -> Result<Vec<String>, String> {
+        let app_user_id = format!("{:?}_{}", app_id, user_id);
+        let rows = DbQuery::get_rows(&conn, TABLE_NAME, "room_id", "app_id=? AND app_user_id=?", query_values!("app_id" => app_id, "app_user_id" => app_user_id)).await?;
+        let mut room_ids: Vec<String> = vec![];
+        for row in rows {
+            room_ids.push(RoomId::try_from_row(row).map_err(|err| format!("{:?}", err))?.room_id)
+        }
+        Ok(room_ids)
+    }
+    // every room of the app
+    pub async fn find_app_room_ids(conn: &Connection, app_id: i64) -> Result<Vec<String>, String> {
+        let rows = DbQuery::get_rows(&conn, TABLE_NAME, "room_id", "app_id=?", query_values!(app_id)).await?;
+        let mut room_ids: HashSet<String> = HashSet::new();
+        for row in rows {
+            room_ids.insert(RoomId::try_from_row(row).map_err(|err| format!("{:?}", err))?.room_id);
+        }
+        Ok(room_ids.into_iter().collect())
+    }
+    pub async fn find_room_owner(conn: &Connection, room_id: &str) -> Result<Option<String>, String> {
+        match DbQuery::get_row(&conn, TABLE_NAME, "room_owner", "room_id=?", query_values!(room_id.to_string())).await {
+            Ok(row) => Ok(Some(RoomOwner::try_from_row(row).map_err(|err| format!("{:?}", err))?.room_owner)),
+            Err(_) => Ok(None),
+        }
+    }
+    pub fn find_room_user_ids(redis_pool: &RedisPool, app_id: i64, room_id: &str) -> Vec<String> {
+        let mut redis_conn = redis_pool.get().unwrap();
+        let room_key = format!("room_{:?}_{}", &app_id, room_id);
+        let room: String = redis_conn.get(room_key).unwrap_or("ROOM_NOT_FOUND".to_string());
+        let mut user_ids: Vec<String> = vec![];
+        if room != "ROOM_NOT_FOUND".to_string() {
+            match serde_json::from_str::<RedisRoom>(&room) {
+                Ok(roomData) => {
+                    for user in roomData.users {
+                        user_ids.push(user.user_id);
+                    }
+                },
+                Err(msg) => {
+                    println!("serde_json::from_str::<Value> Error {:?}", msg);
+                }
+            }
+        }
+        user_ids
+    }
+    // Bumps unread_mentions of the given members, returns their new counts.
+    pub fn add_unread_mentions(redis_pool: &RedisPool, app_id: i64, room_id: &str, user_ids: &[String]) -> HashMap<String, i32> {
+        let mut redis_conn = redis_pool.get().unwrap();
+        let room_key = format!("room_{:?}_{}", &app_id, room_id);
+        let room: String = redis_conn.get(room_key.clone()).unwrap_or("ROOM_NOT_FOUND".to_string());
+        let mut counts: HashMap<String, i32> = HashMap::new();
+        if room != "ROOM_NOT_FOUND".to_string() {
+            match serde_json::from_str::<RedisRoom>(&room) {
+                Ok(mut roomData) => {
+                    for user in roomData.users.iter_mut() {
+                        if user_ids.contains(&user.user_id) {
+                            user.unread_mentions += 1;
+                            counts.insert(user.user_id.clone(), user.unread_mentions);
+                        }
+                    }
+                    let room_str = serde_json::to_string(&roomData).unwrap_or("error".to_string());
+                    if room_str != "error".to_string() {
+                        redis_conn.set::<String, String, String>(room_key, room_str);
+                    }
+                },
+                Err(msg) => {
+                    println!("serde_json::from_str::<Value> Error {:?}", msg);
+                }
+            }
+        }
+        counts
+    }
     pub async fn update_last_msg(conn: &Connection, last_msg: LastMessage, app_id: i64, room_id: String) -> Result<String, String> {
         let mut contacts = HashMap::new();
         contacts.insert("msg_owner", last_msg.msg_owner);
@@ -367,4 +608,19 @@ impl ChatRoom {
             },
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_tells_a_cleared_field_from_a_left_out_one() {
+        let update: UpdateChatRoom = serde_json::from_value(json!({ "room_id": "r1", "user_id": "u1", "banner": null, "about": "new" })).unwrap();
+        assert_eq!(update.banner, Some(None));
+        assert_eq!(update.about, Some(Some("new".to_string())));
+        let update: UpdateChatRoom = serde_json::from_value(json!({ "room_id": "r1", "user_id": "u1" })).unwrap();
+        assert_eq!(update.banner, None);
+        assert_eq!(update.about, None);
+    }
+}
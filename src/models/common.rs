@@ -12,7 +12,7 @@ use crate::config::CASSANDRA_DB_NAME;
 use actix_web::{web, Error as AWError};
 use failure::Error;
 use futures::{Future, TryFutureExt};
-use cdrs::types::{ByIndex};
+use cdrs::types::{ByIndex, CBytes};
 
 #[derive(Clone, Debug, TryFromRow)]
 pub struct CountStruct {
@@ -29,7 +29,7 @@ impl DbQuery {
                 let rows = result.get_body().expect("get body").into_rows().expect("into rows");
                 return Ok(rows)
             },
-            Err(ref err) => panic!("can't exec query {:?}", err),
+            Err(ref err) => Err(format!("can't exec query {:?}", err)),
         }
     }
 
@@ -54,6 +54,29 @@ impl DbQuery {
         Ok(rows)
 
     }
+    // One page in clustering order, with the paging state to continue after it when more rows exist.
+    pub async fn get_page(conn: &Connection, table_name: &str, select_string: &str, where_string: &str, where_value: QueryValues, page_size: i32, paging_state: Option<Vec<u8>>) -> Result<(Vec<Row>, Option<Vec<u8>>), String> {
+        let mut query = format!("SELECT {} FROM {}.{}", select_string, CASSANDRA_DB_NAME, table_name);
+        if !where_string.is_empty() {
+            query = format!("{} WHERE {}", query, where_string);
+        }
+        let prepared_query = conn.prepare(query).map_err(|err| format!("can't prepare query {:?}", err))?;
+        let mut params = QueryParamsBuilder::new().values(where_value).page_size(page_size);
+        if let Some(paging_state) = paging_state {
+            params = params.paging_state(CBytes::new(paging_state));
+        }
+        match conn.exec_with_params(&prepared_query, params.finalize()) {
+            Ok(result) => {
+                let body = result.get_body().map_err(|err| format!("can't read body {:?}", err))?;
+                let next_paging_state = body.as_rows_metadata()
+                    .and_then(|metadata| metadata.paging_state)
+                    .and_then(|paging_state| paging_state.into_plain());
+                let rows = body.into_rows().unwrap_or(vec![]);
+                Ok((rows, next_paging_state))
+            },
+            Err(ref err) => Err(format!("can't exec query {:?}", err)),
+        }
+    }
     pub async fn get_row(conn: &Connection, table_name: &str, select_string: &str, where_string: &str, where_value: QueryValues) -> Result<Row, String> {
         let query = format!("SELECT {} FROM {}.{} WHERE {}", select_string, CASSANDRA_DB_NAME, table_name, where_string);
         println!("get_row query ===== {} ", query);
@@ -65,10 +88,25 @@ impl DbQuery {
         }
         Err(format!("record not exist"))
     }
+    pub fn encode_paging_state(paging_state: &[u8]) -> String {
+        paging_state.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+    pub fn decode_paging_state(cursor: &str) -> Result<Vec<u8>, String> {
+        if cursor.len() % 2 != 0 {
+            return Err(format!("invalid cursor"))
+        }
+        (0..cursor.len()).step_by(2)
+            .map(|i| u8::from_str_radix(cursor.get(i..i + 2).unwrap_or(""), 16).map_err(|_| format!("invalid cursor")))
+            .collect()
+    }
     pub async fn insert(conn: &Connection, table_name: &str, fields_str: &str, values_str: &str, values: QueryValues) -> Result<bool, String> {
-        let query = format!("INSERT INTO {}.{} ({}) VALUES ({}) IF NOT EXISTS", CASSANDRA_DB_NAME, table_name, fields_str, values_str);
+        Self::insert_with_ttl(&conn, table_name, fields_str, values_str, values, None).await
+    }
+    // insert whose row expires after ttl_secs, None keeps it until deleted
+    pub async fn insert_with_ttl(conn: &Connection, table_name: &str, fields_str: &str, values_str: &str, values: QueryValues, ttl_secs: Option<i32>) -> Result<bool, String> {
+        let query = format!("INSERT INTO {}.{} ({}) VALUES ({}) IF NOT EXISTS{}", CASSANDRA_DB_NAME, table_name, fields_str, values_str, ttl_clause(ttl_secs));
          println!("insert query ===== {} ", query);
-        let prepared_query = conn.prepare(query).unwrap();
+        let prepared_query = conn.prepare(query).map_err(|err| format!("can't prepare query {:?}", err))?;
         let with_tracing = true;
         let with_warnings = true;
         match conn.exec_with_values_tw(&prepared_query, values, with_tracing, with_warnings) {
@@ -81,17 +119,39 @@ impl DbQuery {
                     Ok(is_inserted) => {
                         return Ok(is_inserted.unwrap())
                     },
-                    Err(ref err) => panic!("can't exec query {:?}", err),
+                    Err(ref err) => Err(format!("can't exec query {:?}", err)),
                 }
             },
-            Err(ref err) => panic!("can't exec query {:?}", err),
+            Err(ref err) => Err(format!("can't exec query {:?}", err)),
         }
-        Err(format!("can't insert"))
     }
-    pub async fn update(conn: &Connection, table_name: &str, update_fields_str: &str, where_string: &str, values: QueryValues) -> Result<bool, String> {
+    // plain INSERT without IF NOT EXISTS, for idempotent writes that don't need the LWT round trip
+    pub async fn upsert(conn: &Connection, table_name: &str, fields_str: &str, values_str: &str, values: QueryValues) -> Result<(), String> {
+        Self::upsert_with_ttl(&conn, table_name, fields_str, values_str, values, None).await
+    }
+    pub async fn upsert_with_ttl(conn: &Connection, table_name: &str, fields_str: &str, values_str: &str, values: QueryValues, ttl_secs: Option<i32>) -> Result<(), String> {
+        let query = format!("INSERT INTO {}.{} ({}) VALUES ({}){}", CASSANDRA_DB_NAME, table_name, fields_str, values_str, ttl_clause(ttl_secs));
+        let prepared_query = conn.prepare(query).map_err(|err| format!("can't prepare query {:?}", err))?;
+        conn.exec_with_values(&prepared_query, values)
+            .map(|_| ())
+            .map_err(|err| format!("can't exec query {:?}", err))
+    }
+    // UPDATE without IF conditions, e.g. counters, there is no applied flag to report
+    pub async fn exec_update(conn: &Connection, table_name: &str, update_fields_str: &str, where_string: &str, values: QueryValues) -> Result<(), String> {
         let query = format!("UPDATE {}.{} SET {} WHERE {}", CASSANDRA_DB_NAME, table_name, update_fields_str, where_string);
+        let prepared_query = conn.prepare(query).map_err(|err| format!("can't prepare query {:?}", err))?;
+        conn.exec_with_values(&prepared_query, values)
+            .map(|_| ())
+            .map_err(|err| format!("can't exec query {:?}", err))
+    }
+    pub async fn update(conn: &Connection, table_name: &str, update_fields_str: &str, where_string: &str, values: QueryValues) -> Result<bool, String> {
+        Self::update_with_ttl(&conn, table_name, update_fields_str, where_string, values, None).await
+    }
+    // cells written to a row with a TTL need one too, or they outlive the rest of the row
+    pub async fn update_with_ttl(conn: &Connection, table_name: &str, update_fields_str: &str, where_string: &str, values: QueryValues, ttl_secs: Option<i32>) -> Result<bool, String> {
+        let query = format!("UPDATE {}.{}{} SET {} WHERE {}", CASSANDRA_DB_NAME, table_name, ttl_clause(ttl_secs), update_fields_str, where_string);
         println!("update query ===== {} ", query);
-        let prepared_query = conn.prepare(query).unwrap();
+        let prepared_query = conn.prepare(query).map_err(|err| format!("can't prepare query {:?}", err))?;
         let with_tracing = true;
         let with_warnings = true;
         match conn.exec_with_values_tw(&prepared_query, values, with_tracing, with_warnings) {
@@ -104,17 +164,16 @@ impl DbQuery {
                     Ok(is_updated) => {
                         return Ok(is_updated.unwrap())
                     },
-                    Err(ref err) => panic!("can't exec query {:?}", err),
+                    Err(ref err) => Err(format!("can't exec query {:?}", err)),
                 }
             },
-            Err(ref err) => panic!("can't exec query {:?}", err),
+            Err(ref err) => Err(format!("can't exec query {:?}", err)),
         }
-        Err(format!("can't insert"))
     }
     pub async fn delete(conn: &Connection, table_name: &str, where_string: &str, values: QueryValues) -> Result<bool, String> {
         let query = format!("DELETE FROM {}.{} WHERE {}", CASSANDRA_DB_NAME, table_name, where_string);
         println!("delete query ===== {} ", query);
-        let prepared_query = conn.prepare(query).unwrap();
+        let prepared_query = conn.prepare(query).map_err(|err| format!("can't prepare query {:?}", err))?;
         let with_tracing = true;
         let with_warnings = true;
         match conn.exec_with_values_tw(&prepared_query, values, with_tracing, with_warnings) {
@@ -127,12 +186,26 @@ impl DbQuery {
                     Ok(is_deleted) => {
                         return Ok(is_deleted.unwrap())
                     },
-                    Err(ref err) => panic!("can't exec query {:?}", err),
+                    Err(ref err) => Err(format!("can't exec query {:?}", err)),
                 }
             },
-            Err(ref err) => panic!("can't exec query {:?}", err),
+            Err(ref err) => Err(format!("can't exec query {:?}", err)),
         }
-        Err(format!("can't insert"))
+    }
+    // DELETE without IF conditions, e.g. whole partitions
+    pub async fn exec_delete(conn: &Connection, table_name: &str, where_string: &str, values: QueryValues) -> Result<(), String> {
+        let query = format!("DELETE FROM {}.{} WHERE {}", CASSANDRA_DB_NAME, table_name, where_string);
+        let prepared_query = conn.prepare(query).map_err(|err| format!("can't prepare query {:?}", err))?;
+        conn.exec_with_values(&prepared_query, values)
+            .map(|_| ())
+            .map_err(|err| format!("can't exec query {:?}", err))
+    }
+}
+
+fn ttl_clause(ttl_secs: Option<i32>) -> String {
+    match ttl_secs {
+        Some(ttl_secs) => format!(" USING TTL {}", ttl_secs),
+        None => String::new(),
     }
 }
 
@@ -0,0 +1,106 @@
+use crate::config::db::RedisPool;
+use r2d2_redis::redis::{self, Commands};
+use serde_json::Value;
+
+// event types
+pub const EVENT_NEW_MESSAGE: &str = "new_message";
+pub const EVENT_UNREAD_COUNT: &str = "unread_count";
+pub const EVENT_MEMBER_ADDED: &str = "member_added";
+pub const EVENT_MEMBER_REMOVED: &str = "member_removed";
+pub const EVENT_ROOM_UPDATED: &str = "room_updated";
+pub const EVENT_MESSAGE_EDITED: &str = "message_edited";
+pub const EVENT_MESSAGE_DELETED: &str = "message_deleted";
+pub const EVENT_MESSAGE_READ: &str = "message_read";
+pub const EVENT_MESSAGE_REACTION: &str = "message_reaction";
+pub const EVENT_MESSAGE_PINNED: &str = "message_pinned";
+pub const EVENT_MESSAGE_UNPINNED: &str = "message_unpinned";
+// only sent to the mentioned user
+pub const EVENT_MENTION: &str = "mention";
+
+// number of events kept per user for Last-Event-ID resume
+pub const EVENT_LOG_SIZE: isize = 500;
+pub const EVENT_POLL_INTERVAL_MS: u64 = 1000;
+// idle polls before a keep-alive comment is sent on the stream
+pub const EVENT_KEEP_ALIVE_POLLS: u32 = 15;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EventQuery {
+    pub app_id: i64,
+    pub user_id: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoomEvent {
+    pub id: i64,
+    pub event_type: String,
+    pub app_id: i64,
+    pub room_id: String,
+    pub data: Value,
+    pub created_at: i64,
+}
+
+impl RoomEvent {
+    pub fn log_key(app_id: i64, user_id: &str) -> String {
+        format!("events_{:?}_{}", app_id, user_id)
+    }
+    pub fn seq_key(app_id: i64, user_id: &str) -> String {
+        format!("events_seq_{:?}_{}", app_id, user_id)
+    }
+
+    // The sequence bump and the log append run in one WATCH/MULTI transaction per user so
+    // concurrent publishers can't push events out of id order.
+    pub fn publish(redis_pool: &RedisPool, app_id: i64, room_id: &str, user_ids: &[String], event_type: &str, data: Value) -> Result<(), String> {
+        let mut redis_conn = redis_pool.get().map_err(|err| err.to_string())?;
+        for user_id in user_ids {
+            let seq_key = Self::seq_key(app_id, user_id);
+            let log_key = Self::log_key(app_id, user_id);
+            redis::transaction(&mut *redis_conn, &[seq_key.clone()], |conn, pipe| {
+                let last_id: Option<i64> = conn.get(seq_key.clone())?;
+                let event = RoomEvent {
+                    id: last_id.unwrap_or(0) + 1,
+                    event_type: event_type.to_string(),
+                    app_id,
+                    room_id: room_id.to_string(),
+                    data: data.clone(),
+                    created_at: chrono::Utc::now().timestamp_millis(),
+                };
+                let event_str = serde_json::to_string(&event).unwrap_or("{}".to_string());
+                pipe.set(seq_key.clone(), event.id).ignore()
+                    .rpush(log_key.clone(), event_str).ignore()
+                    .ltrim(log_key.clone(), -EVENT_LOG_SIZE, -1).ignore()
+                    .query::<Option<()>>(conn)
+            }).map_err(|err: redis::RedisError| err.to_string())?;
+        }
+        Ok(())
+    }
+
+    pub fn last_id(redis_pool: &RedisPool, app_id: i64, user_id: &str) -> Result<i64, String> {
+        let mut redis_conn = redis_pool.get().map_err(|err| err.to_string())?;
+        let id: Option<i64> = redis_conn.get(Self::seq_key(app_id, user_id)).map_err(|err| err.to_string())?;
+        Ok(id.unwrap_or(0))
+    }
+
+    pub fn find_after(redis_pool: &RedisPool, app_id: i64, user_id: &str, last_id: i64) -> Result<Vec<RoomEvent>, String> {
+        let mut redis_conn = redis_pool.get().map_err(|err| err.to_string())?;
+        let events: Vec<String> = redis_conn.lrange(Self::log_key(app_id, user_id), 0, -1).map_err(|err| err.to_string())?;
+        let mut res_events: Vec<RoomEvent> = vec![];
+        for event in events {
+            match serde_json::from_str::<RoomEvent>(&event) {
+                Ok(event) => {
+                    if event.id > last_id {
+                        res_events.push(event)
+                    }
+                },
+                Err(msg) => {
+                    println!("serde_json::from_str::<RoomEvent> Error {:?}", msg);
+                }
+            }
+        }
+        Ok(res_events)
+    }
+
+    // server-sent events wire format
+    pub fn to_sse(&self) -> String {
+        format!("id: {}\nevent: {}\ndata: {}\n\n", self.id, self.event_type, serde_json::to_string(self).unwrap_or("{}".to_string()))
+    }
+}
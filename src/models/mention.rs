@@ -0,0 +1,165 @@
+use crate::{
+    config::db::{Connection, RedisPool},
+    models::{
+        common::DbQuery,
+        chat_room::ChatRoom,
+        event::{RoomEvent, EVENT_MENTION},
+        messages::{Message, ResMessage, MESSAGE_PAGE_DEFAULT_LIMIT, MESSAGE_PAGE_MAX_LIMIT, MESSAGE_STATUS_ACTIVE},
+    },
+};
+use cdrs::{
+    query::*,
+    types::{
+        from_cdrs::FromCDRSByName,
+        prelude::*,
+    }
+};
+use std::result::Result;
+use uuid::Uuid;
+
+// table name, (app_id, user_id) -> messages mentioning the user, newest first
+pub const TABLE_NAME: &str = "chat_user_mentions";
+
+#[derive(Clone, Debug, TryFromRow)]
+pub struct MentionRow {
+    pub room_id: String,
+    pub msg_time: Uuid,
+}
+
+// GET api/messages/mentions query
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MentionQuery {
+    pub app_id: i64,
+    pub user_id: String,
+    pub before: Option<Uuid>,
+    pub limit: Option<i32>,
+    pub cursor: Option<String>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MentionedMessage {
+    pub room_id: String,
+    #[serde(flatten)]
+    pub message: ResMessage,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MentionPage {
+    pub mentions: Vec<MentionedMessage>,
+    pub next_cursor: Option<String>,
+}
+
+// Room members named as "@user_id" in content, in order of first mention. The sender
+// mentioning themselves is ignored.
+pub fn parse_mentions(content: &str, member_ids: &[String], sender: &str) -> Vec<String> {
+    let mut mentions: Vec<String> = vec![];
+    let mut prev: Option<char> = None;
+    let mut chars = content.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        // "a@b" is an email address, not a mention
+        if c == '@' && prev.map_or(true, |prev| !prev.is_alphanumeric()) {
+            let start = i + c.len_utf8();
+            let mut end = start;
+            while let Some(&(j, next)) = chars.peek() {
+                if !(next.is_alphanumeric() || next == '_' || next == '-' || next == '.') {
+                    break;
+                }
+                end = j + next.len_utf8();
+                chars.next();
+            }
+            // a trailing dot ends the sentence
+            let user_id = content[start..end].trim_end_matches('.');
+            if user_id != sender && member_ids.iter().any(|member| member == user_id) && !mentions.iter().any(|mention| mention == user_id) {
+                mentions.push(user_id.to_string());
+            }
+            prev = content[..end].chars().last();
+            continue;
+        }
+        prev = Some(c);
+    }
+    mentions
+}
+
+pub struct Mention;
+
+impl Mention {
+    // Called once the message is stored with its mentions, with the users mentioned for the
+    // first time (all of them on send, the new ones on edit).
+    pub async fn add(conn: &Connection, app_id: i64, room_id: &str, message: &ResMessage, mentions: &[String], redis_pool: &RedisPool) -> Result<(), String> {
+        if mentions.is_empty() {
+            return Ok(())
+        }
+        for user_id in mentions {
+            DbQuery::upsert(&conn, TABLE_NAME, "app_id, user_id, msg_time, room_id, msg_id, msg_owner, created_at", "?, ?, ?, ?, ?, ?, ?", query_values!("app_id" => app_id, "user_id" => user_id.clone(), "msg_time" => message.msg_time, "room_id" => room_id.to_string(), "msg_id" => message.msg_id, "msg_owner" => message.msg_owner.clone(), "created_at" => message.created_at)).await?;
+        }
+        let unread_mentions = ChatRoom::add_unread_mentions(&redis_pool, app_id, room_id, mentions);
+        for user_id in mentions {
+            let data = json!({ "room_id": room_id, "msg_id": message.msg_id, "msg_time": message.msg_time, "msg_owner": message.msg_owner, "unread_mentions": unread_mentions.get(user_id).cloned().unwrap_or(0) });
+            RoomEvent::publish(&redis_pool, app_id, room_id, &[user_id.clone()], EVENT_MENTION, data);
+        }
+        Ok(())
+    }
+
+    // Drops the message from the mentions listing of the users.
+    pub async fn remove(conn: &Connection, app_id: i64, user_ids: &[String], msg_time: Uuid) -> Result<(), String> {
+        for user_id in user_ids {
+            DbQuery::exec_delete(&conn, TABLE_NAME, "app_id=? AND user_id=? AND msg_time=?", query_values!(app_id, user_id.clone(), msg_time)).await?;
+        }
+        Ok(())
+    }
+
+    // Messages deleted for everyone since are left out.
+    pub async fn find_by_user(conn: &Connection, query: MentionQuery) -> Result<MentionPage, String> {
+        let limit = std::cmp::min(std::cmp::max(query.limit.unwrap_or(MESSAGE_PAGE_DEFAULT_LIMIT), 1), MESSAGE_PAGE_MAX_LIMIT);
+        let paging_state = match query.cursor {
+            Some(ref cursor) => Some(DbQuery::decode_paging_state(cursor)?),
+            None => None,
+        };
+        let mut where_string = "app_id=? AND user_id=?".to_string();
+        let mut values: Vec<Value> = vec![query.app_id.into(), query.user_id.clone().into()];
+        if let Some(before) = query.before {
+            where_string.push_str(" AND msg_time<?");
+            values.push(before.into());
+        }
+        let (rows, next_paging_state) = DbQuery::get_page(&conn, TABLE_NAME, "room_id, msg_time", &where_string, QueryValues::SimpleValues(values), limit, paging_state).await?;
+        let mut mentions: Vec<MentionedMessage> = vec![];
+        for row in rows {
+            let mention = MentionRow::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+            match Message::find_by_msg_time(&conn, &mention.room_id, mention.msg_time).await? {
+                Some(message) if message.status == MESSAGE_STATUS_ACTIVE => mentions.push(MentionedMessage { room_id: mention.room_id, message }),
+                _ => {},
+            }
+        }
+        Ok(MentionPage {
+            mentions,
+            next_cursor: next_paging_state.map(|paging_state| DbQuery::encode_paging_state(&paging_state)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members() -> Vec<String> {
+        vec!["alice", "bob", "j.doe", "mary-ann", "t_1"].into_iter().map(String::from).collect()
+    }
+
+    #[test]
+    fn finds_members_in_order_of_first_mention() {
+        assert_eq!(parse_mentions("@bob and @alice, hi @bob", &members(), "carol"), vec!["bob", "alice"]);
+        assert_eq!(parse_mentions("(@alice)", &members(), "carol"), vec!["alice"]);
+    }
+
+    #[test]
+    fn names_can_contain_dots_dashes_and_underscores() {
+        assert_eq!(parse_mentions("@j.doe @mary-ann @t_1", &members(), "carol"), vec!["j.doe", "mary-ann", "t_1"]);
+        // a trailing dot ends the sentence
+        assert_eq!(parse_mentions("thanks @bob.", &members(), "carol"), vec!["bob"]);
+    }
+
+    #[test]
+    fn skips_emails_non_members_and_the_sender() {
+        assert!(parse_mentions("mail bob@alice.com", &members(), "carol").is_empty());
+        assert!(parse_mentions("@dave @", &members(), "carol").is_empty());
+        assert_eq!(parse_mentions("@alice @bob", &members(), "alice"), vec!["bob"]);
+    }
+}
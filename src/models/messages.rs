@@ -1,9 +1,22 @@
 use crate::{
-    config::db::Connection,
+    config::db::{Connection, RedisPool},
     constants,
     models::{
+        attachment::{Attachment, AttachmentView},
         common::{DbQuery},
-        chat_room::{LastMessage, ChatRoom}
+        chat_room::{LastMessage, ChatRoom},
+        event::{RoomEvent, EVENT_MESSAGE_DELETED, EVENT_MESSAGE_EDITED, EVENT_MESSAGE_READ, EVENT_NEW_MESSAGE},
+        mention::{self, Mention},
+        pin::{Pin, PIN_TABLE_NAME},
+        reaction::{Reaction, ReactionSummary, REACTION_TABLE_NAME},
+        retention::{self, RetentionPolicy},
+        system_message::SystemMessage,
+        thread::Thread,
+    },
+    utils::{
+        blob_store::BlobStore,
+        search_index::{IndexedMessage, SearchIndex},
+        time_uuid,
     },
 };
 use cdrs::{
@@ -18,11 +31,42 @@ use cdrs::{
         rows::Row,
     }
 };
+use chrono::{Datelike, NaiveDateTime};
+use std::collections::{HashMap, HashSet};
 use std::result::Result;
 use uuid::Uuid;
 
-// table name
-pub const TABLE_NAME: &str = "chat_room_messages";
+// table name, partitioned by (room_id, bucket)
+pub const TABLE_NAME: &str = "chat_room_bucketed_messages";
+// pre-bucket table with one partition per room, only read by migrate_to_buckets
+pub const LEGACY_TABLE_NAME: &str = "chat_room_messages";
+// buckets a room has messages in, walked by the history query
+pub const BUCKET_TABLE_NAME: &str = "chat_room_message_buckets";
+// previous contents of edited messages
+pub const EDIT_TABLE_NAME: &str = "chat_room_message_edits";
+// messages a user deleted for themselves only
+pub const HIDDEN_TABLE_NAME: &str = "chat_room_message_hidden";
+// newest msg_time each user has read up to, per room
+pub const READ_POINTER_TABLE_NAME: &str = "chat_room_read_pointers";
+
+// rooms up to this many members keep per-message reader lists in read_by_users,
+// bigger rooms only get read counts
+pub const READ_RECEIPT_MAX_READERS: usize = 20;
+// messages a single mark-read walks back to find the senders to notify
+const READ_RECEIPT_SCAN_LIMIT: i32 = 500;
+
+pub const MESSAGE_STATUS_ACTIVE: i8 = 1;
+pub const MESSAGE_STATUS_DELETED: i8 = 2;
+pub const MESSAGE_TYPE_VOICE: i8 = 3;
+
+// ResMessage columns
+const RES_MESSAGE_FIELDS: &str = "msg_owner, owner_name, msg_id, msg_time, reply_on_id, content, url, attachment_id, mentions, message_type, system_message, system_template, system_data, status, read_by_users, send_at, edited_at, deleted_at, last_reply_at, expires_at, created_at";
+// (room_id, msg_id) claimed by client supplied message ids, a retried create finds its message here
+pub const MSG_ID_TABLE_NAME: &str = "chat_room_message_ids";
+
+pub const MESSAGE_PAGE_DEFAULT_LIMIT: i32 = 50;
+pub const MESSAGE_PAGE_MAX_LIMIT: i32 = 200;
+const MIGRATION_PAGE_SIZE: i32 = 500;
 
 #[derive(Clone, Debug, TryFromRow, Serialize, Deserialize)]
 pub struct Message {
@@ -31,30 +75,189 @@ pub struct Message {
     pub msg_owner: String,
     pub owner_name:  Option<String>,
     pub msg_id: Uuid,
+    // server generated timeuuid, the clustering key messages are ordered by
+    pub msg_time: Uuid,
+    // yyyymm of msg_time, see bucket_of
+    pub bucket: i32,
     pub reply_on_id: Option<Uuid>,
     pub content: String,
     pub url: Option<String>,
+    // uploaded through api/attachments
+    pub attachment_id: Option<Uuid>,
+    pub mentions: Option<Vec<String>>,
     pub read_by_users: Option<Vec<String>>,
-    pub message_type: i8,  // 1 = text , 2 = image ..
+    pub message_type: i8,  // 1 = text , 2 = image , 3 = voice ..
     pub system_message: bool,
+    pub system_template: Option<String>,
+    pub system_data: Option<HashMap<String, String>>,
     pub status: i8, // 1= active, 2 = deleted
+    // client clock hint, see time_uuid::send_at_hint
     pub send_at: i64,
+    pub edited_at: Option<i64>,
+    pub deleted_at: Option<i64>,
+    pub deleted_by: Option<String>,
+    pub last_reply_at: Option<i64>,
+    // set under a retention policy, the row carries a TTL up to it
+    pub expires_at: Option<i64>,
+    // set on the first read under disappear_after_read_secs
+    pub disappear_at: Option<i64>,
     pub updated_at: i64,
     pub created_at: i64,
 }
 
 #[derive(Clone, Debug, TryFromRow, Serialize, Deserialize)]
 pub struct ResMessage {
+    pub msg_owner: String,
+    pub owner_name:  Option<String>,
+    pub msg_id: Uuid,
+    pub msg_time: Uuid,
+    pub reply_on_id: Option<Uuid>,
+    pub content: String,
+    pub url: Option<String>,
+    // fetch a download link with GET api/attachments/{attachment_id}/url
+    pub attachment_id: Option<Uuid>,
+    // room members mentioned as @user_id in the current content
+    pub mentions: Option<Vec<String>>,
+    pub message_type: i8,  // 1 = text , 2 = image , 3 = voice ..
+    // posted by the server, e.g. when a message is pinned
+    pub system_message: bool,
+    // one of the system_message::TEMPLATE_* names and its values, content is the English rendering
+    pub system_template: Option<String>,
+    pub system_data: Option<HashMap<String, String>>,
+    // 2 = deleted for everyone, returned as a tombstone without content
+    pub status: i8,
+    // only kept for rooms up to READ_RECEIPT_MAX_READERS members
+    pub read_by_users: Option<Vec<String>>,
+    pub send_at: i64,
+    // set once the message has been edited
+    pub edited_at: Option<i64>,
+    pub deleted_at: Option<i64>,
+    // set on thread parents
+    pub last_reply_at: Option<i64>,
+    // deleted at this time by the room's retention policy
+    pub expires_at: Option<i64>,
+    pub created_at: i64,
+}
+// GET api/messages/{room_id} query, pages run newest first unless only `after` is given
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageQuery {
+    pub before: Option<Uuid>,
+    pub after: Option<Uuid>,
+    pub limit: Option<i32>,
+    // leaves out the messages this user deleted for themselves
+    pub user_id: Option<String>,
+    // next_cursor of the previous page, sent together with the same before/after,
+    // "{bucket}" or "{bucket}:{paging state}"
+    pub cursor: Option<String>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PageMessage {
+    #[serde(flatten)]
+    pub message: ResMessage,
+    // room members other than the owner that have read up to this message
+    pub read_count: i32,
+    pub reply_count: i64,
+    // "reacted_by_me" is only set when the query names a user_id
+    pub reactions: Vec<ReactionSummary>,
+    pub pinned: bool,
+    pub attachment: Option<AttachmentView>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessagePage {
+    pub messages: Vec<PageMessage>,
+    pub next_cursor: Option<String>,
+}
+#[derive(Clone, Debug, TryFromRow)]
+pub struct ReadPointer {
+    pub user_id: String,
+    pub read_up_to: Uuid,
+}
+#[derive(Clone, Debug, TryFromRow)]
+pub struct SearchScanRow {
+    pub app_id: i64,
+    pub room_id: String,
+    pub msg_owner: String,
+    pub msg_id: Uuid,
+    pub msg_time: Uuid,
+    pub content: String,
+    pub system_message: bool,
+    pub status: i8,
+    pub created_at: i64,
+}
+#[derive(Clone, Debug, TryFromRow)]
+pub struct ReadScanRow {
+    pub app_id: i64,
+    pub msg_owner: String,
+    pub msg_id: Uuid,
+    pub msg_time: Uuid,
+    pub bucket: i32,
+    pub expires_at: Option<i64>,
+    pub disappear_at: Option<i64>,
+}
+#[derive(Clone, Debug, TryFromRow)]
+pub struct MessageEdit {
+    pub edited_at: i64,
+    pub editor: String,
+    pub previous_content: String,
+    pub content: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadReceipt {
+    pub room_id: String,
+    pub user_id: String,
+    pub read_up_to: Uuid,
+    // messages of other users this call marked as read
+    pub msg_ids: Vec<Uuid>,
+}
+#[derive(Clone, Debug, TryFromRow)]
+pub struct MessageTime {
+    pub msg_time: Uuid,
+}
+#[derive(Clone, Debug, TryFromRow)]
+pub struct MessageBucket {
+    pub bucket: i32,
+}
+// chat_room_messages row, the table messages were stored in before buckets
+#[derive(Clone, Debug, TryFromRow)]
+pub struct LegacyMessage {
+    pub app_id: i64,
+    pub room_id: String,
     pub msg_owner: String,
     pub owner_name:  Option<String>,
     pub msg_id: Uuid,
     pub reply_on_id: Option<Uuid>,
     pub content: String,
     pub url: Option<String>,
-    pub message_type: i8,  // 1 = text , 2 = image ..
+    pub read_by_users: Option<Vec<String>>,
+    pub message_type: i8,
+    pub system_message: bool,
+    pub status: i8,
     pub send_at: i64,
+    pub updated_at: i64,
     pub created_at: i64,
 }
+
+// Search indexing never fails the write, a missing entry is fixed by `reindex-search`.
+fn index_for_search(app_id: i64, room_id: &str, message: &ResMessage) {
+    let indexed = IndexedMessage {
+        app_id,
+        room_id: room_id.to_string(),
+        msg_owner: message.msg_owner.clone(),
+        msg_id: message.msg_id.to_string(),
+        msg_time: message.msg_time.to_string(),
+        content: message.content.clone(),
+        created_at: message.created_at,
+    };
+    if let Err(err) = SearchIndex::global().index_message(indexed) {
+        println!("can't index message, error - {:?}", &err);
+    }
+}
+
+// Month a message is stored under, as yyyymm.
+pub fn bucket_of(msg_time: &Uuid) -> i32 {
+    let date = NaiveDateTime::from_timestamp(time_uuid::timestamp_millis(msg_time) / 1000, 0);
+    date.year() * 100 + date.month() as i32
+}
 #[derive(Debug, Serialize, Deserialize, TryFromRow)]
 pub struct AddMessage {
     pub app_id: i64,
@@ -65,53 +268,368 @@ pub struct AddMessage {
     pub reply_on_id: Option<Uuid>,
     pub content: String,
     pub url: Option<String>,
+    pub attachment_id: Option<Uuid>,
     pub message_type: Option<i8>,
+    #[serde(skip)]
+    pub system_message: bool,
+    #[serde(skip)]
+    pub system_template: Option<String>,
+    #[serde(skip)]
+    pub system_data: Option<HashMap<String, String>>,
+    // parsed from content by add_new_msg
+    #[serde(skip)]
+    pub mentions: Option<Vec<String>>,
+    // from the room's retention policy
+    #[serde(skip)]
+    pub expires_at: Option<i64>,
     pub send_at: i64
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeleteMessage {
+    pub msg_id: Uuid,
     pub room_id: String,
-    pub send_at: i64
+    pub user_id: String,
+    // false only hides the message for user_id
+    #[serde(default)]
+    pub for_everyone: bool
+}
+// PATCH api/messages/{msg_id} body
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EditMessageBody {
+    pub room_id: String,
+    pub user_id: String,
+    pub content: String
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EditMessage {
+    pub msg_id: Uuid,
+    pub room_id: String,
+    // has to be the msg_owner
+    pub user_id: String,
+    pub content: String
+}
+// marks every message up to and including msg_time as read
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadMessage {
+    pub room_id: String,
+    pub msg_time: Uuid,
+    pub user_id: String
 }
 impl AddMessage {
-    fn into_query_values(self) -> QueryValues {
+    fn into_query_values(self, msg_id: Uuid, msg_time: Uuid) -> QueryValues {
         let message_type = self.message_type.unwrap_or(1);
-        let msg_id = self.msg_id.unwrap_or(Uuid::new_v4());
         println!("msg_id === {:?}", &msg_id);
         query_values!("app_id" => self.app_id, "room_id" => self.room_id, "msg_owner" => self
-        .msg_owner, "content" => self.content, "url" => self.url, "message_type" => message_type, "msg_id" => msg_id.to_string(), "reply_on_id" => self.reply_on_id, "send_at" => self.send_at, "owner_name" => self.owner_name)
+        .msg_owner, "content" => self.content, "url" => self.url, "attachment_id" => self.attachment_id, "mentions" => self.mentions, "message_type" => message_type, "system_message" => self.system_message, "system_template" => self.system_template, "system_data" => self.system_data, "expires_at" => self.expires_at, "msg_id" => msg_id, "msg_time" => msg_time, "bucket" => bucket_of(&msg_time), "reply_on_id" => self.reply_on_id, "send_at" => self.send_at, "owner_name" => self.owner_name)
+    }
+    fn to_res_message(&self, msg_id: Uuid, msg_time: Uuid) -> ResMessage {
+        ResMessage {
+            msg_owner: self.msg_owner.clone(),
+            owner_name: self.owner_name.clone(),
+            msg_id,
+            msg_time,
+            reply_on_id: self.reply_on_id,
+            content: self.content.clone(),
+            url: self.url.clone(),
+            attachment_id: self.attachment_id,
+            mentions: self.mentions.clone(),
+            message_type: self.message_type.unwrap_or(1),
+            system_message: self.system_message,
+            system_template: self.system_template.clone(),
+            system_data: self.system_data.clone(),
+            status: MESSAGE_STATUS_ACTIVE,
+            read_by_users: None,
+            send_at: self.send_at,
+            edited_at: None,
+            deleted_at: None,
+            last_reply_at: None,
+            expires_at: self.expires_at,
+            created_at: time_uuid::timestamp_millis(&msg_time),
+        }
     }
 }
 
 impl Message {
-    pub async fn find_by_room_id(room_id: String, conn: &Connection) ->  Result<Vec<ResMessage>, String> {
-        let rows = DbQuery::get_rows(&conn, TABLE_NAME, "msg_owner, owner_name, msg_id, reply_on_id, content, url, message_type, send_at, created_at", "room_id=? AND status=1", query_values!(room_id)).await.expect("get user");
-        let mut messages: Vec<ResMessage> = vec![];
+    fn to_res_message(&self) -> ResMessage {
+        ResMessage {
+            msg_owner: self.msg_owner.clone(),
+            owner_name: self.owner_name.clone(),
+            msg_id: self.msg_id,
+            msg_time: self.msg_time,
+            reply_on_id: self.reply_on_id,
+            content: self.content.clone(),
+            url: self.url.clone(),
+            attachment_id: self.attachment_id,
+            mentions: self.mentions.clone(),
+            message_type: self.message_type,
+            system_message: self.system_message,
+            system_template: self.system_template.clone(),
+            system_data: self.system_data.clone(),
+            status: self.status,
+            read_by_users: self.read_by_users.clone(),
+            send_at: self.send_at,
+            edited_at: self.edited_at,
+            deleted_at: self.deleted_at,
+            last_reply_at: self.last_reply_at,
+            expires_at: self.expires_at,
+            created_at: self.created_at,
+        }
+    }
+
+    pub async fn find_buckets(conn: &Connection, room_id: &str) -> Result<Vec<i32>, String> {
+        let rows = DbQuery::get_rows(&conn, BUCKET_TABLE_NAME, "bucket", "room_id=?", query_values!(room_id.to_string())).await?;
+        let mut buckets: Vec<i32> = vec![];
+        for row in rows {
+            buckets.push(MessageBucket::try_from_row(row).map_err(|err| format!("{:?}", err))?.bucket)
+        }
+        buckets.sort();
+        Ok(buckets)
+    }
+
+    // Walks the room's buckets in history order until the page is full, each bucket only
+    // fetches the rows still missing from the page.
+    pub async fn find_by_room_id(room_id: String, query: MessageQuery, conn: &Connection) ->  Result<MessagePage, String> {
+        let limit = std::cmp::min(std::cmp::max(query.limit.unwrap_or(MESSAGE_PAGE_DEFAULT_LIMIT), 1), MESSAGE_PAGE_MAX_LIMIT);
+        let ascending = query.after.is_some() && query.before.is_none();
+        let (cursor_bucket, mut paging_state) = match query.cursor {
+            Some(ref cursor) => {
+                let mut parts = cursor.splitn(2, ':');
+                let bucket = parts.next().unwrap_or("").parse::<i32>().map_err(|_| format!("invalid cursor"))?;
+                let paging_state = match parts.next() {
+                    Some(paging_state) => Some(DbQuery::decode_paging_state(paging_state)?),
+                    None => None,
+                };
+                (Some(bucket), paging_state)
+            },
+            None => (None, None),
+        };
+        let oldest = query.after.as_ref().map(bucket_of);
+        let newest = query.before.as_ref().map(bucket_of);
+        let mut buckets: Vec<i32> = Self::find_buckets(&conn, &room_id).await?.into_iter()
+            .filter(|bucket| oldest.map_or(true, |oldest| *bucket >= oldest) && newest.map_or(true, |newest| *bucket <= newest))
+            .filter(|bucket| cursor_bucket.map_or(true, |cursor_bucket| if ascending { *bucket >= cursor_bucket } else { *bucket <= cursor_bucket }))
+            .collect();
+        if !ascending {
+            buckets.reverse();
+        }
+
+        let hidden = match query.user_id {
+            Some(ref user_id) => Self::find_hidden_msg_ids(&conn, &room_id, user_id).await?,
+            None => HashSet::new(),
+        };
+        let read_pointers = Self::find_read_pointers(&conn, &room_id).await?;
+
+        let mut messages: Vec<PageMessage> = vec![];
+        let mut fetched: i32 = 0;
+        let mut next_cursor: Option<String> = None;
+        for (i, bucket) in buckets.iter().enumerate() {
+            if fetched >= limit {
+                next_cursor = Some(bucket.to_string());
+                break;
+            }
+            let mut where_string = "room_id=? AND bucket=?".to_string();
+            let mut values: Vec<Value> = vec![room_id.clone().into(), (*bucket).into()];
+            if let Some(before) = query.before {
+                where_string.push_str(" AND msg_time<?");
+                values.push(before.into());
+            }
+            if let Some(after) = query.after {
+                where_string.push_str(" AND msg_time>?");
+                values.push(after.into());
+            }
+            if ascending {
+                where_string.push_str(" ORDER BY msg_time ASC");
+            }
+            // hidden messages are filtered here so the clustering order is kept, a page can come
+            // back shorter than the limit
+            let (rows, next_paging_state) = DbQuery::get_page(&conn, TABLE_NAME, RES_MESSAGE_FIELDS, &where_string, QueryValues::SimpleValues(values), limit - fetched, paging_state.take()).await?;
+            fetched += rows.len() as i32;
+            for row in rows {
+                let message = ResMessage::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+                // expired but not swept yet
+                if !hidden.contains(&message.msg_id) && !retention::is_expired(message.expires_at) {
+                    let msg_ticks = time_uuid::ticks(&message.msg_time);
+                    let read_count = read_pointers.iter()
+                        .filter(|pointer| pointer.user_id != message.msg_owner && time_uuid::ticks(&pointer.read_up_to) >= msg_ticks)
+                        .count() as i32;
+                    messages.push(PageMessage { message, read_count, reply_count: 0, reactions: vec![], pinned: false, attachment: None })
+                }
+            }
+            if let Some(next_paging_state) = next_paging_state {
+                next_cursor = Some(format!("{}:{}", bucket, DbQuery::encode_paging_state(&next_paging_state)));
+                break;
+            }
+            if fetched >= limit {
+                next_cursor = buckets.get(i + 1).map(|bucket| bucket.to_string());
+                break;
+            }
+        }
+        let parent_ids: Vec<Uuid> = messages.iter()
+            .filter(|page_message| page_message.message.last_reply_at.is_some())
+            .map(|page_message| page_message.message.msg_id)
+            .collect();
+        let reply_counts = Thread::find_reply_counts(&conn, &room_id, parent_ids).await?;
+        let msg_ids: Vec<Uuid> = messages.iter()
+            .filter(|page_message| page_message.message.status == MESSAGE_STATUS_ACTIVE)
+            .map(|page_message| page_message.message.msg_id)
+            .collect();
+        let pinned = Pin::find_pinned_msg_ids(&conn, &room_id).await?;
+        let attachment_ids: Vec<Uuid> = messages.iter()
+            .filter(|page_message| page_message.message.status == MESSAGE_STATUS_ACTIVE)
+            .filter_map(|page_message| page_message.message.attachment_id)
+            .collect();
+        let mut attachments = Attachment::find_views(&conn, attachment_ids, query.user_id.as_ref().map(|user_id| user_id.as_str())).await?;
+        let mut reactions = Reaction::find_summaries(&conn, &room_id, msg_ids, query.user_id.as_ref().map(|user_id| user_id.as_str())).await?;
+        for page_message in messages.iter_mut() {
+            page_message.reply_count = reply_counts.get(&page_message.message.msg_id).cloned().unwrap_or(0);
+            page_message.reactions = reactions.remove(&page_message.message.msg_id).unwrap_or_default();
+            page_message.pinned = pinned.contains(&page_message.message.msg_id);
+            page_message.attachment = page_message.message.attachment_id.and_then(|attachment_id| attachments.remove(&attachment_id));
+        }
+        Ok(MessagePage { messages, next_cursor })
+    }
+
+    pub async fn find_by_msg_time(conn: &Connection, room_id: &str, msg_time: Uuid) -> Result<Option<ResMessage>, String> {
+        match DbQuery::get_row(&conn, TABLE_NAME, RES_MESSAGE_FIELDS, "room_id=? AND bucket=? AND msg_time=?", query_values!("room_id" => room_id.to_string(), "bucket" => bucket_of(&msg_time), "msg_time" => msg_time)).await {
+            Ok(row) => {
+                let message = ResMessage::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+                match retention::is_expired(message.expires_at) {
+                    true => Ok(None),
+                    false => Ok(Some(message)),
+                }
+            },
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub async fn find_read_pointers(conn: &Connection, room_id: &str) -> Result<Vec<ReadPointer>, String> {
+        let rows = DbQuery::get_rows(&conn, READ_POINTER_TABLE_NAME, "user_id, read_up_to", "room_id=?", query_values!(room_id.to_string())).await?;
+        let mut read_pointers: Vec<ReadPointer> = vec![];
         for row in rows {
-            let new_row: ResMessage = ResMessage::try_from_row(row).expect("into RowStruct");
-            messages.push(new_row)
+            read_pointers.push(ReadPointer::try_from_row(row).map_err(|err| format!("{:?}", err))?)
+        }
+        Ok(read_pointers)
+    }
+
+    pub async fn find_hidden_msg_ids(conn: &Connection, room_id: &str, user_id: &str) -> Result<HashSet<Uuid>, String> {
+        let rows = DbQuery::get_rows(&conn, HIDDEN_TABLE_NAME, "msg_id", "room_id=? AND user_id=?", query_values!("room_id" => room_id.to_string(), "user_id" => user_id.to_string())).await?;
+        let mut msg_ids: HashSet<Uuid> = HashSet::new();
+        for row in rows {
+            let msg_id: Option<Uuid> = row.by_name("msg_id").map_err(|err| format!("{:?}", err))?;
+            msg_ids.extend(msg_id);
+        }
+        Ok(msg_ids)
+    }
+
+    // looked up through the msg_id index, the partition is not known from the id alone
+    pub async fn find_message(conn: &Connection, msg_id: Uuid) -> Result<Option<Message>, String> {
+        let rows = DbQuery::get_rows(&conn, TABLE_NAME, "*", "msg_id=?", query_values!(msg_id)).await?;
+        match rows.into_iter().next() {
+            Some(row) => Ok(Some(Message::try_from_row(row).map_err(|err| format!("{:?}", err))?)),
+            None => Ok(None),
         }
-        messages.sort_by_key(|m| m.msg_id);
-        messages.sort_by(|m1, m2| m1.send_at.cmp(&m2.send_at));
-        Ok(messages)
     }
 
-    pub async fn add_new_msg(conn: &Connection, msg: AddMessage) -> Result<String, String> {
+    // msg_time a client msg_id was stored under
+    pub async fn find_claimed_msg_time(conn: &Connection, room_id: &str, msg_id: Uuid) -> Result<Option<Uuid>, String> {
+        match DbQuery::get_row(&conn, MSG_ID_TABLE_NAME, "msg_time", "room_id=? AND msg_id=?", query_values!("room_id" => room_id.to_string(), "msg_id" => msg_id)).await {
+            Ok(row) => Ok(Some(MessageTime::try_from_row(row).map_err(|err| format!("{:?}", err))?.msg_time)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub async fn find_by_msg_id(conn: &Connection, room_id: &str, msg_id: Uuid) -> Result<Option<ResMessage>, String> {
+        match Self::find_claimed_msg_time(&conn, room_id, msg_id).await? {
+            Some(msg_time) => Self::find_by_msg_time(&conn, room_id, msg_time).await,
+            None => Ok(None),
+        }
+    }
+
+    // Returns the stored message as well, on a duplicate client msg_id the one created first.
+    pub async fn add_new_msg(conn: &Connection, mut msg: AddMessage, redis_pool: &RedisPool) -> Result<(String, Option<ResMessage>), String> {
         println!("add_new_msg call");
+        let mut msg_time = time_uuid::now();
+        let msg_id = match msg.msg_id {
+            Some(msg_id) => {
+                let claimed = DbQuery::insert(&conn, MSG_ID_TABLE_NAME, "room_id, msg_id, msg_time", "?, ?, ?", query_values!("room_id" => msg.room_id.clone(), "msg_id" => msg_id, "msg_time" => msg_time)).await;
+                match claimed {
+                    Ok(true) => msg_id,
+                    Ok(false) => {
+                        if let Some(claimed_msg_time) = Self::find_claimed_msg_time(&conn, &msg.room_id, msg_id).await? {
+                            if let Some(existing) = Self::find_by_msg_time(&conn, &msg.room_id, claimed_msg_time).await? {
+                                return Ok((constants::MESSAGE_MSG_ALREADY_EXISTS.to_string(), Some(existing)))
+                            }
+                            // claimed by an earlier attempt that failed before storing the message
+                            msg_time = claimed_msg_time;
+                        }
+                        msg_id
+                    },
+                    Err(ref err) => {
+                        println!("can't claim msg_id, error - {:?}", &err);
+                        return  Err(constants::MESSAGE_MSG_NOT_CREATED.to_string())
+                    },
+                }
+            },
+            None => Uuid::new_v4(),
+        };
+        let thread_parent = match msg.reply_on_id {
+            Some(reply_on_id) => Some(Thread::find_root(&conn, &msg.room_id, reply_on_id).await?),
+            None => None,
+        };
+        msg.reply_on_id = thread_parent.as_ref().map(|parent| parent.msg_id);
+        let attachment = match msg.attachment_id {
+            Some(attachment_id) => match Attachment::find_by_id(&conn, attachment_id).await? {
+                Some(attachment) if attachment.room_id == msg.room_id => Some(attachment),
+                _ => return Err(constants::MESSAGE_ATTACHMENT_NOT_FOUND.to_string()),
+            },
+            None => None,
+        };
+        // the voice bubble is drawn from the duration and waveform of the attachment
+        if msg.message_type == Some(MESSAGE_TYPE_VOICE) && attachment.map_or(true, |attachment| attachment.duration_ms.is_none()) {
+            return Err(constants::MESSAGE_MSG_VOICE_ATTACHMENT_REQUIRED.to_string())
+        }
+        let user_ids = ChatRoom::find_room_user_ids(&redis_pool, msg.app_id, &msg.room_id);
+        if !msg.system_message {
+            let mentions = mention::parse_mentions(&msg.content, &user_ids, &msg.msg_owner);
+            msg.mentions = if mentions.is_empty() { None } else { Some(mentions) };
+        }
+        let policy = RetentionPolicy::find_effective(&conn, msg.app_id, &msg.room_id).await?;
+        msg.expires_at = policy.expires_at(time_uuid::timestamp_millis(&msg_time), None);
+        let ttl_secs = retention::ttl_secs(msg.expires_at);
+        msg.msg_id = Some(msg_id);
+        msg.send_at = time_uuid::send_at_hint(msg.send_at, &msg_time);
+        let res_message = msg.to_res_message(msg_id, msg_time);
+        let event_data = serde_json::to_value(&res_message).unwrap_or(json!({}));
         let last_msg: LastMessage = LastMessage {
             msg_owner: msg.msg_owner.clone(),
             content: msg.content.clone()
         };
         let room_id = msg.room_id.clone();
         let app_id = msg.app_id.clone();
-        let db_insert = DbQuery::insert(&conn, TABLE_NAME, "app_id, room_id, msg_owner, owner_name, msg_id, reply_on_id, content, url, message_type, system_message, status, send_at, updated_at, created_at", "?, ?, ?, ?, uuid(), ?, ?, ?, ?, false, 1, ?, toTimestamp(now()), toTimestamp(now())", msg.into_query_values()).await;
+        if let Err(err) = DbQuery::upsert(&conn, BUCKET_TABLE_NAME, "room_id, bucket", "?, ?", query_values!("room_id" => room_id.clone(), "bucket" => bucket_of(&msg_time))).await {
+            println!("can't insert bucket, error - {:?}", &err);
+            return  Err(constants::MESSAGE_MSG_NOT_CREATED.to_string())
+        }
+        let db_insert = DbQuery::insert_with_ttl(&conn, TABLE_NAME, "app_id, room_id, msg_owner, owner_name, msg_id, msg_time, bucket, reply_on_id, content, url, attachment_id, mentions, message_type, system_message, system_template, system_data, expires_at, status, send_at, updated_at, created_at", "?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 1, ?, toTimestamp(now()), toTimestamp(now())", msg.into_query_values(msg_id, msg_time), ttl_secs).await;
         match db_insert {
             Ok(is_inserted) => {
                 if is_inserted {
                     // ChatRoom::update_last_msg(&conn, last_msg, app_id, room_id).await;
-                    return  Ok(constants::MESSAGE_MSG_CREATED_SUCCESS.to_string())
+                    if let Some(parent) = thread_parent {
+                        if let Err(err) = Thread::add_reply(&conn, &parent, &res_message).await {
+                            println!("can't add thread reply, error - {:?}", &err);
+                        }
+                    }
+                    if !res_message.system_message {
+                        index_for_search(app_id, &room_id, &res_message);
+                    }
+                    if let Err(err) = Mention::add(&conn, app_id, &room_id, &res_message, res_message.mentions.as_deref().unwrap_or(&[]), &redis_pool).await {
+                        println!("can't add mentions, error - {:?}", &err);
+                    }
+                    RoomEvent::publish(&redis_pool, app_id, &room_id, &user_ids, EVENT_NEW_MESSAGE, event_data);
+                    return  Ok((constants::MESSAGE_MSG_CREATED_SUCCESS.to_string(), Some(res_message)))
                 } else {
-                    return  Ok(constants::MESSAGE_MSG_NOT_CREATED.to_string())
+                    return  Ok((constants::MESSAGE_MSG_NOT_CREATED.to_string(), None))
                 }
             },
             Err(ref err) => {
@@ -120,20 +638,344 @@ impl Message {
             },
         }
     }
-    pub async fn delete_msg(conn: &Connection, msg: DeleteMessage) -> Result<String, String> {
-        let db_update = DbQuery::delete(&conn, TABLE_NAME, "room_id=? AND send_at=?", query_values!("room_id" => msg.room_id, "send_at" => msg.send_at)).await;
+    // Server generated message shown inline in the room history, owned by the actor of the
+    // system message.
+    pub async fn add_system_msg(conn: &Connection, app_id: i64, room_id: &str, system: SystemMessage, redis_pool: &RedisPool) -> Result<ResMessage, String> {
+        let msg = AddMessage {
+            app_id,
+            room_id: room_id.to_string(),
+            msg_owner: system.data.get("actor").cloned().unwrap_or_default(),
+            owner_name: None,
+            msg_id: None,
+            reply_on_id: None,
+            content: system.render(),
+            url: None,
+            attachment_id: None,
+            message_type: Some(1),
+            system_message: true,
+            system_template: Some(system.template.to_string()),
+            system_data: Some(system.data),
+            mentions: None,
+            expires_at: None,
+            send_at: chrono::Utc::now().timestamp_millis(),
+        };
+        match Self::add_new_msg(&conn, msg, &redis_pool).await? {
+            (_, Some(res_message)) => Ok(res_message),
+            (message, None) => Err(message),
+        }
+    }
+    // Only the owner can edit, and only within edit_window_secs of sending. The replaced
+    // content is kept in chat_room_message_edits. Returns the app of the message with it.
+    pub async fn edit_msg(conn: &Connection, msg: EditMessage, redis_pool: &RedisPool, edit_window_secs: i64) -> Result<(i64, ResMessage), String> {
+        let mut message = match Self::find_message(&conn, msg.msg_id).await? {
+            Some(message) if message.room_id == msg.room_id && message.status == MESSAGE_STATUS_ACTIVE => message,
+            _ => return Err(constants::MESSAGE_MSG_NOT_FOUND.to_string()),
+        };
+        if message.system_message {
+            return Err(constants::MESSAGE_MSG_SYSTEM_NOT_EDITABLE.to_string())
+        }
+        if message.msg_owner != msg.user_id {
+            return Err(constants::MESSAGE_MSG_EDIT_FORBIDDEN.to_string())
+        }
+        let edited_at = chrono::Utc::now().timestamp_millis();
+        if edited_at - time_uuid::timestamp_millis(&message.msg_time) > edit_window_secs * 1000 {
+            return Err(constants::MESSAGE_MSG_EDIT_WINDOW_EXPIRED.to_string())
+        }
+        let user_ids = ChatRoom::find_room_user_ids(&redis_pool, message.app_id, &message.room_id);
+        let mentions = mention::parse_mentions(&msg.content, &user_ids, &message.msg_owner);
+        let previous_mentions = message.mentions.clone().unwrap_or_default();
+        let added_mentions: Vec<String> = mentions.iter().filter(|user_id| !previous_mentions.contains(user_id)).cloned().collect();
+        let removed_mentions: Vec<String> = previous_mentions.iter().filter(|user_id| !mentions.contains(user_id)).cloned().collect();
+        let mentions = if mentions.is_empty() { None } else { Some(mentions) };
+        let ttl_secs = retention::ttl_secs(message.expires_at);
+        DbQuery::upsert_with_ttl(&conn, EDIT_TABLE_NAME, "room_id, msg_id, edited_at, editor, previous_content, content", "?, ?, ?, ?, ?, ?", query_values!("room_id" => message.room_id.clone(), "msg_id" => message.msg_id, "edited_at" => edited_at, "editor" => msg.user_id, "previous_content" => message.content.clone(), "content" => msg.content.clone()), ttl_secs).await?;
+        let db_update = DbQuery::update_with_ttl(&conn, TABLE_NAME, "content=?, mentions=?, edited_at=?, updated_at=?", "room_id=? AND bucket=? AND msg_time=? IF EXISTS", query_values!("content" => msg.content.clone(), "mentions" => mentions.clone(), "edited_at" => edited_at, "updated_at" => edited_at, "room_id" => message.room_id.clone(), "bucket" => message.bucket, "msg_time" => message.msg_time), ttl_secs).await;
         match db_update {
-            Ok(is_deleted) => {
-                if is_deleted {
-                    return  Ok(constants::MESSAGE_MSG_DELETED_SUCCESS.to_string())
-                } else {
-                    return  Ok(constants::MESSAGE_MSG_NOT_DELETED.to_string())
+            Ok(true) => {
+                message.content = msg.content;
+                message.mentions = mentions;
+                message.edited_at = Some(edited_at);
+                let res_message = message.to_res_message();
+                index_for_search(message.app_id, &message.room_id, &res_message);
+                // users mentioned before keep their notification, only newly mentioned ones get one
+                if let Err(err) = Mention::add(&conn, message.app_id, &message.room_id, &res_message, &added_mentions, &redis_pool).await {
+                    println!("can't add mentions, error - {:?}", &err);
+                }
+                if let Err(err) = Mention::remove(&conn, message.app_id, &removed_mentions, message.msg_time).await {
+                    println!("can't remove mentions, error - {:?}", &err);
                 }
+                RoomEvent::publish(&redis_pool, message.app_id, &message.room_id, &user_ids, EVENT_MESSAGE_EDITED, serde_json::to_value(&res_message).unwrap_or(json!({})));
+                Ok((message.app_id, res_message))
             },
+            Ok(false) => Err(constants::MESSAGE_MSG_NOT_FOUND.to_string()),
             Err(ref err) => {
-                println!("can't insert, error - {:?}", &err);
-                return  Ok(constants::MESSAGE_MSG_NOT_DELETED.to_string())
+                println!("can't update, error - {:?}", &err);
+                return  Err(constants::MESSAGE_MSG_NOT_UPDATED.to_string())
+            },
+        }
+    }
+    // Moves the user's read pointer forward to msg_time, then notifies the senders of the
+    // messages in between. Small rooms also record the reader on each of those messages.
+    pub async fn mark_read(conn: &Connection, msg: ReadMessage, redis_pool: &RedisPool) -> Result<ReadReceipt, String> {
+        let mut receipt = ReadReceipt {
+            room_id: msg.room_id.clone(),
+            user_id: msg.user_id.clone(),
+            read_up_to: msg.msg_time,
+            msg_ids: vec![],
+        };
+        let previous = Self::find_read_pointers(&conn, &msg.room_id).await?.into_iter()
+            .find(|pointer| pointer.user_id == msg.user_id)
+            .map(|pointer| pointer.read_up_to);
+        if previous.map_or(false, |previous| time_uuid::ticks(&previous) >= time_uuid::ticks(&msg.msg_time)) {
+            return Ok(receipt)
+        }
+        let advanced = match DbQuery::insert(&conn, READ_POINTER_TABLE_NAME, "room_id, user_id, read_up_to", "?, ?, ?", query_values!("room_id" => msg.room_id.clone(), "user_id" => msg.user_id.clone(), "read_up_to" => msg.msg_time)).await? {
+            true => true,
+            // another read moved the pointer past msg_time in the meantime
+            false => DbQuery::update(&conn, READ_POINTER_TABLE_NAME, "read_up_to=?", "room_id=? AND user_id=? IF read_up_to < ?", query_values!(msg.msg_time, msg.room_id.clone(), msg.user_id.clone(), msg.msg_time)).await?,
+        };
+        if !advanced {
+            return Ok(receipt)
+        }
+
+        let oldest = previous.as_ref().map(bucket_of);
+        let newest = bucket_of(&msg.msg_time);
+        let mut buckets: Vec<i32> = Self::find_buckets(&conn, &msg.room_id).await?.into_iter()
+            .filter(|bucket| *bucket <= newest && oldest.map_or(true, |oldest| *bucket >= oldest))
+            .collect();
+        buckets.reverse();
+        let mut newly_read: Vec<ReadScanRow> = vec![];
+        let mut fetched: i32 = 0;
+        for bucket in buckets {
+            if fetched >= READ_RECEIPT_SCAN_LIMIT {
+                break;
+            }
+            let mut where_string = "room_id=? AND bucket=? AND msg_time<=?".to_string();
+            let mut values: Vec<Value> = vec![msg.room_id.clone().into(), bucket.into(), msg.msg_time.into()];
+            if let Some(previous) = previous {
+                where_string.push_str(" AND msg_time>?");
+                values.push(previous.into());
+            }
+            let (rows, _) = DbQuery::get_page(&conn, TABLE_NAME, "app_id, msg_owner, msg_id, msg_time, bucket, expires_at, disappear_at", &where_string, QueryValues::SimpleValues(values), READ_RECEIPT_SCAN_LIMIT - fetched, None).await?;
+            fetched += rows.len() as i32;
+            for row in rows {
+                let row = ReadScanRow::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+                if row.msg_owner != msg.user_id {
+                    newly_read.push(row);
+                }
+            }
+        }
+        let app_id = match newly_read.first() {
+            Some(row) => row.app_id,
+            None => return Ok(receipt),
+        };
+
+        let room_user_ids = ChatRoom::find_room_user_ids(&redis_pool, app_id, &msg.room_id);
+        if room_user_ids.len() <= READ_RECEIPT_MAX_READERS {
+            for row in &newly_read {
+                DbQuery::update_with_ttl(&conn, TABLE_NAME, "read_by_users = read_by_users + ?", "room_id=? AND bucket=? AND msg_time=? IF EXISTS", query_values!("read_by_users" => vec![msg.user_id.clone()], "room_id" => msg.room_id.clone(), "bucket" => row.bucket, "msg_time" => row.msg_time), retention::ttl_secs(row.expires_at)).await?;
+            }
+        }
+        // the first read starts the disappearing timer, the sweeper deletes the message once it runs out
+        let policy = RetentionPolicy::find_effective(&conn, app_id, &msg.room_id).await?;
+        let read_at = chrono::Utc::now().timestamp_millis();
+        if let Some(disappear_at) = policy.disappear_at(read_at) {
+            for row in newly_read.iter().filter(|row| row.disappear_at.is_none()) {
+                let expires_at = policy.expires_at(time_uuid::timestamp_millis(&row.msg_time), Some(disappear_at));
+                DbQuery::update_with_ttl(&conn, TABLE_NAME, "disappear_at=?, expires_at=?", "room_id=? AND bucket=? AND msg_time=? IF EXISTS", query_values!("disappear_at" => disappear_at, "expires_at" => expires_at, "room_id" => msg.room_id.clone(), "bucket" => row.bucket, "msg_time" => row.msg_time), retention::ttl_secs(row.expires_at)).await?;
+            }
+        }
+        let mut by_owner: HashMap<String, Vec<Uuid>> = HashMap::new();
+        for row in &newly_read {
+            by_owner.entry(row.msg_owner.clone()).or_insert_with(Vec::new).push(row.msg_id);
+        }
+        for (owner, msg_ids) in by_owner {
+            RoomEvent::publish(&redis_pool, app_id, &msg.room_id, &[owner], EVENT_MESSAGE_READ, json!({ "room_id": msg.room_id, "user_id": msg.user_id, "read_up_to": msg.msg_time, "msg_ids": msg_ids }));
+        }
+        receipt.msg_ids = newly_read.iter().map(|row| row.msg_id).collect();
+        Ok(receipt)
+    }
+    // Deleting for everyone keeps the row as a tombstone (status 2, content, attachment and
+    // mentions cleared, edit history dropped) and is limited to the message owner and the room owner. Returns the app of the message and the
+    // message as the user sees it now.
+    pub async fn delete_msg(conn: &Connection, msg: DeleteMessage, redis_pool: &RedisPool) -> Result<(i64, ResMessage), String> {
+        let mut message = match Self::find_message(&conn, msg.msg_id).await? {
+            Some(message) if message.room_id == msg.room_id => message,
+            _ => return Err(constants::MESSAGE_MSG_NOT_FOUND.to_string()),
+        };
+        if !msg.for_everyone {
+            DbQuery::upsert(&conn, HIDDEN_TABLE_NAME, "room_id, user_id, msg_id", "?, ?, ?", query_values!("room_id" => message.room_id.clone(), "user_id" => msg.user_id.clone(), "msg_id" => message.msg_id)).await?;
+            // only the user's other devices need to drop it
+            RoomEvent::publish(&redis_pool, message.app_id, &message.room_id, &[msg.user_id], EVENT_MESSAGE_DELETED, json!({ "msg_id": message.msg_id, "msg_time": message.msg_time, "for_everyone": false }));
+            return Ok((message.app_id, message.to_res_message()))
+        }
+        if message.status == MESSAGE_STATUS_DELETED {
+            return Ok((message.app_id, message.to_res_message()))
+        }
+        if message.msg_owner != msg.user_id && ChatRoom::find_room_owner(&conn, &message.room_id).await? != Some(msg.user_id.clone()) {
+            return Err(constants::MESSAGE_MSG_DELETE_FORBIDDEN.to_string())
+        }
+        let deleted_at = chrono::Utc::now().timestamp_millis();
+        let db_update = DbQuery::update_with_ttl(&conn, TABLE_NAME, "status=?, content='', url=null, attachment_id=null, mentions=null, deleted_at=?, deleted_by=?, updated_at=?", "room_id=? AND bucket=? AND msg_time=? IF EXISTS", query_values!("status" => MESSAGE_STATUS_DELETED, "deleted_at" => deleted_at, "deleted_by" => msg.user_id.clone(), "updated_at" => deleted_at, "room_id" => message.room_id.clone(), "bucket" => message.bucket, "msg_time" => message.msg_time), retention::ttl_secs(message.expires_at)).await;
+        match db_update {
+            Ok(true) => {
+                message.status = MESSAGE_STATUS_DELETED;
+                message.content = String::new();
+                message.url = None;
+                let attachment_id = message.attachment_id.take();
+                let mentions = message.mentions.take().unwrap_or_default();
+                message.deleted_at = Some(deleted_at);
+                message.deleted_by = Some(msg.user_id.clone());
+                SearchIndex::global().remove_message(&message.msg_id.to_string());
+                if let Err(err) = DbQuery::exec_delete(&conn, EDIT_TABLE_NAME, "room_id=? AND msg_id=?", query_values!(message.room_id.clone(), message.msg_id)).await {
+                    println!("can't delete edit history, error - {:?}", &err);
+                }
+                if let Some(attachment_id) = attachment_id {
+                    if let Err(err) = Attachment::mark_deleted(&conn, attachment_id).await {
+                        println!("can't mark attachment deleted, error - {:?}", &err);
+                    }
+                }
+                if let Err(err) = Mention::remove(&conn, message.app_id, &mentions, message.msg_time).await {
+                    println!("can't remove mentions, error - {:?}", &err);
+                }
+                if let Err(err) = Pin::remove(&conn, &message, &msg.user_id, &redis_pool).await {
+                    println!("can't remove pin, error - {:?}", &err);
+                }
+                if let Some(parent_id) = message.reply_on_id {
+                    if let Err(err) = Thread::remove_reply(&conn, &message.room_id, parent_id, message.msg_time).await {
+                        println!("can't remove thread reply, error - {:?}", &err);
+                    }
+                }
+                let user_ids = ChatRoom::find_room_user_ids(&redis_pool, message.app_id, &message.room_id);
+                RoomEvent::publish(&redis_pool, message.app_id, &message.room_id, &user_ids, EVENT_MESSAGE_DELETED, json!({ "msg_id": message.msg_id, "msg_time": message.msg_time, "for_everyone": true }));
+                Ok((message.app_id, message.to_res_message()))
+            },
+            Ok(false) => Err(constants::MESSAGE_MSG_NOT_FOUND.to_string()),
+            Err(ref err) => {
+                println!("can't delete, error - {:?}", &err);
+                Err(constants::MESSAGE_MSG_NOT_DELETED.to_string())
             },
         }
     }
+
+    // Copies chat_room_messages into the bucketed table, safe to run again after an interruption.
+    pub async fn migrate_to_buckets(conn: &Connection) -> Result<u64, String> {
+        let mut migrated: u64 = 0;
+        let mut paging_state: Option<Vec<u8>> = None;
+        loop {
+            let (rows, next_paging_state) = DbQuery::get_page(&conn, LEGACY_TABLE_NAME, "app_id, room_id, msg_owner, owner_name, msg_id, reply_on_id, content, url, read_by_users, message_type, system_message, status, send_at, updated_at, created_at", "", QueryValues::SimpleValues(vec![]), MIGRATION_PAGE_SIZE, paging_state.take()).await?;
+            for row in rows {
+                let legacy = LegacyMessage::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+                // the legacy table has no msg_time, the one derived from send_at and msg_id is the
+                // same on every run so copying a message again overwrites the earlier copy
+                let msg_time = time_uuid::at_millis(legacy.send_at, &legacy.msg_id);
+                let bucket = bucket_of(&msg_time);
+                DbQuery::upsert(&conn, BUCKET_TABLE_NAME, "room_id, bucket", "?, ?", query_values!("room_id" => legacy.room_id.clone(), "bucket" => bucket)).await?;
+                DbQuery::upsert(&conn, TABLE_NAME, "app_id, room_id, msg_owner, owner_name, msg_id, msg_time, bucket, reply_on_id, content, url, read_by_users, message_type, system_message, status, send_at, updated_at, created_at", "?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?", query_values!(legacy.app_id, legacy.room_id, legacy.msg_owner, legacy.owner_name, legacy.msg_id, msg_time, bucket, legacy.reply_on_id, legacy.content, legacy.url, legacy.read_by_users, legacy.message_type, legacy.system_message, legacy.status, legacy.send_at, legacy.updated_at, legacy.created_at)).await?;
+                migrated += 1;
+            }
+            match next_paging_state {
+                Some(next_paging_state) => paging_state = Some(next_paging_state),
+                None => break,
+            }
+            println!("migrated {} messages", migrated);
+        }
+        Ok(migrated)
+    }
+
+    // Called by the retention sweeper, everything stored about the message goes with it. The
+    // message row goes last so a failed sweep is picked up again by the next one.
+    pub async fn delete_expired(conn: &Connection, message: &Message, blob_store: &dyn BlobStore) -> Result<(), String> {
+        DbQuery::exec_delete(&conn, EDIT_TABLE_NAME, "room_id=? AND msg_id=?", query_values!(message.room_id.clone(), message.msg_id)).await?;
+        DbQuery::exec_delete(&conn, REACTION_TABLE_NAME, "room_id=? AND msg_id=?", query_values!(message.room_id.clone(), message.msg_id)).await?;
+        DbQuery::exec_delete(&conn, PIN_TABLE_NAME, "room_id=? AND msg_id=?", query_values!(message.room_id.clone(), message.msg_id)).await?;
+        DbQuery::exec_delete(&conn, MSG_ID_TABLE_NAME, "room_id=? AND msg_id=?", query_values!(message.room_id.clone(), message.msg_id)).await?;
+        if let Some(mentions) = &message.mentions {
+            Mention::remove(&conn, message.app_id, mentions, message.msg_time).await?;
+        }
+        match message.reply_on_id {
+            Some(parent_id) => Thread::remove_reply(&conn, &message.room_id, parent_id, message.msg_time).await?,
+            None => Thread::remove_thread(&conn, &message.room_id, message.msg_id).await?,
+        }
+        if let Some(attachment_id) = message.attachment_id {
+            Attachment::purge(&conn, attachment_id, blob_store).await?;
+        }
+        DbQuery::delete(&conn, TABLE_NAME, "room_id=? AND bucket=? AND msg_time=? IF EXISTS", query_values!(message.room_id.clone(), message.bucket, message.msg_time)).await?;
+        Ok(())
+    }
+
+    // Rewrites the room's messages that carry a TTL without one, called when a legal hold is
+    // placed so nothing expires before the next sweep. Returns how many were rewritten.
+    pub async fn clear_expiry(conn: &Connection, room_id: &str) -> Result<u64, String> {
+        let mut rewritten: u64 = 0;
+        for bucket in Self::find_buckets(&conn, room_id).await? {
+            let mut paging_state: Option<Vec<u8>> = None;
+            loop {
+                let (rows, next_paging_state) = DbQuery::get_page(&conn, TABLE_NAME, "*", "room_id=? AND bucket=?", query_values!(room_id.to_string(), bucket), MIGRATION_PAGE_SIZE, paging_state.take()).await?;
+                for row in rows {
+                    let message = Message::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+                    if message.expires_at.is_some() {
+                        Self::rewrite_with_expiry(&conn, &message, None).await?;
+                        rewritten += 1;
+                    }
+                }
+                match next_paging_state {
+                    Some(next_paging_state) => paging_state = Some(next_paging_state),
+                    None => break,
+                }
+            }
+        }
+        Ok(rewritten)
+    }
+
+    // Writes the whole row and its edit history again with a TTL up to expires_at, or without
+    // one, as a TTL can't be changed in place.
+    pub async fn rewrite_with_expiry(conn: &Connection, message: &Message, expires_at: Option<i64>) -> Result<(), String> {
+        let ttl_secs = retention::ttl_secs(expires_at);
+        let m = message.clone();
+        DbQuery::upsert_with_ttl(&conn, TABLE_NAME, "app_id, room_id, msg_owner, owner_name, msg_id, msg_time, bucket, reply_on_id, content, url, attachment_id, mentions, read_by_users, message_type, system_message, system_template, system_data, status, send_at, edited_at, deleted_at, deleted_by, last_reply_at, expires_at, disappear_at, updated_at, created_at", "?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?", query_values!(m.app_id, m.room_id, m.msg_owner, m.owner_name, m.msg_id, m.msg_time, m.bucket, m.reply_on_id, m.content, m.url, m.attachment_id, m.mentions, m.read_by_users, m.message_type, m.system_message, m.system_template, m.system_data, m.status, m.send_at, m.edited_at, m.deleted_at, m.deleted_by, m.last_reply_at, expires_at, m.disappear_at, m.updated_at, m.created_at), ttl_secs).await?;
+        let rows = DbQuery::get_rows(&conn, EDIT_TABLE_NAME, "edited_at, editor, previous_content, content", "room_id=? AND msg_id=?", query_values!(message.room_id.clone(), message.msg_id)).await?;
+        for row in rows {
+            let edit = MessageEdit::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+            DbQuery::upsert_with_ttl(&conn, EDIT_TABLE_NAME, "room_id, msg_id, edited_at, editor, previous_content, content", "?, ?, ?, ?, ?, ?", query_values!(message.room_id.clone(), message.msg_id, edit.edited_at, edit.editor, edit.previous_content, edit.content), ttl_secs).await?;
+        }
+        Ok(())
+    }
+
+    // Rebuilds the search index from the messages table, for indexes lost or created after
+    // messages were already stored. Committed once at the end so searches see the old index
+    // until then. The server has to be stopped, it holds the index writer lock.
+    pub async fn reindex_search(conn: &Connection) -> Result<u64, String> {
+        let search_index = SearchIndex::try_global()?;
+        // documents of messages that are gone from the table would otherwise stay
+        search_index.remove_all()?;
+        let mut indexed: u64 = 0;
+        let mut paging_state: Option<Vec<u8>> = None;
+        loop {
+            let (rows, next_paging_state) = DbQuery::get_page(&conn, TABLE_NAME, "app_id, room_id, msg_owner, msg_id, msg_time, content, system_message, status, created_at", "", QueryValues::SimpleValues(vec![]), MIGRATION_PAGE_SIZE, paging_state.take()).await?;
+            for row in rows {
+                let row = SearchScanRow::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+                if row.system_message || row.status != MESSAGE_STATUS_ACTIVE {
+                    continue;
+                }
+                search_index.index_message(IndexedMessage {
+                    app_id: row.app_id,
+                    room_id: row.room_id,
+                    msg_owner: row.msg_owner,
+                    msg_id: row.msg_id.to_string(),
+                    msg_time: row.msg_time.to_string(),
+                    content: row.content,
+                    created_at: row.created_at,
+                })?;
+                indexed += 1;
+            }
+            match next_paging_state {
+                Some(next_paging_state) => paging_state = Some(next_paging_state),
+                None => break,
+            }
+            println!("indexed {} messages", indexed);
+        }
+        search_index.commit_if_dirty()?;
+        Ok(indexed)
+    }
 }
\ No newline at end of file
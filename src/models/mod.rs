@@ -3,5 +3,15 @@ pub mod user_token;
 pub mod response;
 pub mod common;
 pub mod app_user;
+pub mod attachment;
 pub mod chat_room;
+pub mod event;
+pub mod mention;
 pub mod messages;
+pub mod pin;
+pub mod presence;
+pub mod reaction;
+pub mod retention;
+pub mod search;
+pub mod system_message;
+pub mod thread;
@@ -0,0 +1,124 @@
+use crate::{
+    config::db::{Connection, RedisPool},
+    constants,
+    models::{
+        common::DbQuery,
+        chat_room::ChatRoom,
+        event::{RoomEvent, EVENT_MESSAGE_PINNED, EVENT_MESSAGE_UNPINNED},
+        messages::{Message, MESSAGE_STATUS_ACTIVE},
+        system_message::{SystemMessage, TEMPLATE_MESSAGE_PINNED},
+    },
+};
+use cdrs::{
+    query::*,
+    types::{
+        from_cdrs::FromCDRSByName,
+        prelude::*,
+    }
+};
+use std::collections::HashSet;
+use std::result::Result;
+use uuid::Uuid;
+
+// table name
+pub const PIN_TABLE_NAME: &str = "chat_room_pinned_messages";
+pub const PIN_MAX_PER_ROOM: i64 = 50;
+
+#[derive(Clone, Debug, TryFromRow, Serialize, Deserialize)]
+pub struct PinnedMessage {
+    pub msg_id: Uuid,
+    pub msg_time: Uuid,
+    pub pinned_by: String,
+    pub pinned_at: i64,
+}
+// POST/DELETE api/messages/{msg_id}/pin body
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PinBody {
+    pub room_id: String,
+    // has to be the room owner
+    pub user_id: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PinMessage {
+    pub msg_id: Uuid,
+    pub room_id: String,
+    pub user_id: String,
+}
+
+pub struct Pin;
+
+impl Pin {
+    // newest pin first
+    pub async fn find_by_room_id(conn: &Connection, room_id: &str) -> Result<Vec<PinnedMessage>, String> {
+        let rows = DbQuery::get_rows(&conn, PIN_TABLE_NAME, "msg_id, msg_time, pinned_by, pinned_at", "room_id=?", query_values!(room_id.to_string())).await?;
+        let mut pins: Vec<PinnedMessage> = vec![];
+        for row in rows {
+            pins.push(PinnedMessage::try_from_row(row).map_err(|err| format!("{:?}", err))?)
+        }
+        pins.sort_by_key(|pin| std::cmp::Reverse(pin.pinned_at));
+        Ok(pins)
+    }
+
+    pub async fn find_pinned_msg_ids(conn: &Connection, room_id: &str) -> Result<HashSet<Uuid>, String> {
+        Ok(Self::find_by_room_id(&conn, room_id).await?.into_iter().map(|pin| pin.msg_id).collect())
+    }
+
+    // only active messages can be pinned, a deleted one can still be unpinned
+    async fn find_message(conn: &Connection, msg: &PinMessage, active_only: bool) -> Result<Message, String> {
+        if ChatRoom::find_room_owner(&conn, &msg.room_id).await?.as_ref() != Some(&msg.user_id) {
+            return Err(constants::MESSAGE_PIN_FORBIDDEN.to_string())
+        }
+        match Message::find_message(&conn, msg.msg_id).await? {
+            Some(message) if message.room_id == msg.room_id && (!active_only || message.status == MESSAGE_STATUS_ACTIVE) => Ok(message),
+            _ => Err(constants::MESSAGE_MSG_NOT_FOUND.to_string()),
+        }
+    }
+
+    // Pinning an already pinned message keeps the original pin and posts nothing.
+    pub async fn pin(conn: &Connection, msg: PinMessage, redis_pool: &RedisPool) -> Result<PinnedMessage, String> {
+        let message = Self::find_message(&conn, &msg, true).await?;
+        let pins = Self::find_by_room_id(&conn, &msg.room_id).await?;
+        if let Some(pin) = pins.iter().find(|pin| pin.msg_id == message.msg_id) {
+            return Ok(pin.clone())
+        }
+        if pins.len() as i64 >= PIN_MAX_PER_ROOM {
+            return Err(constants::MESSAGE_PIN_LIMIT_REACHED.to_string())
+        }
+        let pin = PinnedMessage {
+            msg_id: message.msg_id,
+            msg_time: message.msg_time,
+            pinned_by: msg.user_id,
+            pinned_at: chrono::Utc::now().timestamp_millis(),
+        };
+        let pinned = DbQuery::insert(&conn, PIN_TABLE_NAME, "room_id, msg_id, msg_time, pinned_by, pinned_at", "?, ?, ?, ?, ?", query_values!("room_id" => message.room_id.clone(), "msg_id" => pin.msg_id, "msg_time" => pin.msg_time, "pinned_by" => pin.pinned_by.clone(), "pinned_at" => pin.pinned_at)).await?;
+        if pinned {
+            let user_ids = ChatRoom::find_room_user_ids(&redis_pool, message.app_id, &message.room_id);
+            if let Err(err) = RoomEvent::publish(&redis_pool, message.app_id, &message.room_id, &user_ids, EVENT_MESSAGE_PINNED, serde_json::to_value(&pin).unwrap_or(json!({}))) {
+                println!("can't publish pin event, error - {:?}", &err);
+            }
+            let system = SystemMessage::new(TEMPLATE_MESSAGE_PINNED, &pin.pinned_by).with("msg_id", &pin.msg_id.to_string());
+            if let Err(err) = Message::add_system_msg(&conn, message.app_id, &message.room_id, system, &redis_pool).await {
+                println!("can't add pin system message, error - {:?}", &err);
+            }
+        }
+        Ok(pin)
+    }
+
+    pub async fn unpin(conn: &Connection, msg: PinMessage, redis_pool: &RedisPool) -> Result<(), String> {
+        let message = Self::find_message(&conn, &msg, false).await?;
+        Self::remove(&conn, &message, &msg.user_id, &redis_pool).await?;
+        Ok(())
+    }
+
+    // Also called when the message is deleted for everyone, returns whether it was pinned.
+    pub async fn remove(conn: &Connection, message: &Message, unpinned_by: &str, redis_pool: &RedisPool) -> Result<bool, String> {
+        let unpinned = DbQuery::delete(&conn, PIN_TABLE_NAME, "room_id=? AND msg_id=? IF EXISTS", query_values!(message.room_id.clone(), message.msg_id)).await?;
+        if unpinned {
+            let user_ids = ChatRoom::find_room_user_ids(&redis_pool, message.app_id, &message.room_id);
+            if let Err(err) = RoomEvent::publish(&redis_pool, message.app_id, &message.room_id, &user_ids, EVENT_MESSAGE_UNPINNED, json!({ "msg_id": message.msg_id, "msg_time": message.msg_time, "unpinned_by": unpinned_by })) {
+                println!("can't publish unpin event, error - {:?}", &err);
+            }
+        }
+        Ok(unpinned)
+    }
+}
@@ -0,0 +1,95 @@
+use crate::config::db::RedisPool;
+use r2d2_redis::redis::{self, Commands};
+use serde_json::Value;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PresenceEvent {
+    Connected,
+    Disconnected,
+    LastWill,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PresenceUpdate {
+    pub app_id: i64,
+    pub user_id: String,
+    pub device_id: String,
+    pub event: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserPresence {
+    pub user_id: String,
+    pub is_online: bool,
+    pub devices: i64,
+    pub last_seen: i64,
+}
+
+impl PresenceEvent {
+    pub fn from_payload(payload: &str) -> Option<PresenceEvent> {
+        match payload.trim() {
+            "connected" | "online" => Some(PresenceEvent::Connected),
+            "disconnected" | "offline" => Some(PresenceEvent::Disconnected),
+            "lwt" => Some(PresenceEvent::LastWill),
+            _ => None
+        }
+    }
+}
+
+pub struct Presence;
+
+impl Presence {
+    // redis key holding the user json read by api/app-users/online/{app_id}
+    pub fn user_key(app_id: i64, user_id: &str) -> String {
+        format!("user_{:?}_{}", app_id, user_id)
+    }
+    // redis set of the device ids a user currently has connected
+    pub fn devices_key(app_id: i64, user_id: &str) -> String {
+        format!("presence_{:?}_{}", app_id, user_id)
+    }
+
+    // Runs as a WATCH/MULTI transaction over the device set and the user json, retried when
+    // another device of the same user changed either in between.
+    pub fn update(redis_pool: &RedisPool, app_id: i64, user_id: &str, device_id: &str, event: PresenceEvent) -> Result<UserPresence, String> {
+        let mut redis_conn = redis_pool.get().map_err(|err| err.to_string())?;
+        let devices_key = Self::devices_key(app_id, user_id);
+        let user_key = Self::user_key(app_id, user_id);
+        redis::transaction(&mut *redis_conn, &[devices_key.clone(), user_key.clone()], |conn, pipe| {
+            let is_member: bool = conn.sismember(devices_key.clone(), device_id.to_string())?;
+            let mut devices: i64 = conn.scard(devices_key.clone())?;
+            match event {
+                PresenceEvent::Connected => {
+                    if !is_member {
+                        devices += 1;
+                    }
+                    pipe.sadd(devices_key.clone(), device_id.to_string()).ignore();
+                },
+                PresenceEvent::Disconnected | PresenceEvent::LastWill => {
+                    if is_member {
+                        devices -= 1;
+                    }
+                    pipe.srem(devices_key.clone(), device_id.to_string()).ignore();
+                }
+            }
+            let presence = UserPresence {
+                user_id: user_id.to_string(),
+                is_online: devices > 0,
+                devices,
+                last_seen: chrono::Utc::now().timestamp_millis(),
+            };
+
+            // keep any fields other writers stored on the user and only touch the presence ones
+            let user: Option<String> = conn.get(user_key.clone())?;
+            let mut user_data = match user.map(|user| serde_json::from_str::<Value>(&user)) {
+                Some(Ok(Value::Object(map))) => Value::Object(map),
+                _ => json!({ "id": user_id }),
+            };
+            user_data["isOnline"] = json!(presence.is_online);
+            user_data["devices"] = json!(presence.devices);
+            user_data["lastSeen"] = json!(presence.last_seen);
+            pipe.set(user_key.clone(), user_data.to_string()).ignore();
+            let applied: Option<()> = pipe.query(conn)?;
+            Ok(applied.map(|_| presence))
+        }).map_err(|err| err.to_string())
+    }
+}
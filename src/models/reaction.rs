@@ -0,0 +1,151 @@
+use crate::{
+    config::db::{Connection, RedisPool},
+    constants,
+    models::{
+        common::DbQuery,
+        chat_room::ChatRoom,
+        event::{RoomEvent, EVENT_MESSAGE_REACTION},
+        messages::{Message, MESSAGE_STATUS_ACTIVE},
+    },
+};
+use cdrs::{
+    query::*,
+    types::{
+        from_cdrs::FromCDRSByName,
+        prelude::*,
+    }
+};
+use std::collections::{BTreeMap, HashMap};
+use std::result::Result;
+use uuid::Uuid;
+
+// table name, one row per (message, emoji, user)
+pub const REACTION_TABLE_NAME: &str = "chat_room_message_reactions";
+pub const REACTION_MAX_DISTINCT: usize = 20;
+// an emoji with skin tone and zero width joiners stays well under this
+pub const REACTION_MAX_EMOJI_LEN: usize = 32;
+
+#[derive(Clone, Debug, TryFromRow)]
+pub struct ReactionRow {
+    pub msg_id: Uuid,
+    pub emoji: String,
+    pub user_id: String,
+}
+#[derive(Clone, Debug, TryFromRow)]
+pub struct ReactionTime {
+    pub emoji: String,
+    pub created_at: i64,
+}
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReactionSummary {
+    pub emoji: String,
+    pub count: i64,
+    pub reacted_by_me: bool,
+}
+// POST/DELETE api/messages/{msg_id}/reactions body
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReactionBody {
+    pub room_id: String,
+    pub user_id: String,
+    pub emoji: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReactMessage {
+    pub msg_id: Uuid,
+    pub room_id: String,
+    pub user_id: String,
+    pub emoji: String,
+}
+
+pub struct Reaction;
+
+impl Reaction {
+    // Reaction summaries per message, sorted by emoji so repeated reads come back stable.
+    pub async fn find_summaries(conn: &Connection, room_id: &str, msg_ids: Vec<Uuid>, user_id: Option<&str>) -> Result<HashMap<Uuid, Vec<ReactionSummary>>, String> {
+        let mut summaries: HashMap<Uuid, Vec<ReactionSummary>> = HashMap::new();
+        if msg_ids.is_empty() {
+            return Ok(summaries)
+        }
+        let rows = DbQuery::get_rows(&conn, REACTION_TABLE_NAME, "msg_id, emoji, user_id", "room_id=? AND msg_id IN ?", query_values!(room_id.to_string(), msg_ids)).await?;
+        let mut grouped: HashMap<Uuid, BTreeMap<String, ReactionSummary>> = HashMap::new();
+        for row in rows {
+            let reaction = ReactionRow::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+            let summary = grouped.entry(reaction.msg_id).or_insert_with(BTreeMap::new)
+                .entry(reaction.emoji.clone())
+                .or_insert(ReactionSummary { emoji: reaction.emoji, count: 0, reacted_by_me: false });
+            summary.count += 1;
+            if user_id == Some(reaction.user_id.as_str()) {
+                summary.reacted_by_me = true;
+            }
+        }
+        for (msg_id, by_emoji) in grouped {
+            summaries.insert(msg_id, by_emoji.into_iter().map(|(_, summary)| summary).collect());
+        }
+        Ok(summaries)
+    }
+
+    async fn find_message(conn: &Connection, msg: &ReactMessage) -> Result<Message, String> {
+        match Message::find_message(&conn, msg.msg_id).await? {
+            Some(message) if message.room_id == msg.room_id && message.status == MESSAGE_STATUS_ACTIVE => Ok(message),
+            _ => Err(constants::MESSAGE_MSG_NOT_FOUND.to_string()),
+        }
+    }
+
+    pub async fn add(conn: &Connection, msg: ReactMessage, redis_pool: &RedisPool) -> Result<Vec<ReactionSummary>, String> {
+        let emoji = msg.emoji.trim().to_string();
+        if emoji.is_empty() || emoji.len() > REACTION_MAX_EMOJI_LEN {
+            return Err(constants::MESSAGE_REACTION_INVALID.to_string())
+        }
+        let message = Self::find_message(&conn, &msg).await?;
+        if !ChatRoom::find_room_user_ids(&redis_pool, message.app_id, &message.room_id).contains(&msg.user_id) {
+            return Err(constants::MESSAGE_REACTION_FORBIDDEN.to_string())
+        }
+        let current = Self::find_summaries(&conn, &message.room_id, vec![message.msg_id], None).await?.remove(&message.msg_id).unwrap_or_default();
+        if current.len() >= REACTION_MAX_DISTINCT && !current.iter().any(|summary| summary.emoji == emoji) {
+            return Err(constants::MESSAGE_REACTION_LIMIT_REACHED.to_string())
+        }
+        let created_at = chrono::Utc::now().timestamp_millis();
+        let added = DbQuery::insert(&conn, REACTION_TABLE_NAME, "room_id, msg_id, emoji, user_id, created_at", "?, ?, ?, ?, ?", query_values!("room_id" => message.room_id.clone(), "msg_id" => message.msg_id, "emoji" => emoji.clone(), "user_id" => msg.user_id.clone(), "created_at" => created_at)).await?;
+        if added && !current.iter().any(|summary| summary.emoji == emoji) && !Self::within_limit(&conn, &message, &emoji).await? {
+            DbQuery::delete(&conn, REACTION_TABLE_NAME, "room_id=? AND msg_id=? AND emoji=? AND user_id=? IF EXISTS", query_values!(message.room_id.clone(), message.msg_id, emoji.clone(), msg.user_id.clone())).await?;
+            return Err(constants::MESSAGE_REACTION_LIMIT_REACHED.to_string())
+        }
+        Self::publish_change(&conn, &message, &msg.user_id, &emoji, added, "added", &redis_pool).await
+    }
+
+    // Concurrent adds of new emojis can all pass the check before the insert, re-checked after
+    // it the REACTION_MAX_DISTINCT emojis first used earliest keep their place.
+    async fn within_limit(conn: &Connection, message: &Message, emoji: &str) -> Result<bool, String> {
+        let rows = DbQuery::get_rows(&conn, REACTION_TABLE_NAME, "emoji, created_at", "room_id=? AND msg_id=?", query_values!(message.room_id.clone(), message.msg_id)).await?;
+        let mut first_used: HashMap<String, i64> = HashMap::new();
+        for row in rows {
+            let reaction = ReactionTime::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+            let used_at = first_used.entry(reaction.emoji).or_insert(reaction.created_at);
+            *used_at = std::cmp::min(*used_at, reaction.created_at);
+        }
+        let mut emojis: Vec<(i64, String)> = first_used.into_iter().map(|(emoji, used_at)| (used_at, emoji)).collect();
+        emojis.sort();
+        Ok(emojis.iter().position(|(_, used)| used == emoji).map_or(true, |position| position < REACTION_MAX_DISTINCT))
+    }
+
+    pub async fn remove(conn: &Connection, msg: ReactMessage, redis_pool: &RedisPool) -> Result<Vec<ReactionSummary>, String> {
+        let emoji = msg.emoji.trim().to_string();
+        let message = Self::find_message(&conn, &msg).await?;
+        let removed = DbQuery::delete(&conn, REACTION_TABLE_NAME, "room_id=? AND msg_id=? AND emoji=? AND user_id=? IF EXISTS", query_values!(message.room_id.clone(), message.msg_id, emoji.clone(), msg.user_id.clone())).await?;
+        Self::publish_change(&conn, &message, &msg.user_id, &emoji, removed, "removed", &redis_pool).await
+    }
+
+    // Returns the summaries as seen by the reacting user. Room members only hear about
+    // reactions that actually changed something.
+    async fn publish_change(conn: &Connection, message: &Message, user_id: &str, emoji: &str, changed: bool, action: &str, redis_pool: &RedisPool) -> Result<Vec<ReactionSummary>, String> {
+        let reactions = Self::find_summaries(&conn, &message.room_id, vec![message.msg_id], Some(user_id)).await?.remove(&message.msg_id).unwrap_or_default();
+        if changed {
+            let counts: Vec<_> = reactions.iter().map(|summary| json!({ "emoji": summary.emoji, "count": summary.count })).collect();
+            let user_ids = ChatRoom::find_room_user_ids(&redis_pool, message.app_id, &message.room_id);
+            if let Err(err) = RoomEvent::publish(&redis_pool, message.app_id, &message.room_id, &user_ids, EVENT_MESSAGE_REACTION, json!({ "msg_id": message.msg_id, "msg_time": message.msg_time, "user_id": user_id, "emoji": emoji, "action": action, "reactions": counts })) {
+                println!("can't publish reaction event, error - {:?}", &err);
+            }
+        }
+        Ok(reactions)
+    }
+}
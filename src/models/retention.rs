@@ -0,0 +1,281 @@
+use crate::{
+    config::db::{Connection, Pool},
+    constants,
+    models::{
+        chat_room::ChatRoom,
+        common::DbQuery,
+        messages::{Message, TABLE_NAME as MESSAGE_TABLE_NAME},
+    },
+    utils::{blob_store::BlobStore, search_index::SearchIndex, time_uuid},
+};
+use cdrs::{
+    query::*,
+    types::{
+        from_cdrs::FromCDRSByName,
+        prelude::*,
+    }
+};
+use std::collections::HashMap;
+use futures::executor;
+use std::result::Result;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// table name, (app_id, room_id) with room_id "" for the app wide policy
+pub const TABLE_NAME: &str = "chat_retention_policies";
+pub const APP_POLICY_ROOM_ID: &str = "";
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+// longest TTL Cassandra accepts
+const MAX_TTL_SECS: i64 = 20 * 365 * SECONDS_PER_DAY;
+const SWEEP_PAGE_SIZE: i32 = 500;
+// a legal hold job retries failed rooms this often before leaving them to the sweeper
+const HOLD_MAX_ATTEMPTS: u64 = 5;
+const HOLD_RETRY_DELAY_SECS: u64 = 30;
+
+#[derive(Clone, Debug, Default, TryFromRow, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub app_id: i64,
+    // left out for the app wide policy, a room policy replaces it for that room
+    #[serde(default)]
+    pub room_id: String,
+    // messages are deleted this many days after they were sent
+    pub retention_days: Option<i32>,
+    // disappearing messages, deleted this many seconds after they were sent
+    pub disappear_after_secs: Option<i32>,
+    // deleted this many seconds after someone other than the sender read them
+    pub disappear_after_read_secs: Option<i32>,
+    // keeps every message until lifted, on the app policy it holds all rooms of the app
+    #[serde(default)]
+    pub legal_hold: bool,
+}
+// GET api/retention/policies/{app_id} query
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RetentionPolicyQuery {
+    pub room_id: Option<String>,
+}
+
+impl RetentionPolicy {
+    pub fn default_for(app_id: i64, room_id: &str) -> RetentionPolicy {
+        RetentionPolicy { app_id, room_id: room_id.to_string(), ..Default::default() }
+    }
+
+    // The room policy if there is one, else the app policy. A legal hold on either applies.
+    pub async fn find_effective(conn: &Connection, app_id: i64, room_id: &str) -> Result<RetentionPolicy, String> {
+        let rows = DbQuery::get_rows(&conn, TABLE_NAME, "app_id, room_id, retention_days, disappear_after_secs, disappear_after_read_secs, legal_hold", "app_id=? AND room_id IN ?", query_values!(app_id, vec![APP_POLICY_ROOM_ID.to_string(), room_id.to_string()])).await?;
+        let mut app_policy: Option<RetentionPolicy> = None;
+        let mut room_policy: Option<RetentionPolicy> = None;
+        for row in rows {
+            let policy = RetentionPolicy::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+            if policy.room_id == APP_POLICY_ROOM_ID {
+                app_policy = Some(policy);
+            } else {
+                room_policy = Some(policy);
+            }
+        }
+        let app_hold = app_policy.as_ref().map_or(false, |policy| policy.legal_hold);
+        let mut policy = room_policy.or(app_policy).unwrap_or_else(|| Self::default_for(app_id, APP_POLICY_ROOM_ID));
+        policy.legal_hold = policy.legal_hold || app_hold;
+        Ok(policy)
+    }
+
+    pub async fn save(conn: &Connection, policy: RetentionPolicy) -> Result<RetentionPolicy, String> {
+        let limits = vec![policy.retention_days, policy.disappear_after_secs, policy.disappear_after_read_secs];
+        if limits.iter().any(|limit| limit.map_or(false, |limit| limit <= 0)) {
+            return Err(constants::MESSAGE_RETENTION_POLICY_INVALID.to_string())
+        }
+        DbQuery::upsert(&conn, TABLE_NAME, "app_id, room_id, retention_days, disappear_after_secs, disappear_after_read_secs, legal_hold, updated_at", "?, ?, ?, ?, ?, ?, toTimestamp(now())", query_values!("app_id" => policy.app_id, "room_id" => policy.room_id.clone(), "retention_days" => policy.retention_days, "disappear_after_secs" => policy.disappear_after_secs, "disappear_after_read_secs" => policy.disappear_after_read_secs, "legal_hold" => policy.legal_hold)).await?;
+        Ok(policy)
+    }
+
+    // When a message sent at sent_at (msg_time millis) expires, None while on legal hold or without limits.
+    // disappear_at is set once the message was read under disappear_after_read_secs.
+    pub fn expires_at(&self, sent_at: i64, disappear_at: Option<i64>) -> Option<i64> {
+        if self.legal_hold {
+            return None
+        }
+        vec![
+            self.retention_days.map(|days| sent_at + days as i64 * SECONDS_PER_DAY * 1000),
+            self.disappear_after_secs.map(|secs| sent_at + secs as i64 * 1000),
+            disappear_at,
+        ].into_iter().filter_map(|expires_at| expires_at).min()
+    }
+
+    // read time of the first reader other than the sender plus disappear_after_read_secs
+    pub fn disappear_at(&self, read_at: i64) -> Option<i64> {
+        match self.legal_hold {
+            true => None,
+            false => self.disappear_after_read_secs.map(|secs| read_at + secs as i64 * 1000),
+        }
+    }
+}
+
+// TTL for a write to a row that expires at expires_at, at least a second as 0 would keep it.
+pub fn ttl_secs(expires_at: Option<i64>) -> Option<i32> {
+    expires_at.map(|expires_at| {
+        let remaining = (expires_at - chrono::Utc::now().timestamp_millis() + 999) / 1000;
+        std::cmp::min(std::cmp::max(remaining, 1), MAX_TTL_SECS) as i32
+    })
+}
+
+pub fn is_expired(expires_at: Option<i64>) -> bool {
+    expires_at.map_or(false, |expires_at| expires_at <= chrono::Utc::now().timestamp_millis())
+}
+
+pub struct Retention;
+
+enum SweepAction {
+    Deleted,
+    Rewritten,
+    Kept,
+}
+
+impl Retention {
+    // Sweeps every interval_secs. TTLs set on insert cover the policy at send time, the sweeper
+    // applies later policy changes, read timers and legal holds to stored messages. Its own
+    // actix system runs the blob store's http client.
+    pub fn start_sweeper(pool: Pool, blob_store: Arc<dyn BlobStore>, interval_secs: u64) {
+        thread::spawn(move || {
+            let mut system = actix_rt::System::new("retention-sweeper");
+            loop {
+                thread::sleep(Duration::from_secs(interval_secs));
+                let (pool, blob_store) = (pool.clone(), blob_store.clone());
+                match system.block_on(async move { Self::sweep(&pool, blob_store.as_ref()).await }) {
+                    Ok((deleted, rewritten, failed)) => println!("retention sweep deleted {} and rewrote {} messages, {} failed", deleted, rewritten, failed),
+                    Err(err) => println!("retention sweep failed, error - {:?}", &err),
+                }
+            }
+        });
+    }
+
+    // Clears the TTLs of the messages a new legal hold covers, in the background as an app hold
+    // rewrites every room of the app. Rooms that keep failing are left to the next sweep.
+    pub fn start_hold(pool: Pool, policy: &RetentionPolicy) {
+        let (app_id, room_id) = (policy.app_id, policy.room_id.clone());
+        thread::spawn(move || executor::block_on(async move {
+            let mut pending: Option<Vec<String>> = None;
+            for attempt in 0..HOLD_MAX_ATTEMPTS {
+                if attempt > 0 {
+                    thread::sleep(Duration::from_secs(attempt * HOLD_RETRY_DELAY_SECS));
+                }
+                let room_ids = match pending.take() {
+                    Some(room_ids) => room_ids,
+                    None => match Self::held_room_ids(&pool, app_id, &room_id).await {
+                        Ok(room_ids) => room_ids,
+                        Err(err) => {
+                            println!("can't find rooms under the legal hold of app {}, error - {:?}", app_id, &err);
+                            continue;
+                        },
+                    },
+                };
+                let mut failed: Vec<String> = vec![];
+                for room_id in room_ids {
+                    if let Err(err) = Message::clear_expiry(&pool, &room_id).await {
+                        println!("can't clear TTLs of room {} under legal hold, error - {:?}", room_id, &err);
+                        failed.push(room_id);
+                    }
+                }
+                if failed.is_empty() {
+                    return;
+                }
+                pending = Some(failed);
+            }
+            println!("legal hold of app {} left to the sweeper, rooms {:?}", app_id, pending);
+        }));
+    }
+
+    async fn held_room_ids(conn: &Connection, app_id: i64, room_id: &str) -> Result<Vec<String>, String> {
+        match room_id {
+            APP_POLICY_ROOM_ID => ChatRoom::find_app_room_ids(&conn, app_id).await,
+            room_id => Ok(vec![room_id.to_string()]),
+        }
+    }
+
+    // Deletes messages past their expiry and rewrites those whose expiry moved, returns how
+    // many of each and how many failed. A failing message is logged and skipped so it can't
+    // hold up retention of every message scanned after it.
+    pub async fn sweep(conn: &Connection, blob_store: &dyn BlobStore) -> Result<(u64, u64, u64), String> {
+        let (mut deleted, mut rewritten, mut failed) = (0u64, 0u64, 0u64);
+        let mut policies: HashMap<String, RetentionPolicy> = HashMap::new();
+        let mut paging_state: Option<Vec<u8>> = None;
+        loop {
+            // every column, an expiry that moved rewrites the whole row
+            let (rows, next_paging_state) = DbQuery::get_page(&conn, MESSAGE_TABLE_NAME, "*", "", QueryValues::SimpleValues(vec![]), SWEEP_PAGE_SIZE, paging_state.take()).await?;
+            for row in rows {
+                match Self::sweep_message(&conn, row, &mut policies, blob_store).await {
+                    Ok(SweepAction::Deleted) => deleted += 1,
+                    Ok(SweepAction::Rewritten) => rewritten += 1,
+                    Ok(SweepAction::Kept) => {},
+                    Err(err) => {
+                        println!("retention sweep skipped a message, error - {:?}", &err);
+                        failed += 1;
+                    },
+                }
+            }
+            match next_paging_state {
+                Some(next_paging_state) => paging_state = Some(next_paging_state),
+                None => break,
+            }
+        }
+        SearchIndex::global().commit_if_dirty()?;
+        Ok((deleted, rewritten, failed))
+    }
+
+    async fn sweep_message(conn: &Connection, row: Row, policies: &mut HashMap<String, RetentionPolicy>, blob_store: &dyn BlobStore) -> Result<SweepAction, String> {
+        let message = Message::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+        if !policies.contains_key(&message.room_id) {
+            let policy = RetentionPolicy::find_effective(&conn, message.app_id, &message.room_id).await?;
+            policies.insert(message.room_id.clone(), policy);
+        }
+        // from the send time like on insert, so rows whose policy didn't change compare equal
+        let expires_at = policies[&message.room_id].expires_at(time_uuid::timestamp_millis(&message.msg_time), message.disappear_at);
+        if is_expired(expires_at) {
+            Message::delete_expired(&conn, &message, blob_store).await?;
+            SearchIndex::global().remove_message(&message.msg_id.to_string());
+            Ok(SweepAction::Deleted)
+        } else if expires_at != message.expires_at {
+            Message::rewrite_with_expiry(&conn, &message, expires_at).await?;
+            Ok(SweepAction::Rewritten)
+        } else {
+            Ok(SweepAction::Kept)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(retention_days: Option<i32>, disappear_after_secs: Option<i32>, legal_hold: bool) -> RetentionPolicy {
+        RetentionPolicy { retention_days, disappear_after_secs, disappear_after_read_secs: Some(60), legal_hold, ..RetentionPolicy::default_for(1, "r1") }
+    }
+
+    #[test]
+    fn expires_at_the_earliest_limit() {
+        assert_eq!(policy(None, None, false).expires_at(1000, None), None);
+        assert_eq!(policy(Some(1), None, false).expires_at(1000, None), Some(1000 + SECONDS_PER_DAY * 1000));
+        assert_eq!(policy(Some(1), Some(10), false).expires_at(1000, None), Some(11_000));
+        assert_eq!(policy(Some(1), Some(10), false).expires_at(1000, Some(5000)), Some(5000));
+    }
+
+    #[test]
+    fn legal_hold_keeps_everything() {
+        assert_eq!(policy(Some(1), Some(10), true).expires_at(1000, Some(5000)), None);
+        assert_eq!(policy(None, None, true).disappear_at(1000), None);
+        assert_eq!(policy(None, None, false).disappear_at(1000), Some(61_000));
+    }
+
+    #[test]
+    fn ttl_rounds_up_and_stays_in_range() {
+        let now = chrono::Utc::now().timestamp_millis();
+        assert_eq!(ttl_secs(None), None);
+        // already expired rows still get a TTL, 0 would keep them
+        assert_eq!(ttl_secs(Some(now - 10_000)), Some(1));
+        let ttl = ttl_secs(Some(now + 10_500)).unwrap();
+        assert!(ttl == 11 || ttl == 10);
+        assert_eq!(ttl_secs(Some(i64::max_value() / 2)), Some(MAX_TTL_SECS as i32));
+        assert!(is_expired(Some(now - 1)));
+        assert!(!is_expired(None));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{
+    config::db::Connection,
+    constants,
+    models::{
+        chat_room::ChatRoom,
+        messages::Message,
+    },
+    utils::search_index::{SearchFilter, SearchHit, SearchIndex},
+};
+use std::collections::{HashMap, HashSet};
+use std::result::Result;
+use uuid::Uuid;
+
+pub const SEARCH_PAGE_DEFAULT_LIMIT: usize = 20;
+pub const SEARCH_PAGE_MAX_LIMIT: usize = 100;
+
+// GET api/search/messages query, from/to are created_at millis, to is exclusive
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchQuery {
+    pub app_id: i64,
+    // the caller, results are limited to their rooms
+    pub user_id: String,
+    pub q: String,
+    pub room_id: Option<String>,
+    pub sender: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchPage {
+    pub hits: Vec<SearchHit>,
+    // matches before hidden and expired messages are dropped from the page
+    pub total: usize,
+    pub next_offset: Option<usize>,
+}
+
+pub struct Search;
+
+impl Search {
+    pub async fn find_messages(conn: &Connection, query: SearchQuery) -> Result<SearchPage, String> {
+        let member_of = ChatRoom::find_room_ids(&conn, query.app_id, &query.user_id).await?;
+        let room_ids = match query.room_id {
+            Some(room_id) if member_of.contains(&room_id) => vec![room_id],
+            Some(_) => return Err(constants::MESSAGE_SEARCH_ROOM_FORBIDDEN.to_string()),
+            None => member_of,
+        };
+        if room_ids.is_empty() || query.q.trim().is_empty() {
+            return Ok(SearchPage { hits: vec![], total: 0, next_offset: None })
+        }
+        let limit = std::cmp::min(std::cmp::max(query.limit.unwrap_or(SEARCH_PAGE_DEFAULT_LIMIT), 1), SEARCH_PAGE_MAX_LIMIT);
+        let offset = query.offset.unwrap_or(0);
+        let filter = SearchFilter {
+            app_id: query.app_id,
+            room_ids,
+            sender: query.sender,
+            from: query.from,
+            to: query.to,
+        };
+        let (hits, total) = SearchIndex::global().search(&query.q, filter, limit, offset)?;
+        let next_offset = match offset + hits.len() < total {
+            true => Some(offset + hits.len()),
+            false => None,
+        };
+        // messages the caller deleted for themselves stay out of their results
+        let mut hidden: HashMap<String, HashSet<Uuid>> = HashMap::new();
+        let mut visible: Vec<SearchHit> = vec![];
+        for hit in hits {
+            if !hidden.contains_key(&hit.room_id) {
+                let msg_ids = Message::find_hidden_msg_ids(&conn, &hit.room_id, &query.user_id).await?;
+                hidden.insert(hit.room_id.clone(), msg_ids);
+            }
+            let is_hidden = Uuid::parse_str(&hit.msg_id).map_or(false, |msg_id| hidden[&hit.room_id].contains(&msg_id));
+            if is_hidden {
+                continue;
+            }
+            // expired by a retention TTL, which the index doesn't see
+            let msg_time = Uuid::parse_str(&hit.msg_time).map_err(|err| format!("{:?}", err))?;
+            match Message::find_by_msg_time(&conn, &hit.room_id, msg_time).await? {
+                Some(_) => visible.push(hit),
+                None => SearchIndex::global().remove_message(&hit.msg_id),
+            }
+        }
+        Ok(SearchPage { hits: visible, total, next_offset })
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+// system message templates, sent as system_template with the values in system_data so
+// clients can show their own translation. Values are user ids unless the key ends in _name(s).
+pub const TEMPLATE_ROOM_CREATED: &str = "room_created";
+pub const TEMPLATE_USERS_ADDED: &str = "users_added";
+pub const TEMPLATE_USER_REMOVED: &str = "user_removed";
+pub const TEMPLATE_USER_LEFT: &str = "user_left";
+pub const TEMPLATE_ROOM_RENAMED: &str = "room_renamed";
+pub const TEMPLATE_BANNER_CHANGED: &str = "banner_changed";
+pub const TEMPLATE_BANNER_REMOVED: &str = "banner_removed";
+pub const TEMPLATE_MESSAGE_PINNED: &str = "message_pinned";
+
+// English text stored as the message content, "{key}" is replaced with data[key]
+fn template_text(template: &str) -> &'static str {
+    match template {
+        TEMPLATE_ROOM_CREATED => "{actor_name} created the room {room_name}",
+        TEMPLATE_USERS_ADDED => "{actor_name} added {user_names}",
+        TEMPLATE_USER_REMOVED => "{actor_name} removed {user_id}",
+        TEMPLATE_USER_LEFT => "{actor_name} left the room",
+        TEMPLATE_ROOM_RENAMED => "{actor_name} renamed the room from {old_room_name} to {room_name}",
+        TEMPLATE_BANNER_CHANGED => "{actor_name} changed the room banner",
+        TEMPLATE_BANNER_REMOVED => "{actor_name} removed the room banner",
+        TEMPLATE_MESSAGE_PINNED => "{actor_name} pinned a message",
+        _ => "",
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SystemMessage {
+    pub template: &'static str,
+    pub data: HashMap<String, String>,
+}
+
+impl SystemMessage {
+    // actor is the user that caused the message, shown by id until a name is given with `actor_name`
+    pub fn new(template: &'static str, actor: &str) -> SystemMessage {
+        let mut data: HashMap<String, String> = HashMap::new();
+        data.insert("actor".to_string(), actor.to_string());
+        data.insert("actor_name".to_string(), actor.to_string());
+        SystemMessage { template, data }
+    }
+
+    pub fn with(mut self, key: &str, value: &str) -> SystemMessage {
+        self.data.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    // Single pass over the template, so braces inside a value are never taken for placeholders.
+    pub fn render(&self) -> String {
+        let mut rest = template_text(self.template);
+        let mut content = String::with_capacity(rest.len());
+        while let Some(start) = rest.find('{') {
+            content.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let placeholder = rest.find('}').map(|end| (&rest[1..end], end));
+            match placeholder.and_then(|(key, end)| self.data.get(key).map(|value| (value, end))) {
+                Some((value, end)) => {
+                    content.push_str(value);
+                    rest = &rest[end + 1..];
+                },
+                None => {
+                    content.push('{');
+                    rest = &rest[1..];
+                },
+            }
+        }
+        content.push_str(rest);
+        content
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_values_of_the_template_placeholders() {
+        let system = SystemMessage::new(TEMPLATE_ROOM_RENAMED, "u1")
+            .with("actor_name", "Ann")
+            .with("old_room_name", "Old")
+            .with("room_name", "New");
+        assert_eq!(system.render(), "Ann renamed the room from Old to New");
+    }
+
+    #[test]
+    fn values_are_not_rendered_again() {
+        let system = SystemMessage::new(TEMPLATE_ROOM_RENAMED, "u1")
+            .with("old_room_name", "{room_name}")
+            .with("room_name", "{actor_name}");
+        assert_eq!(system.render(), "u1 renamed the room from {room_name} to {actor_name}");
+    }
+
+    #[test]
+    fn keeps_placeholders_without_a_value() {
+        assert_eq!(SystemMessage::new(TEMPLATE_USERS_ADDED, "u1").render(), "u1 added {user_names}");
+        assert_eq!(SystemMessage::new("unknown", "u1").render(), "");
+    }
+}
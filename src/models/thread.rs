@@ -0,0 +1,261 @@
+use crate::{
+    config::db::Connection,
+    constants,
+    models::{
+        common::DbQuery,
+        messages::{self, Message, ResMessage, MESSAGE_PAGE_DEFAULT_LIMIT, MESSAGE_PAGE_MAX_LIMIT},
+        retention,
+    },
+    utils::time_uuid,
+};
+use cdrs::{
+    query::*,
+    types::{
+        from_cdrs::FromCDRSByName,
+        prelude::*,
+    }
+};
+use futures::future;
+use std::collections::HashMap;
+use std::result::Result;
+use uuid::Uuid;
+
+// table names
+// (room_id, parent_id) -> msg_time of each reply, the reply itself stays in the messages table
+pub const REPLY_TABLE_NAME: &str = "chat_room_thread_replies";
+pub const REPLY_COUNT_TABLE_NAME: &str = "chat_room_thread_reply_counts";
+// threads a user replied in or owns the parent of
+pub const PARTICIPANT_TABLE_NAME: &str = "chat_room_thread_participants";
+pub const THREAD_READ_TABLE_NAME: &str = "chat_room_thread_reads";
+
+#[derive(Clone, Debug, TryFromRow)]
+pub struct ThreadReply {
+    pub msg_time: Uuid,
+}
+#[derive(Clone, Debug, TryFromRow)]
+pub struct ReplyCount {
+    pub parent_id: Uuid,
+    pub reply_count: i64,
+}
+#[derive(Clone, Debug, TryFromRow)]
+pub struct LatestReply {
+    pub parent_id: Uuid,
+    pub msg_time: Uuid,
+}
+#[derive(Clone, Debug, TryFromRow)]
+pub struct ThreadRead {
+    pub read_up_to: Uuid,
+}
+#[derive(Clone, Debug, TryFromRow)]
+pub struct ParentThreadRead {
+    pub parent_id: Uuid,
+    pub read_up_to: Uuid,
+}
+#[derive(Clone, Debug, TryFromRow)]
+pub struct ParticipantThread {
+    pub parent_id: Uuid,
+}
+
+// GET api/messages/{room_id}/threads/{msg_id} query, replies run newest first
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadQuery {
+    pub before: Option<Uuid>,
+    pub limit: Option<i32>,
+    // adds the user's unread reply count
+    pub user_id: Option<String>,
+    pub cursor: Option<String>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadPage {
+    pub parent: ResMessage,
+    pub replies: Vec<ResMessage>,
+    pub reply_count: i64,
+    pub unread_count: Option<i64>,
+    pub next_cursor: Option<String>,
+}
+// marks the replies up to and including msg_time as read
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadThread {
+    pub room_id: String,
+    pub parent_id: Uuid,
+    pub user_id: String,
+    pub msg_time: Uuid,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnreadThreadsQuery {
+    pub room_id: String,
+    pub user_id: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnreadThread {
+    pub parent_id: Uuid,
+    pub unread_count: i64,
+    pub last_reply_at: Option<i64>,
+}
+
+pub struct Thread;
+
+impl Thread {
+    // Threads are one level deep, a reply to a reply joins the thread of its root message.
+    pub async fn find_root(conn: &Connection, room_id: &str, reply_on_id: Uuid) -> Result<Message, String> {
+        let parent = match Message::find_message(&conn, reply_on_id).await? {
+            Some(parent) if parent.room_id == room_id => parent,
+            _ => return Err(constants::MESSAGE_MSG_PARENT_NOT_FOUND.to_string()),
+        };
+        match parent.reply_on_id {
+            Some(root_id) => match Message::find_message(&conn, root_id).await? {
+                Some(root) if root.room_id == room_id => Ok(root),
+                _ => Err(constants::MESSAGE_MSG_PARENT_NOT_FOUND.to_string()),
+            },
+            None => Ok(parent),
+        }
+    }
+
+    // Called once the reply is stored.
+    pub async fn add_reply(conn: &Connection, parent: &Message, reply: &ResMessage) -> Result<(), String> {
+        DbQuery::upsert(&conn, REPLY_TABLE_NAME, "room_id, parent_id, msg_time, msg_id", "?, ?, ?, ?", query_values!("room_id" => parent.room_id.clone(), "parent_id" => parent.msg_id, "msg_time" => reply.msg_time, "msg_id" => reply.msg_id)).await?;
+        DbQuery::exec_update(&conn, REPLY_COUNT_TABLE_NAME, "reply_count = reply_count + 1", "room_id=? AND parent_id=?", query_values!("room_id" => parent.room_id.clone(), "parent_id" => parent.msg_id)).await?;
+        DbQuery::update_with_ttl(&conn, messages::TABLE_NAME, "last_reply_at=?", "room_id=? AND bucket=? AND msg_time=? IF EXISTS", query_values!("last_reply_at" => reply.created_at, "room_id" => parent.room_id.clone(), "bucket" => parent.bucket, "msg_time" => parent.msg_time), retention::ttl_secs(parent.expires_at)).await?;
+        for user_id in vec![parent.msg_owner.clone(), reply.msg_owner.clone()] {
+            DbQuery::upsert(&conn, PARTICIPANT_TABLE_NAME, "room_id, user_id, parent_id", "?, ?, ?", query_values!("room_id" => parent.room_id.clone(), "user_id" => user_id, "parent_id" => parent.msg_id)).await?;
+        }
+        // the replier has read everything up to their own reply
+        Self::mark_read(&conn, ReadThread {
+            room_id: parent.room_id.clone(),
+            parent_id: parent.msg_id,
+            user_id: reply.msg_owner.clone(),
+            msg_time: reply.msg_time,
+        }).await
+    }
+
+    // Called when a reply is deleted for everyone or expires. The reply row goes too so
+    // unread counts agree with reply_count, a reply deleted before it expires is counted once.
+    pub async fn remove_reply(conn: &Connection, room_id: &str, parent_id: Uuid, msg_time: Uuid) -> Result<(), String> {
+        if !DbQuery::delete(&conn, REPLY_TABLE_NAME, "room_id=? AND parent_id=? AND msg_time=? IF EXISTS", query_values!(room_id.to_string(), parent_id, msg_time)).await? {
+            return Ok(())
+        }
+        DbQuery::exec_update(&conn, REPLY_COUNT_TABLE_NAME, "reply_count = reply_count - 1", "room_id=? AND parent_id=?", query_values!("room_id" => room_id.to_string(), "parent_id" => parent_id)).await
+    }
+
+    // Called when a thread parent expires, its replies expire on their own.
+    pub async fn remove_thread(conn: &Connection, room_id: &str, parent_id: Uuid) -> Result<(), String> {
+        DbQuery::exec_delete(&conn, REPLY_TABLE_NAME, "room_id=? AND parent_id=?", query_values!(room_id.to_string(), parent_id)).await?;
+        DbQuery::exec_delete(&conn, REPLY_COUNT_TABLE_NAME, "room_id=? AND parent_id=?", query_values!(room_id.to_string(), parent_id)).await
+    }
+
+    pub async fn find_reply_counts(conn: &Connection, room_id: &str, parent_ids: Vec<Uuid>) -> Result<HashMap<Uuid, i64>, String> {
+        let mut counts: HashMap<Uuid, i64> = HashMap::new();
+        if parent_ids.is_empty() {
+            return Ok(counts)
+        }
+        let rows = DbQuery::get_rows(&conn, REPLY_COUNT_TABLE_NAME, "parent_id, reply_count", "room_id=? AND parent_id IN ?", query_values!(room_id.to_string(), parent_ids)).await?;
+        for row in rows {
+            let count = ReplyCount::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+            counts.insert(count.parent_id, count.reply_count);
+        }
+        Ok(counts)
+    }
+
+    pub async fn find_thread(conn: &Connection, room_id: &str, parent_id: Uuid, query: ThreadQuery) -> Result<ThreadPage, String> {
+        let parent = match Message::find_message(&conn, parent_id).await? {
+            Some(parent) if parent.room_id == room_id => parent,
+            _ => return Err(constants::MESSAGE_MSG_NOT_FOUND.to_string()),
+        };
+        let limit = std::cmp::min(std::cmp::max(query.limit.unwrap_or(MESSAGE_PAGE_DEFAULT_LIMIT), 1), MESSAGE_PAGE_MAX_LIMIT);
+        let paging_state = match query.cursor {
+            Some(ref cursor) => Some(DbQuery::decode_paging_state(cursor)?),
+            None => None,
+        };
+        let mut where_string = "room_id=? AND parent_id=?".to_string();
+        let mut values: Vec<Value> = vec![room_id.to_string().into(), parent_id.into()];
+        if let Some(before) = query.before {
+            where_string.push_str(" AND msg_time<?");
+            values.push(before.into());
+        }
+        let (rows, next_paging_state) = DbQuery::get_page(&conn, REPLY_TABLE_NAME, "msg_time", &where_string, QueryValues::SimpleValues(values), limit, paging_state).await?;
+        let mut replies: Vec<ResMessage> = vec![];
+        for row in rows {
+            let reply = ThreadReply::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+            if let Some(message) = Message::find_by_msg_time(&conn, room_id, reply.msg_time).await? {
+                replies.push(message);
+            }
+        }
+        let reply_count = Self::find_reply_counts(&conn, room_id, vec![parent_id]).await?.get(&parent_id).cloned().unwrap_or(0);
+        let unread_count = match query.user_id {
+            Some(ref user_id) => Some(Self::count_unread(&conn, room_id, parent_id, user_id).await?),
+            None => None,
+        };
+        Ok(ThreadPage {
+            parent: Message::find_by_msg_time(&conn, room_id, parent.msg_time).await?.ok_or(constants::MESSAGE_MSG_NOT_FOUND.to_string())?,
+            replies,
+            reply_count,
+            unread_count,
+            next_cursor: next_paging_state.map(|paging_state| DbQuery::encode_paging_state(&paging_state)),
+        })
+    }
+
+    pub async fn find_read_up_to(conn: &Connection, room_id: &str, parent_id: Uuid, user_id: &str) -> Result<Option<Uuid>, String> {
+        match DbQuery::get_row(&conn, THREAD_READ_TABLE_NAME, "read_up_to", "room_id=? AND parent_id=? AND user_id=?", query_values!(room_id.to_string(), parent_id, user_id.to_string())).await {
+            Ok(row) => Ok(Some(ThreadRead::try_from_row(row).map_err(|err| format!("{:?}", err))?.read_up_to)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub async fn count_unread(conn: &Connection, room_id: &str, parent_id: Uuid, user_id: &str) -> Result<i64, String> {
+        match Self::find_read_up_to(&conn, room_id, parent_id, user_id).await? {
+            Some(read_up_to) => DbQuery::get_count(&conn, REPLY_TABLE_NAME, "room_id=? AND parent_id=? AND msg_time>?", query_values!(room_id.to_string(), parent_id, read_up_to)).await,
+            None => DbQuery::get_count(&conn, REPLY_TABLE_NAME, "room_id=? AND parent_id=?", query_values!(room_id.to_string(), parent_id)).await,
+        }
+    }
+
+    // only ever moves the read pointer forward
+    pub async fn mark_read(conn: &Connection, read: ReadThread) -> Result<(), String> {
+        let inserted = DbQuery::insert(&conn, THREAD_READ_TABLE_NAME, "room_id, parent_id, user_id, read_up_to", "?, ?, ?, ?", query_values!("room_id" => read.room_id.clone(), "parent_id" => read.parent_id, "user_id" => read.user_id.clone(), "read_up_to" => read.msg_time)).await?;
+        if !inserted {
+            DbQuery::update(&conn, THREAD_READ_TABLE_NAME, "read_up_to=?", "room_id=? AND parent_id=? AND user_id=? IF read_up_to < ?", query_values!(read.msg_time, read.room_id, read.parent_id, read.user_id, read.msg_time)).await?;
+        }
+        Ok(())
+    }
+
+    // Threads the user takes part in that have replies they haven't read. The latest reply and
+    // read pointer of every thread are read in one query each, replies are only counted for
+    // threads with a reply past the read pointer.
+    pub async fn find_unread(conn: &Connection, query: UnreadThreadsQuery) -> Result<Vec<UnreadThread>, String> {
+        let rows = DbQuery::get_rows(&conn, PARTICIPANT_TABLE_NAME, "parent_id", "room_id=? AND user_id=?", query_values!(query.room_id.clone(), query.user_id.clone())).await?;
+        let mut parent_ids: Vec<Uuid> = vec![];
+        for row in rows {
+            parent_ids.push(ParticipantThread::try_from_row(row).map_err(|err| format!("{:?}", err))?.parent_id);
+        }
+        if parent_ids.is_empty() {
+            return Ok(vec![])
+        }
+        let rows = DbQuery::get_rows(&conn, REPLY_TABLE_NAME, "parent_id, msg_time", "room_id=? AND parent_id IN ? PER PARTITION LIMIT 1", query_values!(query.room_id.clone(), parent_ids.clone())).await?;
+        let mut latest_replies: Vec<LatestReply> = vec![];
+        for row in rows {
+            latest_replies.push(LatestReply::try_from_row(row).map_err(|err| format!("{:?}", err))?);
+        }
+        let rows = DbQuery::get_rows(&conn, THREAD_READ_TABLE_NAME, "parent_id, read_up_to", "room_id=? AND parent_id IN ? AND user_id=?", query_values!(query.room_id.clone(), parent_ids.clone(), query.user_id.clone())).await?;
+        let mut read_up_to: HashMap<Uuid, Uuid> = HashMap::new();
+        for row in rows {
+            let read = ParentThreadRead::try_from_row(row).map_err(|err| format!("{:?}", err))?;
+            read_up_to.insert(read.parent_id, read.read_up_to);
+        }
+        let unread_replies: Vec<LatestReply> = latest_replies.into_iter()
+            .filter(|reply| read_up_to.get(&reply.parent_id).map_or(true, |read_up_to| time_uuid::ticks(&reply.msg_time) > time_uuid::ticks(read_up_to)))
+            .collect();
+        let counts = future::join_all(unread_replies.iter().map(|reply| match read_up_to.get(&reply.parent_id) {
+            Some(read_up_to) => DbQuery::get_count(&conn, REPLY_TABLE_NAME, "room_id=? AND parent_id=? AND msg_time>?", query_values!(query.room_id.clone(), reply.parent_id, *read_up_to)),
+            None => DbQuery::get_count(&conn, REPLY_TABLE_NAME, "room_id=? AND parent_id=?", query_values!(query.room_id.clone(), reply.parent_id)),
+        })).await;
+        let mut threads: Vec<UnreadThread> = vec![];
+        for (reply, unread_count) in unread_replies.into_iter().zip(counts) {
+            threads.push(UnreadThread {
+                parent_id: reply.parent_id,
+                unread_count: unread_count?,
+                last_reply_at: Some(time_uuid::timestamp_millis(&reply.msg_time)),
+            });
+        }
+        threads.sort_by_key(|thread| std::cmp::Reverse(thread.last_reply_at));
+        Ok(threads)
+    }
+}
@@ -0,0 +1,56 @@
+use crate::{
+    config::db::{Pool, RedisPool},
+    constants,
+    error::ServiceError,
+    models::attachment::{Attachment, AttachmentPolicy, NewAttachment, SignedUrl},
+    utils::blob_store::BlobStore,
+};
+use actix_web::{
+    http::StatusCode,
+    web::Bytes,
+};
+use uuid::Uuid;
+
+pub async fn upload(new: NewAttachment, pool: &Pool, redis_pool: &RedisPool, blob_store: &dyn BlobStore) -> Result<Attachment, ServiceError> {
+    match Attachment::create(&pool.clone(), new, &redis_pool, blob_store).await {
+        Ok(attachment) => Ok(attachment),
+        Err(message) => Err(ServiceError::new(attachment_error_status(&message), message))
+    }
+}
+pub async fn sign_url(attachment_id: Uuid, user_id: String, pool: &Pool, redis_pool: &RedisPool) -> Result<SignedUrl, ServiceError> {
+    match Attachment::sign_url(&pool.clone(), attachment_id, user_id, &redis_pool).await {
+        Ok(signed_url) => Ok(signed_url),
+        Err(message) => Err(ServiceError::new(attachment_error_status(&message), message))
+    }
+}
+pub async fn download(attachment_id: Uuid, token: String, thumbnail: bool, pool: &Pool, redis_pool: &RedisPool, blob_store: &dyn BlobStore) -> Result<(Attachment, Bytes), ServiceError> {
+    match Attachment::download(&pool.clone(), attachment_id, token, thumbnail, &redis_pool, blob_store).await {
+        Ok(file) => Ok(file),
+        Err(message) => Err(ServiceError::new(attachment_error_status(&message), message))
+    }
+}
+pub async fn find_policy(app_id: i64, pool: &Pool) -> Result<AttachmentPolicy, ServiceError> {
+    match AttachmentPolicy::find_by_app_id(&pool.clone(), app_id).await {
+        Ok(policy) => Ok(policy),
+        Err(_) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, constants::MESSAGE_CAN_NOT_FETCH_DATA.to_string())),
+    }
+}
+pub async fn save_policy(policy: AttachmentPolicy, pool: &Pool) -> Result<AttachmentPolicy, ServiceError> {
+    match AttachmentPolicy::save(&pool.clone(), policy).await {
+        Ok(policy) => Ok(policy),
+        Err(message) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, message))
+    }
+}
+
+fn attachment_error_status(message: &str) -> StatusCode {
+    match message {
+        constants::MESSAGE_ATTACHMENT_NOT_FOUND => StatusCode::NOT_FOUND,
+        constants::MESSAGE_ATTACHMENT_FORBIDDEN => StatusCode::FORBIDDEN,
+        constants::MESSAGE_ATTACHMENT_INVALID_TOKEN => StatusCode::UNAUTHORIZED,
+        constants::MESSAGE_ATTACHMENT_TOO_LARGE => StatusCode::PAYLOAD_TOO_LARGE,
+        constants::MESSAGE_ATTACHMENT_TYPE_NOT_ALLOWED => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        constants::MESSAGE_ATTACHMENT_INVALID_IMAGE
+        | constants::MESSAGE_ATTACHMENT_INVALID_VOICE => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
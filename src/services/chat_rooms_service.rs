@@ -2,7 +2,8 @@ use crate::{
     config::db::Pool,
     constants,
     error::ServiceError,
-    models::chat_room::{ ChatRoom, ResChatRoom, CreateChatRoom, RoomID, ADDChatRoomUser, DeleteRoom},
+    models::chat_room::{ ChatRoom, ResChatRoom, CreateChatRoom, RoomID, ADDChatRoomUser, RemoveChatRoomUser, UpdateChatRoom, DeleteRoom},
+    utils::kafka_producer::{ChatEvent, EventProducer},
 };
 use actix_web::{
     http::{
@@ -31,21 +32,97 @@ pub async fn find_by_app_id_and_user_id_and_type(app_id: i64, user_id: String, r
         Err(_) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, constants::MESSAGE_CAN_NOT_FETCH_DATA.to_string())),
     }
 }
-pub async fn create_room(room: CreateChatRoom, pool: &Pool, redis_pool: &RedisPool) -> Result<RoomID, ServiceError> {
+pub async fn create_room(room: CreateChatRoom, pool: &Pool, redis_pool: &RedisPool, kafka_producer: &EventProducer) -> Result<RoomID, ServiceError> {
+    let app_id = room.app_id;
+    let room_owner = room.room_owner.clone();
+    let room_type = room.room_type;
+    let users: Vec<String> = room.users.iter().map(|user| user.user_id.clone()).collect();
     match ChatRoom::create_room(&pool.clone(), room, &redis_pool).await {
-        Ok(message) => Ok(message),
+        Ok(message) => {
+            kafka_producer.emit(ChatEvent::RoomCreated {
+                app_id,
+                room_id: message.room_id.clone(),
+                room_owner,
+                room_type,
+                users,
+            });
+            Ok(message)
+        },
         Err(message) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, message.to_string()))
     }
 }
-pub async fn add_room_user(room: ADDChatRoomUser, pool: &Pool, redis_pool: &RedisPool) -> Result<RoomID, ServiceError> {
+pub async fn add_room_user(room: ADDChatRoomUser, pool: &Pool, redis_pool: &RedisPool, kafka_producer: &EventProducer) -> Result<RoomID, ServiceError> {
     match ChatRoom::add_room_user(&pool.clone(), room, &redis_pool).await {
-        Ok(message) => Ok(message),
-        Err(message) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, message.to_string()))
+        Ok((app_id, message, users)) => {
+            if !users.is_empty() {
+                kafka_producer.emit(ChatEvent::RoomUserAdded {
+                    app_id,
+                    room_id: message.room_id.clone(),
+                    users,
+                });
+            }
+            Ok(message)
+        },
+        Err(message) => Err(ServiceError::new(chat_room_error_status(&message), message))
+    }
+}
+pub async fn remove_room_user(room_user: RemoveChatRoomUser, pool: &Pool, redis_pool: &RedisPool, kafka_producer: &EventProducer) -> Result<RoomID, ServiceError> {
+    let user_id = room_user.user_id.clone();
+    let removed_by = room_user.removed_by.clone();
+    match ChatRoom::remove_room_user(&pool.clone(), room_user, &redis_pool).await {
+        Ok((app_id, message)) => {
+            kafka_producer.emit(ChatEvent::RoomUserRemoved {
+                app_id,
+                room_id: message.room_id.clone(),
+                user_id,
+                removed_by,
+            });
+            Ok(message)
+        },
+        Err(message) => Err(ServiceError::new(chat_room_error_status(&message), message))
+    }
+}
+pub async fn update_room(room: UpdateChatRoom, pool: &Pool, redis_pool: &RedisPool, kafka_producer: &EventProducer) -> Result<RoomID, ServiceError> {
+    let room_name = room.room_name.clone();
+    let banner = room.banner.clone().map(Option::unwrap_or_default);
+    let about = room.about.clone().map(Option::unwrap_or_default);
+    match ChatRoom::update_room(&pool.clone(), room, &redis_pool).await {
+        Ok((app_id, message)) => {
+            kafka_producer.emit(ChatEvent::RoomUpdated {
+                app_id,
+                room_id: message.room_id.clone(),
+                room_name,
+                banner,
+                about,
+            });
+            Ok(message)
+        },
+        Err(message) => Err(ServiceError::new(chat_room_error_status(&message), message))
     }
 }
-pub async fn delete_room(room: DeleteRoom, pool: &Pool) -> Result<String, ServiceError> {
+pub async fn delete_room(room: DeleteRoom, pool: &Pool, kafka_producer: &EventProducer) -> Result<String, ServiceError> {
+    let event = ChatEvent::RoomDeleted {
+        app_id: room.app_id,
+        room_id: room.room_id.clone(),
+    };
     match ChatRoom::delete_room(&pool.clone(), room).await {
-        Ok(message) => Ok(message),
+        Ok(message) => {
+            kafka_producer.emit(event);
+            Ok(message)
+        },
         Err(message) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, message.to_string()))
     }
+}
+
+fn chat_room_error_status(message: &str) -> StatusCode {
+    match message {
+        constants::CHAT_ROOM_NOT_FOUND
+        | constants::CHAT_ROOM_USER_NOT_FOUND => StatusCode::NOT_FOUND,
+        constants::CHAT_ROOM_UPDATE_FORBIDDEN
+        | constants::CHAT_ROOM_REMOVE_USER_FORBIDDEN
+        | constants::CHAT_ROOM_ADD_USER_FORBIDDEN => StatusCode::FORBIDDEN,
+        constants::CHAT_ROOM_OWNER_NOT_REMOVABLE
+        | constants::CHAT_ROOM_DIRECT_NOT_RENAMABLE => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
 }
\ No newline at end of file
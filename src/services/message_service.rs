@@ -1,8 +1,15 @@
 use crate::{
-    config::db::Pool,
+    config::db::{Pool, RedisPool},
     constants,
     error::ServiceError,
-    models::messages::{ Message, AddMessage, DeleteMessage, ResMessage},
+    models::{
+        mention::{Mention, MentionPage, MentionQuery},
+        messages::{ Message, AddMessage, DeleteMessage, EditMessage, MessagePage, MessageQuery, ReadMessage, ReadReceipt, ResMessage},
+        pin::{Pin, PinMessage, PinnedMessage},
+        reaction::{Reaction, ReactionSummary, ReactMessage},
+        thread::{Thread, ReadThread, ThreadPage, ThreadQuery, UnreadThread, UnreadThreadsQuery},
+    },
+    utils::kafka_producer::{ChatEvent, EventProducer},
 };
 use actix_web::{
     http::{
@@ -11,22 +18,147 @@ use actix_web::{
     },
     web,
 };
+use uuid::Uuid;
 
-pub async fn find_by_room_id(room_id: String, pool: &Pool) -> Result<Vec<ResMessage>, ServiceError> {
-    match Message::find_by_room_id(room_id, &pool.clone()).await {
+pub async fn find_by_room_id(room_id: String, query: MessageQuery, pool: &Pool) -> Result<MessagePage, ServiceError> {
+    match Message::find_by_room_id(room_id, query, &pool.clone()).await {
         Ok(messages) => Ok(messages),
         Err(_) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, constants::MESSAGE_CAN_NOT_FETCH_DATA.to_string())),
     }
 }
-pub async fn add_msg(msg: AddMessage, pool: &Pool) -> Result<String, ServiceError> {
-    match Message::add_new_msg(&pool.clone(), msg).await {
-        Ok(message) => Ok(message),
-        Err(message) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, message.to_string()))
+pub async fn add_msg(msg: AddMessage, pool: &Pool, redis_pool: &RedisPool, kafka_producer: &EventProducer) -> Result<(String, Option<ResMessage>), ServiceError> {
+    let app_id = msg.app_id;
+    let room_id = msg.room_id.clone();
+    match Message::add_new_msg(&pool.clone(), msg, &redis_pool).await {
+        Ok((message, res_message)) => {
+            if let (constants::MESSAGE_MSG_CREATED_SUCCESS, Some(created)) = (message.as_str(), &res_message) {
+                kafka_producer.emit(ChatEvent::MessageCreated {
+                    app_id,
+                    room_id,
+                    msg_id: created.msg_id,
+                    msg_time: created.msg_time,
+                    msg_owner: created.msg_owner.clone(),
+                    content: created.content.clone(),
+                    message_type: created.message_type,
+                    send_at: created.send_at,
+                });
+            }
+            Ok((message, res_message))
+        },
+        Err(message) => Err(ServiceError::new(message_error_status(&message), message))
+    }
+}
+pub async fn edit_msg(msg: EditMessage, pool: &Pool, redis_pool: &RedisPool, kafka_producer: &EventProducer, edit_window_secs: i64) -> Result<ResMessage, ServiceError> {
+    let room_id = msg.room_id.clone();
+    match Message::edit_msg(&pool.clone(), msg, &redis_pool, edit_window_secs).await {
+        Ok((app_id, res_message)) => {
+            kafka_producer.emit(ChatEvent::MessageEdited {
+                app_id,
+                room_id,
+                msg_id: res_message.msg_id,
+                msg_time: res_message.msg_time,
+                content: res_message.content.clone(),
+                edited_at: res_message.edited_at.unwrap_or(0),
+            });
+            Ok(res_message)
+        },
+        Err(message) => Err(ServiceError::new(message_error_status(&message), message))
     }
 }
-pub async fn delete_msg(msg: DeleteMessage, pool: &Pool) -> Result<String, ServiceError> {
-    match Message::delete_msg(&pool.clone(), msg).await {
-        Ok(message) => Ok(message),
+pub async fn mark_read(msg: ReadMessage, pool: &Pool, redis_pool: &RedisPool) -> Result<ReadReceipt, ServiceError> {
+    match Message::mark_read(&pool.clone(), msg, &redis_pool).await {
+        Ok(receipt) => Ok(receipt),
         Err(message) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, message.to_string()))
     }
+}
+pub async fn delete_msg(msg: DeleteMessage, pool: &Pool, redis_pool: &RedisPool, kafka_producer: &EventProducer) -> Result<ResMessage, ServiceError> {
+    let room_id = msg.room_id.clone();
+    let for_everyone = msg.for_everyone;
+    match Message::delete_msg(&pool.clone(), msg, &redis_pool).await {
+        Ok((app_id, res_message)) => {
+            if for_everyone {
+                kafka_producer.emit(ChatEvent::MessageDeleted {
+                    app_id,
+                    room_id,
+                    msg_id: res_message.msg_id,
+                    msg_time: res_message.msg_time,
+                });
+            }
+            Ok(res_message)
+        },
+        Err(message) => Err(ServiceError::new(message_error_status(&message), message))
+    }
+}
+pub async fn find_thread(room_id: String, parent_id: Uuid, query: ThreadQuery, pool: &Pool) -> Result<ThreadPage, ServiceError> {
+    match Thread::find_thread(&pool.clone(), &room_id, parent_id, query).await {
+        Ok(thread) => Ok(thread),
+        Err(message) => Err(ServiceError::new(message_error_status(&message), message))
+    }
+}
+pub async fn mark_thread_read(read: ReadThread, pool: &Pool) -> Result<(), ServiceError> {
+    match Thread::mark_read(&pool.clone(), read).await {
+        Ok(_) => Ok(()),
+        Err(message) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, message))
+    }
+}
+pub async fn find_unread_threads(query: UnreadThreadsQuery, pool: &Pool) -> Result<Vec<UnreadThread>, ServiceError> {
+    match Thread::find_unread(&pool.clone(), query).await {
+        Ok(threads) => Ok(threads),
+        Err(_) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, constants::MESSAGE_CAN_NOT_FETCH_DATA.to_string())),
+    }
+}
+pub async fn find_mentions(query: MentionQuery, pool: &Pool) -> Result<MentionPage, ServiceError> {
+    match Mention::find_by_user(&pool.clone(), query).await {
+        Ok(mentions) => Ok(mentions),
+        Err(_) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, constants::MESSAGE_CAN_NOT_FETCH_DATA.to_string())),
+    }
+}
+pub async fn add_reaction(msg: ReactMessage, pool: &Pool, redis_pool: &RedisPool) -> Result<Vec<ReactionSummary>, ServiceError> {
+    match Reaction::add(&pool.clone(), msg, &redis_pool).await {
+        Ok(reactions) => Ok(reactions),
+        Err(message) => Err(ServiceError::new(message_error_status(&message), message))
+    }
+}
+pub async fn remove_reaction(msg: ReactMessage, pool: &Pool, redis_pool: &RedisPool) -> Result<Vec<ReactionSummary>, ServiceError> {
+    match Reaction::remove(&pool.clone(), msg, &redis_pool).await {
+        Ok(reactions) => Ok(reactions),
+        Err(message) => Err(ServiceError::new(message_error_status(&message), message))
+    }
+}
+pub async fn find_pins(room_id: String, pool: &Pool) -> Result<Vec<PinnedMessage>, ServiceError> {
+    match Pin::find_by_room_id(&pool.clone(), &room_id).await {
+        Ok(pins) => Ok(pins),
+        Err(_) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, constants::MESSAGE_CAN_NOT_FETCH_DATA.to_string())),
+    }
+}
+pub async fn pin_msg(msg: PinMessage, pool: &Pool, redis_pool: &RedisPool) -> Result<PinnedMessage, ServiceError> {
+    match Pin::pin(&pool.clone(), msg, &redis_pool).await {
+        Ok(pin) => Ok(pin),
+        Err(message) => Err(ServiceError::new(message_error_status(&message), message))
+    }
+}
+pub async fn unpin_msg(msg: PinMessage, pool: &Pool, redis_pool: &RedisPool) -> Result<(), ServiceError> {
+    match Pin::unpin(&pool.clone(), msg, &redis_pool).await {
+        Ok(_) => Ok(()),
+        Err(message) => Err(ServiceError::new(message_error_status(&message), message))
+    }
+}
+
+fn message_error_status(message: &str) -> StatusCode {
+    match message {
+        constants::MESSAGE_MSG_NOT_FOUND
+        | constants::MESSAGE_MSG_PARENT_NOT_FOUND
+        | constants::MESSAGE_ATTACHMENT_NOT_FOUND => StatusCode::NOT_FOUND,
+        constants::MESSAGE_MSG_EDIT_FORBIDDEN
+        | constants::MESSAGE_MSG_SYSTEM_NOT_EDITABLE
+        | constants::MESSAGE_MSG_EDIT_WINDOW_EXPIRED
+        | constants::MESSAGE_MSG_DELETE_FORBIDDEN
+        | constants::MESSAGE_REACTION_FORBIDDEN
+        | constants::MESSAGE_PIN_FORBIDDEN => StatusCode::FORBIDDEN,
+        constants::MESSAGE_REACTION_INVALID
+        | constants::MESSAGE_REACTION_LIMIT_REACHED
+        | constants::MESSAGE_PIN_LIMIT_REACHED
+        | constants::MESSAGE_MSG_VOICE_ATTACHMENT_REQUIRED => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
 }
\ No newline at end of file
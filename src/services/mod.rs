@@ -2,3 +2,6 @@ pub mod account_service;
 pub mod app_user_service;
 pub mod chat_rooms_service;
 pub mod message_service;
+pub mod attachment_service;
+pub mod search_service;
+pub mod retention_service;
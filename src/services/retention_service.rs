@@ -0,0 +1,34 @@
+use crate::{
+    config::db::Pool,
+    constants,
+    error::ServiceError,
+    models::retention::{Retention, RetentionPolicy},
+};
+use actix_web::http::StatusCode;
+
+pub async fn find_policy(app_id: i64, room_id: Option<String>, pool: &Pool) -> Result<RetentionPolicy, ServiceError> {
+    let room_id = room_id.unwrap_or_default();
+    match RetentionPolicy::find_effective(&pool.clone(), app_id, &room_id).await {
+        Ok(policy) => Ok(policy),
+        Err(_) => Err(ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, constants::MESSAGE_CAN_NOT_FETCH_DATA.to_string())),
+    }
+}
+pub async fn save_policy(policy: RetentionPolicy, pool: &Pool) -> Result<RetentionPolicy, ServiceError> {
+    match RetentionPolicy::save(&pool.clone(), policy).await {
+        Ok(policy) => {
+            // TTLs set before the hold would still delete the held messages
+            if policy.legal_hold {
+                Retention::start_hold(pool.clone(), &policy);
+            }
+            Ok(policy)
+        },
+        Err(message) => Err(ServiceError::new(retention_error_status(&message), message))
+    }
+}
+
+fn retention_error_status(message: &str) -> StatusCode {
+    match message {
+        constants::MESSAGE_RETENTION_POLICY_INVALID => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
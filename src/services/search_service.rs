@@ -0,0 +1,21 @@
+use crate::{
+    config::db::Pool,
+    constants,
+    error::ServiceError,
+    models::search::{Search, SearchPage, SearchQuery},
+};
+use actix_web::http::StatusCode;
+
+pub async fn search_messages(query: SearchQuery, pool: &Pool) -> Result<SearchPage, ServiceError> {
+    match Search::find_messages(&pool.clone(), query).await {
+        Ok(page) => Ok(page),
+        Err(message) => {
+            let status = match message.as_str() {
+                constants::MESSAGE_SEARCH_ROOM_FORBIDDEN => StatusCode::FORBIDDEN,
+                _ if message.starts_with("invalid search query") => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Err(ServiceError::new(status, message))
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use actix_web::{client::Client, web::Bytes};
+use futures::future::Future;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{env, fs, path::PathBuf, pin::Pin, sync::Arc};
+
+pub const BLOB_STORE_LOCAL: &str = "local";
+pub const BLOB_STORE_S3: &str = "s3";
+// largest object read back from S3 in one response
+const S3_MAX_OBJECT_SIZE: usize = 100 * 1024 * 1024;
+
+pub type BlobFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, String>> + 'a>>;
+
+// Where uploaded files live. Keys are generated by the server ("{app_id}/{sha256}"), so
+// implementations don't need to escape them.
+pub trait BlobStore: Send + Sync {
+    fn put<'a>(&'a self, key: &'a str, content_type: &'a str, data: Bytes) -> BlobFuture<'a, ()>;
+    fn get<'a>(&'a self, key: &'a str) -> BlobFuture<'a, Bytes>;
+    fn delete<'a>(&'a self, key: &'a str) -> BlobFuture<'a, ()>;
+}
+
+// BLOB_STORE=local (default) keeps files under BLOB_LOCAL_ROOT, BLOB_STORE=s3 talks to any
+// S3-compatible endpoint with path-style addressing.
+pub fn from_env() -> Arc<dyn BlobStore> {
+    match env::var("BLOB_STORE").unwrap_or(BLOB_STORE_LOCAL.to_string()).as_str() {
+        BLOB_STORE_S3 => Arc::new(S3BlobStore {
+            endpoint: env::var("S3_ENDPOINT").expect("S3_ENDPOINT not found.").trim_end_matches('/').to_string(),
+            bucket: env::var("S3_BUCKET").expect("S3_BUCKET not found."),
+            region: env::var("S3_REGION").unwrap_or("us-east-1".to_string()),
+            access_key: env::var("S3_ACCESS_KEY").expect("S3_ACCESS_KEY not found."),
+            secret_key: env::var("S3_SECRET_KEY").expect("S3_SECRET_KEY not found."),
+        }),
+        _ => Arc::new(LocalBlobStore {
+            root: PathBuf::from(env::var("BLOB_LOCAL_ROOT").unwrap_or("./uploads".to_string())),
+        }),
+    }
+}
+
+pub struct LocalBlobStore {
+    pub root: PathBuf,
+}
+
+impl BlobStore for LocalBlobStore {
+    fn put<'a>(&'a self, key: &'a str, _content_type: &'a str, data: Bytes) -> BlobFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.root.join(key);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).map_err(|err| format!("can't create {:?}, error - {:?}", dir, err))?;
+            }
+            fs::write(&path, &data).map_err(|err| format!("can't write {:?}, error - {:?}", path, err))
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BlobFuture<'a, Bytes> {
+        Box::pin(async move {
+            let path = self.root.join(key);
+            fs::read(&path).map(Bytes::from).map_err(|err| format!("can't read {:?}, error - {:?}", path, err))
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BlobFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.root.join(key);
+            fs::remove_file(&path).map_err(|err| format!("can't delete {:?}, error - {:?}", path, err))
+        })
+    }
+}
+
+pub struct S3BlobStore {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+// content hash of uploads, also the S3 payload hash
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("hmac accepts any key length");
+    mac.input(data.as_bytes());
+    mac.result().code().to_vec()
+}
+
+impl S3BlobStore {
+    // AWS Signature Version 4 headers for a single request, the payload is always hashed.
+    fn signed_headers(&self, method: &str, path: &str, payload: &[u8]) -> Vec<(&'static str, String)> {
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let host = self.endpoint.splitn(2, "://").last().unwrap_or(&self.endpoint).to_string();
+        let payload_hash = sha256_hex(payload);
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method, path, host, payload_hash, amz_date, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, sha256_hex(canonical_request.as_bytes()));
+        let mut signing_key = hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        for part in &[self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part);
+        }
+        let signature = hex_encode(&hmac_sha256(&signing_key, &string_to_sign));
+        vec![
+            ("x-amz-date", amz_date),
+            ("x-amz-content-sha256", payload_hash),
+            ("authorization", format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}", self.access_key, scope, signature)),
+        ]
+    }
+
+    fn object_path(&self, key: &str) -> String {
+        format!("/{}/{}", self.bucket, key)
+    }
+}
+
+impl BlobStore for S3BlobStore {
+    fn put<'a>(&'a self, key: &'a str, content_type: &'a str, data: Bytes) -> BlobFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.object_path(key);
+            let mut request = Client::default().put(format!("{}{}", self.endpoint, path)).header("content-type", content_type);
+            for (name, value) in self.signed_headers("PUT", &path, &data) {
+                request = request.header(name, value);
+            }
+            let response = request.send_body(data).await.map_err(|err| format!("can't put {}, error - {:?}", key, err))?;
+            match response.status().is_success() {
+                true => Ok(()),
+                false => Err(format!("can't put {}, status - {}", key, response.status())),
+            }
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BlobFuture<'a, Bytes> {
+        Box::pin(async move {
+            let path = self.object_path(key);
+            let mut request = Client::default().get(format!("{}{}", self.endpoint, path));
+            for (name, value) in self.signed_headers("GET", &path, b"") {
+                request = request.header(name, value);
+            }
+            let mut response = request.send().await.map_err(|err| format!("can't get {}, error - {:?}", key, err))?;
+            if !response.status().is_success() {
+                return Err(format!("can't get {}, status - {}", key, response.status()))
+            }
+            response.body().limit(S3_MAX_OBJECT_SIZE).await.map_err(|err| format!("can't read {}, error - {:?}", key, err))
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BlobFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.object_path(key);
+            let mut request = Client::default().delete(format!("{}{}", self.endpoint, path));
+            for (name, value) in self.signed_headers("DELETE", &path, b"") {
+                request = request.header(name, value);
+            }
+            let response = request.send().await.map_err(|err| format!("can't delete {}, error - {:?}", key, err))?;
+            match response.status().is_success() {
+                true => Ok(()),
+                false => Err(format!("can't delete {}, status - {}", key, response.status())),
+            }
+        })
+    }
+}
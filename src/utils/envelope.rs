@@ -0,0 +1,223 @@
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        chat_room::ADDChatRoomUser,
+        messages::{AddMessage, DeleteMessage, EditMessage, ReadMessage},
+        presence::PresenceUpdate,
+    },
+    utils::{
+        ingest::{IngestError, IngestMessage},
+        kafka_consumer::KafkaData,
+        kafka_handlers::{TOPIC_ADD_ROOM_USER, TOPIC_DELETE_MESSAGE, TOPIC_EDIT_MESSAGE, TOPIC_NEW_MESSAGE, TOPIC_PRESENCE, TOPIC_READ_MESSAGE},
+    },
+};
+
+// version 1 is the legacy KafkaData {topic, user_key, payload} with a json string payload
+pub const ENVELOPE_VERSION_LEGACY: u32 = 1;
+pub const ENVELOPE_VERSION_CURRENT: u32 = 2;
+
+// message headers, they take precedence over the same fields in the body
+pub const HEADER_EVENT_TYPE: &str = "event_type";
+pub const HEADER_TENANT: &str = "tenant";
+pub const HEADER_TRACE_ID: &str = "trace_id";
+pub const HEADER_SCHEMA_VERSION: &str = "schema_version";
+
+// body of a version 2 message
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EnvelopeBody {
+    pub version: u32,
+    pub event_type: Option<String>,
+    pub tenant: Option<i64>,
+    pub trace_id: Option<String>,
+    pub user_key: Option<String>,
+    pub payload: Value,
+}
+
+// app and room every message and room event payload has to name
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoomScope {
+    pub app_id: i64,
+    pub room_id: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageEnvelope {
+    pub version: u32,
+    pub event_type: String,
+    // app id the message belongs to
+    pub tenant: Option<i64>,
+    pub trace_id: String,
+    pub user_key: String,
+    pub payload: Value,
+}
+
+fn header(msg: &IngestMessage, name: &str) -> Option<String> {
+    msg.headers.iter()
+        .find(|(header_name, _)| header_name == name)
+        .map(|(_, value)| String::from_utf8_lossy(value).to_string())
+}
+
+impl MessageEnvelope {
+    pub fn parse(msg: &IngestMessage) -> Result<MessageEnvelope, IngestError> {
+        let payload = std::str::from_utf8(&msg.payload)
+            .map_err(|e| IngestError::Poison(format!("Error while deserializing message payload: {:?}", e)))?;
+        let body: Value = serde_json::from_str(payload)
+            .map_err(|e| IngestError::Poison(format!("Message is not json: {:?}", e)))?;
+
+        let header_version = match header(msg, HEADER_SCHEMA_VERSION) {
+            Some(version) => Some(version.parse::<u32>()
+                .map_err(|_| IngestError::Poison(format!("Invalid {} header {:?}", HEADER_SCHEMA_VERSION, version)))?),
+            None => None,
+        };
+        let body_version = body.get("version").and_then(|version| version.as_u64()).map(|version| version as u32);
+        let version = header_version.or(body_version).unwrap_or(ENVELOPE_VERSION_LEGACY);
+
+        let mut envelope = match version {
+            ENVELOPE_VERSION_LEGACY => {
+                let legacy = serde_json::from_value::<KafkaData>(body)
+                    .map_err(|e| IngestError::Poison(format!("serde_json::from_str::<KafkaData> Error {:?}", e)))?;
+                let legacy_payload: Value = serde_json::from_str(&legacy.payload)
+                    .map_err(|e| IngestError::Poison(format!("Legacy payload is not json: {:?}", e)))?;
+                MessageEnvelope {
+                    version,
+                    event_type: legacy.topic,
+                    tenant: None,
+                    trace_id: String::new(),
+                    user_key: legacy.user_key,
+                    payload: legacy_payload,
+                }
+            },
+            ENVELOPE_VERSION_CURRENT => {
+                let body = serde_json::from_value::<EnvelopeBody>(body)
+                    .map_err(|e| IngestError::Poison(format!("serde_json::from_str::<EnvelopeBody> Error {:?}", e)))?;
+                MessageEnvelope {
+                    version,
+                    event_type: body.event_type.unwrap_or_default(),
+                    tenant: body.tenant,
+                    trace_id: body.trace_id.unwrap_or_default(),
+                    user_key: body.user_key.unwrap_or_default(),
+                    payload: body.payload,
+                }
+            },
+            _ => return Err(IngestError::Poison(format!("Unsupported envelope version {}", version))),
+        };
+
+        if let Some(event_type) = header(msg, HEADER_EVENT_TYPE) {
+            envelope.event_type = event_type;
+        }
+        if let Some(tenant) = header(msg, HEADER_TENANT) {
+            envelope.tenant = Some(tenant.parse::<i64>()
+                .map_err(|_| IngestError::Poison(format!("Invalid {} header {:?}", HEADER_TENANT, tenant)))?);
+        }
+        if let Some(trace_id) = header(msg, HEADER_TRACE_ID) {
+            envelope.trace_id = trace_id;
+        }
+        if envelope.trace_id.is_empty() {
+            envelope.trace_id = Uuid::new_v4().to_string();
+        }
+
+        envelope.validate()?;
+        Ok(envelope)
+    }
+
+    // The payload has to parse as the request of its event type, messages of unknown event
+    // types can never be handled.
+    pub fn validate(&self) -> Result<(), IngestError> {
+        if self.event_type.is_empty() {
+            return Err(IngestError::Poison("Envelope without event type".to_string()));
+        }
+        if !self.payload.is_object() {
+            return Err(IngestError::Poison(format!("Payload of {} is not an object", self.event_type)));
+        }
+        let event_type = self.event_type.as_str();
+        let app_id = if event_type.starts_with(TOPIC_NEW_MESSAGE) {
+            self.room_payload::<AddMessage>()?
+        } else if event_type.starts_with(TOPIC_EDIT_MESSAGE) {
+            self.room_payload::<EditMessage>()?
+        } else if event_type.starts_with(TOPIC_DELETE_MESSAGE) {
+            self.room_payload::<DeleteMessage>()?
+        } else if event_type.starts_with(TOPIC_READ_MESSAGE) {
+            self.room_payload::<ReadMessage>()?
+        } else if event_type.starts_with(TOPIC_ADD_ROOM_USER) {
+            self.room_payload::<ADDChatRoomUser>()?
+        } else if event_type.starts_with(TOPIC_PRESENCE) {
+            self.typed_payload::<PresenceUpdate>()?.app_id
+        } else {
+            return Err(IngestError::Poison(format!("Unknown event type {:?}", self.event_type)));
+        };
+        // a tenant scoped message can only touch its own app
+        if let Some(tenant) = self.tenant {
+            if app_id != tenant {
+                return Err(IngestError::Poison(format!("Payload app_id {} does not match tenant {}", app_id, tenant)));
+            }
+        }
+        Ok(())
+    }
+
+    fn typed_payload<T: DeserializeOwned>(&self) -> Result<T, IngestError> {
+        serde_json::from_value::<T>(self.payload.clone())
+            .map_err(|e| IngestError::Poison(format!("Invalid {} payload: {:?}", self.event_type, e)))
+    }
+
+    // checks the request of the event type and the room scope, returns the app id
+    fn room_payload<T: DeserializeOwned>(&self) -> Result<i64, IngestError> {
+        self.typed_payload::<T>()?;
+        let scope = self.typed_payload::<RoomScope>()?;
+        if scope.room_id.is_empty() {
+            return Err(IngestError::Poison(format!("Payload of {} without room_id", self.event_type)));
+        }
+        Ok(scope.app_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(event_type: &str, tenant: Option<i64>, payload: Value) -> MessageEnvelope {
+        MessageEnvelope {
+            version: ENVELOPE_VERSION_CURRENT,
+            event_type: event_type.to_string(),
+            tenant,
+            trace_id: String::new(),
+            user_key: String::new(),
+            payload,
+        }
+    }
+
+    fn is_poison(result: Result<(), IngestError>) -> bool {
+        matches!(result, Err(IngestError::Poison(_)))
+    }
+
+    #[test]
+    fn accepts_payload_of_its_event_type() {
+        let delete = json!({ "app_id": 1, "room_id": "r1", "msg_id": Uuid::nil(), "user_id": "u1" });
+        assert!(envelope("DELETEMESSAGE_1", Some(1), delete).validate().is_ok());
+        let presence = json!({ "app_id": 1, "user_id": "u1", "device_id": "d1", "event": "connected" });
+        assert!(envelope("PRESENCE_1", None, presence).validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_message_events_without_room_scope() {
+        assert!(is_poison(envelope("DELETEMESSAGE_1", None, json!({ "room_id": "r1", "msg_id": Uuid::nil(), "user_id": "u1" })).validate()));
+        assert!(is_poison(envelope("DELETEMESSAGE_1", None, json!({ "app_id": 1, "room_id": "", "msg_id": Uuid::nil(), "user_id": "u1" })).validate()));
+        assert!(is_poison(envelope("NEWMESSAGE_1", None, json!({ "app_id": 1, "room_id": "r1" })).validate()));
+    }
+
+    #[test]
+    fn rejects_room_users_added_by_nobody() {
+        let users = json!([{ "user_id": "u2", "user_name": "U2" }]);
+        assert!(envelope("ADDROOMUSER_1", None, json!({ "app_id": 1, "room_id": "r1", "users": users, "added_by": "u1" })).validate().is_ok());
+        assert!(is_poison(envelope("ADDROOMUSER_1", None, json!({ "app_id": 1, "room_id": "r1", "users": users })).validate()));
+    }
+
+    #[test]
+    fn rejects_unknown_event_types_and_other_tenants() {
+        let delete = json!({ "app_id": 1, "room_id": "r1", "msg_id": Uuid::nil(), "user_id": "u1" });
+        assert!(is_poison(envelope("RENAMEROOM_1", None, delete.clone()).validate()));
+        assert!(is_poison(envelope("DELETEMESSAGE_1", Some(2), delete).validate()));
+    }
+}
@@ -0,0 +1,489 @@
+use futures::{executor, future::Future};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::utils::{
+    envelope::MessageEnvelope,
+    kafka_handlers::HandlerRegistry,
+};
+
+// ingest backends, selected with INGEST_BACKEND
+pub const INGEST_BACKEND_KAFKA: &str = "kafka";
+pub const INGEST_BACKEND_REDIS: &str = "redis";
+pub const INGEST_BACKEND_MEMORY: &str = "memory";
+
+// how long a source waits for a message before the pipeline polls again
+pub const INGEST_POLL_TIMEOUT_MS: u64 = 1000;
+// longest wait between two retries of a message
+pub const INGEST_MAX_BACKOFF_MS: u64 = 60_000;
+
+#[derive(Clone, Debug)]
+pub struct IngestMessage {
+    // kafka topic or redis stream the message was read from
+    pub source: String,
+    pub partition: i32,
+    // kafka offset or redis stream entry id
+    pub offset: String,
+    pub key: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+// Poison messages can never be stored and go straight to the dead letter topic,
+// transient failures (e.g. Cassandra unavailable) are retried first.
+#[derive(Debug)]
+pub enum IngestError {
+    Poison(String),
+    Transient(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub backoff_ms: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConcurrencyPolicy {
+    // messages of one room (or of one key or partition when there's no room) always go to the same worker
+    pub workers: usize,
+    // polled but not yet acked messages, polling pauses once this many are in flight
+    pub max_in_flight: usize,
+}
+
+pub type SourceFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + 'a>>;
+
+pub trait IngestSource {
+    fn name(&self) -> String;
+    // waits up to INGEST_POLL_TIMEOUT_MS, None when nothing arrived in time
+    fn poll(&mut self) -> Option<Result<IngestMessage, String>>;
+    // marks the message as done so it is not delivered again
+    fn ack(&mut self, msg: &IngestMessage) -> Result<(), String>;
+    fn dead_letter<'a>(&'a mut self, msg: &'a IngestMessage, error: &'a str, attempts: u32) -> SourceFuture<'a>;
+}
+
+struct Completion {
+    msg: IngestMessage,
+    // error and attempts of a message that has to be dead lettered
+    result: Result<(), (String, u32)>,
+}
+
+// Offsets handed to the workers, per source partition in poll order. Workers finish out of
+// order, a message is only acked once everything polled before it on its partition is done.
+#[derive(Default)]
+struct InFlight {
+    partitions: HashMap<(String, i32), VecDeque<(String, Option<IngestMessage>)>>,
+    count: usize,
+}
+
+impl InFlight {
+    fn start(&mut self, msg: &IngestMessage) {
+        self.partitions.entry((msg.source.clone(), msg.partition))
+            .or_insert_with(VecDeque::new)
+            .push_back((msg.offset.clone(), None));
+        self.count += 1;
+    }
+
+    // returns the messages that can be acked, in poll order
+    fn complete(&mut self, msg: IngestMessage) -> Vec<IngestMessage> {
+        let mut ackable = vec![];
+        if let Some(offsets) = self.partitions.get_mut(&(msg.source.clone(), msg.partition)) {
+            if let Some(entry) = offsets.iter_mut().find(|(offset, done)| offset == &msg.offset && done.is_none()) {
+                entry.1 = Some(msg);
+            }
+            while offsets.front().map_or(false, |(_, done)| done.is_some()) {
+                if let Some((_, Some(done))) = offsets.pop_front() {
+                    ackable.push(done);
+                }
+            }
+        }
+        self.count -= ackable.len();
+        ackable
+    }
+}
+
+// Unparsable messages fail the same way on any worker, they only need a stable one.
+fn worker_for(msg: &IngestMessage, workers: usize) -> usize {
+    let room_id = MessageEnvelope::parse(msg).ok()
+        .and_then(|envelope| envelope.payload.get("room_id").and_then(|room_id| room_id.as_str()).map(|room_id| room_id.to_string()));
+    let mut hasher = DefaultHasher::new();
+    match (room_id, &msg.key) {
+        (Some(room_id), _) => room_id.hash(&mut hasher),
+        (None, Some(key)) => key.hash(&mut hasher),
+        (None, None) => (&msg.source, msg.partition).hash(&mut hasher),
+    }
+    (hasher.finish() % workers as u64) as usize
+}
+
+pub async fn run<C: Send + Sync + 'static>(mut source: Box<dyn IngestSource>, ctx: C, registry: HandlerRegistry<C>, retry: RetryPolicy, concurrency: ConcurrencyPolicy) {
+    println!(" ingest pipeline starting {} with {} workers", source.name(), concurrency.workers);
+    let ctx = Arc::new(ctx);
+    let registry = Arc::new(registry);
+    let (done_tx, done_rx) = mpsc::channel::<Completion>();
+    let workers: Vec<mpsc::Sender<IngestMessage>> = (0..std::cmp::max(concurrency.workers, 1)).map(|_| {
+        let (msg_tx, msg_rx) = mpsc::channel::<IngestMessage>();
+        let (ctx, registry, retry, done_tx) = (ctx.clone(), registry.clone(), retry.clone(), done_tx.clone());
+        thread::spawn(move || {
+            executor::block_on(async move {
+                for msg in msg_rx {
+                    let result = process_with_retry(&msg, &*ctx, &*registry, &retry).await;
+                    if done_tx.send(Completion { msg, result }).is_err() {
+                        break;
+                    }
+                }
+            })
+        });
+        msg_tx
+    }).collect();
+
+    let mut in_flight = InFlight::default();
+    loop {
+        // settle finished messages, waiting for one when the pipeline is full
+        loop {
+            let completion = if in_flight.count >= concurrency.max_in_flight {
+                done_rx.recv().ok()
+            } else {
+                done_rx.try_recv().ok()
+            };
+            let completion = match completion {
+                Some(completion) => completion,
+                None => break,
+            };
+            if let Err((e, attempts)) = &completion.result {
+                dead_letter(source.as_mut(), &completion.msg, e, *attempts, &retry).await;
+            }
+            for msg in in_flight.complete(completion.msg) {
+                if let Err(e) = source.ack(&msg) {
+                    error!("Can't ack ingested message: {}", e);
+                }
+            }
+        }
+
+        let msg = match source.poll() {
+            None => continue,
+            Some(Err(e)) => {
+                error!("Ingest error: {}", e);
+                continue;
+            },
+            Some(Ok(msg)) => msg,
+        };
+        in_flight.start(&msg);
+        let worker = worker_for(&msg, workers.len());
+        if let Err(e) = workers[worker].send(msg) {
+            error!("Ingest worker {} stopped: {}", worker, e);
+            return;
+        }
+    }
+}
+
+async fn process_with_retry<C>(msg: &IngestMessage, ctx: &C, registry: &HandlerRegistry<C>, retry: &RetryPolicy) -> Result<(), (String, u32)> {
+    let mut attempts: u32 = 0;
+    loop {
+        attempts += 1;
+        match process_message(msg, ctx, registry).await {
+            Ok(()) => return Ok(()),
+            Err(IngestError::Poison(e)) => return Err((e, attempts)),
+            Err(IngestError::Transient(e)) => {
+                if attempts > retry.max_retries {
+                    return Err((e, attempts));
+                }
+                let backoff = 2u64.checked_pow(attempts - 1)
+                    .map_or(INGEST_MAX_BACKOFF_MS, |factor| std::cmp::min(retry.backoff_ms.saturating_mul(factor), INGEST_MAX_BACKOFF_MS));
+                warn!("Can't store ingested message, retry {} in {}ms: {}", attempts, backoff, e);
+                thread::sleep(Duration::from_millis(backoff));
+            }
+        }
+    }
+}
+
+pub async fn process_message<C>(msg: &IngestMessage, ctx: &C, registry: &HandlerRegistry<C>) -> Result<(), IngestError> {
+    let envelope = MessageEnvelope::parse(msg)?;
+    info!("Ingesting {} v{} trace_id {}", envelope.event_type, envelope.version, envelope.trace_id);
+    match registry.find(&envelope.event_type) {
+        Some(handler) => handler(ctx, envelope).await,
+        None => Err(IngestError::Poison(format!("No ingest handler for event type {:?}", envelope.event_type))),
+    }
+}
+
+// Keeps trying until the dead letter destination accepts the message so the source is
+// never acked past a lost message.
+async fn dead_letter(source: &mut dyn IngestSource, msg: &IngestMessage, error: &str, attempts: u32, retry: &RetryPolicy) {
+    error!("Dead lettering message {}/{} after {} attempt(s): {}", msg.source, msg.offset, attempts, error);
+    let mut backoff = retry.backoff_ms;
+    while let Err(e) = source.dead_letter(msg, error, attempts).await {
+        error!("Can't dead letter message from {}: {}", source.name(), e);
+        thread::sleep(Duration::from_millis(backoff));
+        backoff = std::cmp::min(backoff.saturating_mul(2), INGEST_MAX_BACKOFF_MS);
+    }
+}
+
+// Headers added to a dead lettered message, on top of its original ones.
+pub fn dead_letter_headers(msg: &IngestMessage, error: &str, attempts: u32) -> Vec<(String, Vec<u8>)> {
+    let mut headers = msg.headers.clone();
+    headers.push(("dlq_error".to_string(), error.as_bytes().to_vec()));
+    headers.push(("dlq_attempts".to_string(), attempts.to_string().into_bytes()));
+    headers.push(("dlq_source_topic".to_string(), msg.source.as_bytes().to_vec()));
+    headers.push(("dlq_source_partition".to_string(), msg.partition.to_string().into_bytes()));
+    headers.push(("dlq_source_offset".to_string(), msg.offset.as_bytes().to_vec()));
+    headers
+}
+
+// In-process queue, for local development and driving the pipeline without a broker.
+#[derive(Clone, Default)]
+pub struct MemorySource {
+    queue: Arc<Mutex<VecDeque<IngestMessage>>>,
+    acked: Arc<Mutex<Vec<IngestMessage>>>,
+    dead_letters: Arc<Mutex<Vec<(IngestMessage, String)>>>,
+}
+
+impl MemorySource {
+    pub fn new() -> MemorySource {
+        MemorySource::default()
+    }
+
+    pub fn push(&self, payload: &str) {
+        let mut queue = self.queue.lock().unwrap();
+        let offset = queue.len() + self.acked.lock().unwrap().len();
+        queue.push_back(IngestMessage {
+            source: INGEST_BACKEND_MEMORY.to_string(),
+            partition: 0,
+            offset: offset.to_string(),
+            key: None,
+            payload: payload.as_bytes().to_vec(),
+            headers: vec![],
+        });
+    }
+
+    pub fn acked(&self) -> Vec<IngestMessage> {
+        self.acked.lock().unwrap().clone()
+    }
+
+    pub fn dead_letters(&self) -> Vec<(IngestMessage, String)> {
+        self.dead_letters.lock().unwrap().clone()
+    }
+}
+
+impl IngestSource for MemorySource {
+    fn name(&self) -> String {
+        INGEST_BACKEND_MEMORY.to_string()
+    }
+
+    fn poll(&mut self) -> Option<Result<IngestMessage, String>> {
+        let msg = self.queue.lock().unwrap().pop_front();
+        if msg.is_none() {
+            thread::sleep(Duration::from_millis(INGEST_POLL_TIMEOUT_MS));
+        }
+        msg.map(Ok)
+    }
+
+    fn ack(&mut self, msg: &IngestMessage) -> Result<(), String> {
+        self.acked.lock().unwrap().push(msg.clone());
+        Ok(())
+    }
+
+    fn dead_letter<'a>(&'a mut self, msg: &'a IngestMessage, error: &'a str, _attempts: u32) -> SourceFuture<'a> {
+        self.dead_letters.lock().unwrap().push((msg.clone(), error.to_string()));
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::kafka_handlers::{HandlerFuture, TOPIC_DELETE_MESSAGE, TOPIC_EDIT_MESSAGE, TOPIC_NEW_MESSAGE};
+    use serde_json::Value;
+    use std::time::Instant;
+    use uuid::Uuid;
+
+    // handled payloads as "room_id:seq" in completion order, and attempts per payload
+    #[derive(Default)]
+    struct TestContext {
+        handled: Mutex<Vec<String>>,
+        attempts: Mutex<HashMap<String, u32>>,
+    }
+
+    type Ctx = Arc<TestContext>;
+
+    fn payload_id(envelope: &MessageEnvelope) -> String {
+        format!("{}:{}", envelope.payload["room_id"].as_str().unwrap_or(""), envelope.payload["seq"])
+    }
+
+    fn attempt(ctx: &Ctx, envelope: &MessageEnvelope) -> u32 {
+        let mut attempts = ctx.attempts.lock().unwrap();
+        let count = attempts.entry(payload_id(envelope)).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    // sleeps delay_ms, then records the message
+    fn record(ctx: &Ctx, envelope: MessageEnvelope) -> HandlerFuture<'_> {
+        Box::pin(async move {
+            attempt(ctx, &envelope);
+            thread::sleep(Duration::from_millis(envelope.payload["delay_ms"].as_u64().unwrap_or(0)));
+            ctx.handled.lock().unwrap().push(payload_id(&envelope));
+            Ok(())
+        })
+    }
+
+    // fails with a transient error for the first fail_times attempts
+    fn flaky(ctx: &Ctx, envelope: MessageEnvelope) -> HandlerFuture<'_> {
+        Box::pin(async move {
+            if attempt(ctx, &envelope) <= envelope.payload["fail_times"].as_u64().unwrap_or(0) as u32 {
+                return Err(IngestError::Transient("unavailable".to_string()));
+            }
+            ctx.handled.lock().unwrap().push(payload_id(&envelope));
+            Ok(())
+        })
+    }
+
+    fn poison(ctx: &Ctx, envelope: MessageEnvelope) -> HandlerFuture<'_> {
+        Box::pin(async move {
+            attempt(ctx, &envelope);
+            Err(IngestError::Poison("can't be stored".to_string()))
+        })
+    }
+
+    fn registry() -> HandlerRegistry<Ctx> {
+        let mut registry = HandlerRegistry::new();
+        registry
+            .register(TOPIC_NEW_MESSAGE, record)
+            .register(TOPIC_EDIT_MESSAGE, flaky)
+            .register(TOPIC_DELETE_MESSAGE, poison);
+        registry
+    }
+
+    fn push(source: &MemorySource, event_type: &str, room_id: &str, seq: u32, extra: Value) {
+        let mut payload = json!({ "app_id": 1, "room_id": room_id, "user_id": "u1", "msg_owner": "u1", "msg_id": Uuid::nil(), "content": "hi", "send_at": 0, "seq": seq });
+        if let (Some(payload), Some(extra)) = (payload.as_object_mut(), extra.as_object()) {
+            payload.extend(extra.clone());
+        }
+        source.push(&json!({ "version": 2, "event_type": format!("{}1", event_type), "payload": payload }).to_string());
+    }
+
+    fn start(source: &MemorySource, workers: usize, max_retries: u32) -> Ctx {
+        let ctx: Ctx = Arc::new(TestContext::default());
+        let (source, pipeline_ctx) = (source.clone(), ctx.clone());
+        thread::spawn(move || {
+            executor::block_on(run(
+                Box::new(source),
+                pipeline_ctx,
+                registry(),
+                RetryPolicy { max_retries, backoff_ms: 1 },
+                ConcurrencyPolicy { workers, max_in_flight: 100 },
+            ))
+        });
+        ctx
+    }
+
+    fn wait_until<F: Fn() -> bool>(done: F) {
+        let started = Instant::now();
+        while !done() {
+            assert!(started.elapsed() < Duration::from_secs(10), "pipeline did not settle in time");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn offsets(msgs: &[IngestMessage]) -> Vec<String> {
+        msgs.iter().map(|msg| msg.offset.clone()).collect()
+    }
+
+    #[test]
+    fn finds_handler_by_longest_prefix() {
+        let mut registry = registry();
+        registry.register("NEWMESSAGE_PRIORITY_", poison);
+        let ctx: Ctx = Arc::new(TestContext::default());
+        let envelope = |event_type: &str| MessageEnvelope {
+            version: 2,
+            event_type: event_type.to_string(),
+            tenant: None,
+            trace_id: String::new(),
+            user_key: String::new(),
+            payload: json!({ "room_id": "r1", "seq": 0 }),
+        };
+        let handle = |event_type: &str| registry.find(event_type).map(|handler| executor::block_on(handler(&ctx, envelope(event_type))));
+        assert!(matches!(handle("NEWMESSAGE_1"), Some(Ok(()))));
+        assert!(matches!(handle("NEWMESSAGE_PRIORITY_1"), Some(Err(IngestError::Poison(_)))));
+        assert!(handle("RENAMEROOM_1").is_none());
+    }
+
+    #[test]
+    fn dispatches_to_handler_of_event_type() {
+        let source = MemorySource::new();
+        push(&source, TOPIC_NEW_MESSAGE, "r1", 0, json!({}));
+        push(&source, TOPIC_EDIT_MESSAGE, "r1", 1, json!({}));
+        push(&source, TOPIC_DELETE_MESSAGE, "r1", 2, json!({}));
+        let ctx = start(&source, 1, 0);
+        wait_until(|| source.acked().len() == 3);
+        assert_eq!(*ctx.handled.lock().unwrap(), vec!["r1:0".to_string(), "r1:1".to_string()]);
+        assert_eq!(ctx.attempts.lock().unwrap()["r1:2"], 1);
+        assert_eq!(offsets(&source.dead_letters().into_iter().map(|(msg, _)| msg).collect::<Vec<_>>()), vec!["2".to_string()]);
+    }
+
+    #[test]
+    fn acks_in_poll_order_when_completed_out_of_order() {
+        let source = MemorySource::new();
+        // the first message is the slowest, the others finish before it on other workers
+        push(&source, TOPIC_NEW_MESSAGE, "r0", 0, json!({ "delay_ms": 300 }));
+        for seq in 1..8 {
+            push(&source, TOPIC_NEW_MESSAGE, &format!("r{}", seq), seq, json!({}));
+        }
+        let ctx = start(&source, 4, 0);
+        wait_until(|| source.acked().len() == 8);
+        assert_ne!(ctx.handled.lock().unwrap()[0], "r0:0");
+        assert_eq!(offsets(&source.acked()), (0..8).map(|offset| offset.to_string()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let source = MemorySource::new();
+        push(&source, TOPIC_EDIT_MESSAGE, "r1", 0, json!({ "fail_times": 2 }));
+        let ctx = start(&source, 1, 3);
+        wait_until(|| source.acked().len() == 1);
+        assert_eq!(ctx.attempts.lock().unwrap()["r1:0"], 3);
+        assert_eq!(*ctx.handled.lock().unwrap(), vec!["r1:0".to_string()]);
+        assert!(source.dead_letters().is_empty());
+    }
+
+    #[test]
+    fn dead_letters_poison_and_exhausted_retries() {
+        let source = MemorySource::new();
+        push(&source, TOPIC_DELETE_MESSAGE, "r1", 0, json!({}));
+        push(&source, TOPIC_EDIT_MESSAGE, "r1", 1, json!({ "fail_times": 10 }));
+        source.push("not json");
+        push(&source, TOPIC_NEW_MESSAGE, "r1", 3, json!({}));
+        let ctx = start(&source, 1, 2);
+        wait_until(|| source.acked().len() == 4);
+        // poison is never retried, transient errors are retried max_retries times
+        assert_eq!(ctx.attempts.lock().unwrap()["r1:0"], 1);
+        assert_eq!(ctx.attempts.lock().unwrap()["r1:1"], 3);
+        let dead_letters = source.dead_letters();
+        assert_eq!(offsets(&dead_letters.iter().map(|(msg, _)| msg.clone()).collect::<Vec<_>>()), vec!["0", "1", "2"]);
+        assert_eq!(dead_letters[1].1, "unavailable");
+        assert_eq!(*ctx.handled.lock().unwrap(), vec!["r1:3".to_string()]);
+    }
+
+    #[test]
+    fn keeps_order_within_a_room() {
+        let source = MemorySource::new();
+        for seq in 0..20 {
+            let room_id = format!("r{}", seq % 4);
+            push(&source, TOPIC_NEW_MESSAGE, &room_id, seq, json!({ "delay_ms": (20 - seq) % 7 * 5 }));
+        }
+        let ctx = start(&source, 4, 0);
+        wait_until(|| source.acked().len() == 20);
+        let handled = ctx.handled.lock().unwrap().clone();
+        for room in 0..4 {
+            let room_id = format!("r{}:", room);
+            let seqs: Vec<u32> = handled.iter()
+                .filter(|id| id.starts_with(&room_id))
+                .map(|id| id[room_id.len()..].parse::<u32>().unwrap())
+                .collect();
+            assert_eq!(seqs, (0..20).filter(|seq| seq % 4 == room).collect::<Vec<_>>());
+        }
+    }
+}
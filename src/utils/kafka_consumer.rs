@@ -5,6 +5,7 @@ use futures::stream::FuturesUnordered;
 use rdkafka::message::{BorrowedMessage, OwnedMessage};
 use rdkafka::client::ClientContext;
 use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
+use rdkafka::consumer::base_consumer::BaseConsumer;
 use rdkafka::consumer::stream_consumer::StreamConsumer;
 use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, Rebalance};
 use rdkafka::error::KafkaResult;
@@ -14,13 +15,18 @@ use rdkafka::util::get_rdkafka_version;
 use serde_json::{Value};
 
 use crate::{
-    config::db::Pool,
     constants,
-    models::messages::{ Message as ModelMessage, AddMessage},
+    utils::{
+        ingest::{dead_letter_headers, IngestMessage, IngestSource, SourceFuture, INGEST_POLL_TIMEOUT_MS},
+        kafka_producer::EventProducer,
+    },
 };
 use serde::Deserializer;
 use uuid::Uuid;
 use futures::executor;
+use rdkafka::message::OwnedHeaders;
+use rdkafka::Offset;
+use std::time::Duration;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct KafkaData {
@@ -28,6 +34,14 @@ pub struct KafkaData {
     pub user_key: String,
     pub payload: String,
 }
+
+#[derive(Clone, Debug)]
+pub struct ConsumerConfig {
+    pub brokers: String,
+    pub group_id: String,
+    pub input_topic: String,
+    pub dead_letter_topic: String,
+}
 struct CustomContext;
 
 impl ClientContext for CustomContext {}
@@ -44,79 +58,84 @@ impl ConsumerContext for CustomContext {
     }
 }
 // A type alias with your custom consumer can be created for convenience.
-type LoggingConsumer = StreamConsumer<CustomContext>;
+type LoggingConsumer = BaseConsumer<CustomContext>;
 
-pub async fn start(
-    brokers: String,
-    group_id: String,
-    input_topic: String,
-    pool: Pool
-) {
-    let context = CustomContext;
-    let consumer: LoggingConsumer = ClientConfig::new()
-        .set("group.id", &group_id)
-        .set("bootstrap.servers", &brokers)
-        .set("enable.partition.eof", "false")
-        .set("session.timeout.ms", "6000")
-        .set("enable.auto.commit", "true")
-        //.set("statistics.interval.ms", "30000")
-        //.set("auto.offset.reset", "smallest")
-        .set_log_level(RDKafkaLogLevel::Debug)
-        .create_with_context(context)
-        .expect("Consumer creation failed");
+pub struct KafkaSource {
+    consumer: LoggingConsumer,
+    config: ConsumerConfig,
+    kafka_producer: EventProducer,
+}
 
-    consumer
-        .subscribe(&[&input_topic])
-        .expect("Can't subscribe to specified topics");
+impl KafkaSource {
+    pub fn new(config: ConsumerConfig, kafka_producer: EventProducer) -> KafkaSource {
+        let context = CustomContext;
+        let consumer: LoggingConsumer = ClientConfig::new()
+            .set("group.id", &config.group_id)
+            .set("bootstrap.servers", &config.brokers)
+            .set("enable.partition.eof", "false")
+            .set("session.timeout.ms", "6000")
+            // offsets are committed by hand once a message is stored or dead lettered
+            .set("enable.auto.commit", "false")
+            //.set("statistics.interval.ms", "30000")
+            //.set("auto.offset.reset", "smallest")
+            .set_log_level(RDKafkaLogLevel::Debug)
+            .create_with_context(context)
+            .expect("Consumer creation failed");
 
-    // consumer.start() returns a stream. The stream can be used ot chain together expensive steps,
-    // such as complex computations on a thread pool or asynchronous IO.
-    let mut message_stream = consumer.start();
+        consumer
+            .subscribe(&[&config.input_topic])
+            .expect("Can't subscribe to specified topics");
 
-    println!(" kafka consumer starting {:?} input_topic {:?}", brokers, input_topic);
-    while let Some(message) = message_stream.next().await {
-        match message {
-            Err(e) => println!("Kafka error: {}", e),
-            Ok(m) => {
-                let payload = match m.payload_view::<str>() {
-                    None => "",
-                    Some(Ok(s)) => s,
-                    Some(Err(e)) => {
-                        println!("Error while deserializing message payload: {:?}", e);
-                        ""
-                    }
-                };
+        println!(" kafka consumer starting {:?} input_topic {:?}", config.brokers, config.input_topic);
+        KafkaSource { consumer, config, kafka_producer }
+    }
+}
+
+impl IngestSource for KafkaSource {
+    fn name(&self) -> String {
+        format!("kafka {}", self.config.input_topic)
+    }
 
-                // println!("  payload == {:?}", payload);
-                match serde_json::from_str::<KafkaData>(&payload) {
-                    Ok(payload_data) => {
-                        if payload_data.topic.starts_with("NEWMESSAGE_") {
-                            println!("  payload_data.payload == {:?}", payload_data.payload);
-                            match serde_json::from_str::<AddMessage>(&payload_data.payload) {
-                                Ok(add_message) => {
-                                    // println!("serde_json::from_str::<AddMessage> add_message {:?}", add_message);
-                                    ModelMessage::add_new_msg(&pool.clone(), add_message).await;
-                                },
-                                Err(msg) => {
-                                    // handle error here
-                                    println!("serde_json::from_str::<Value> Error {:?}", msg);
-                                }
-                            }
+    fn poll(&mut self) -> Option<Result<IngestMessage, String>> {
+        match self.consumer.poll(Duration::from_millis(INGEST_POLL_TIMEOUT_MS))? {
+            Err(e) => Some(Err(format!("Kafka error: {}", e))),
+            Ok(m) => {
+                let mut headers: Vec<(String, Vec<u8>)> = vec![];
+                if let Some(m_headers) = m.headers() {
+                    for i in 0..m_headers.count() {
+                        if let Some((name, value)) = m_headers.get(i) {
+                            headers.push((name.to_string(), value.to_vec()));
                         }
-                    },
-                    Err(msg) => {
-                        // handle error here
-                        println!("serde_json::from_str::<KafkaData> Error {:?}", msg);
-                    }
-                }
-                if let Some(headers) = m.headers() {
-                    for i in 0..headers.count() {
-                        let header = headers.get(i).unwrap();
-                        // println!("  Header {:#?}: {:?}", header.0, header.1);
                     }
                 }
-                consumer.commit_message(&m, CommitMode::Async).unwrap();
+                Some(Ok(IngestMessage {
+                    source: m.topic().to_string(),
+                    partition: m.partition(),
+                    offset: m.offset().to_string(),
+                    key: m.key().map(|key| key.to_vec()),
+                    payload: m.payload().unwrap_or(&[]).to_vec(),
+                    headers,
+                }))
+            }
+        }
+    }
+
+    fn ack(&mut self, msg: &IngestMessage) -> Result<(), String> {
+        let offset = msg.offset.parse::<i64>().map_err(|e| e.to_string())?;
+        let mut offsets = TopicPartitionList::new();
+        // the committed offset is the next one to read
+        offsets.add_partition_offset(&msg.source, msg.partition, Offset::Offset(offset + 1));
+        self.consumer.commit(&offsets, CommitMode::Async).map_err(|e| format!("{:?}", e))
+    }
+
+    fn dead_letter<'a>(&'a mut self, msg: &'a IngestMessage, error: &'a str, attempts: u32) -> SourceFuture<'a> {
+        Box::pin(async move {
+            let mut headers = OwnedHeaders::new();
+            for (name, value) in dead_letter_headers(msg, error, attempts) {
+                headers = headers.add(&name, &value);
             }
-        };
+            let key = msg.key.clone().unwrap_or(vec![]);
+            self.kafka_producer.send(&self.config.dead_letter_topic, &key, &msg.payload, headers).await
+        })
     }
 }
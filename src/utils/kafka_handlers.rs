@@ -0,0 +1,145 @@
+use futures::future::Future;
+use serde::de::DeserializeOwned;
+use std::pin::Pin;
+
+use crate::{
+    config::db::{Pool, RedisPool},
+    error::ServiceError,
+    models::{
+        chat_room::ADDChatRoomUser,
+        messages::{AddMessage, DeleteMessage, EditMessage, ReadMessage},
+        presence::{Presence, PresenceEvent, PresenceUpdate},
+    },
+    services::{chat_rooms_service, message_service},
+    utils::{
+        envelope::MessageEnvelope,
+        ingest::IngestError,
+        kafka_producer::EventProducer,
+    },
+};
+
+// event type prefixes of the built-in handlers
+pub const TOPIC_NEW_MESSAGE: &str = "NEWMESSAGE_";
+pub const TOPIC_EDIT_MESSAGE: &str = "EDITMESSAGE_";
+pub const TOPIC_DELETE_MESSAGE: &str = "DELETEMESSAGE_";
+pub const TOPIC_READ_MESSAGE: &str = "READMESSAGE_";
+pub const TOPIC_ADD_ROOM_USER: &str = "ADDROOMUSER_";
+pub const TOPIC_PRESENCE: &str = "PRESENCE_";
+
+pub struct HandlerContext {
+    pub pool: Pool,
+    pub redis_pool: RedisPool,
+    pub kafka_producer: EventProducer,
+    pub message_edit_window_secs: i64,
+}
+
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Result<(), IngestError>> + 'a>>;
+// handlers get the context the pipeline was started with, HandlerContext outside of tests
+pub type Handler<C = HandlerContext> = for<'a> fn(&'a C, MessageEnvelope) -> HandlerFuture<'a>;
+
+pub struct HandlerRegistry<C = HandlerContext> {
+    handlers: Vec<(String, Handler<C>)>,
+}
+
+impl<C> HandlerRegistry<C> {
+    pub fn new() -> HandlerRegistry<C> {
+        HandlerRegistry { handlers: vec![] }
+    }
+
+    pub fn register(&mut self, event_type_prefix: &str, handler: Handler<C>) -> &mut HandlerRegistry<C> {
+        self.handlers.push((event_type_prefix.to_string(), handler));
+        self
+    }
+
+    // the longest matching prefix wins so specific handlers can shadow generic ones
+    pub fn find(&self, event_type: &str) -> Option<Handler<C>> {
+        self.handlers.iter()
+            .filter(|(prefix, _)| event_type.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, handler)| *handler)
+    }
+}
+
+impl Default for HandlerRegistry {
+    fn default() -> HandlerRegistry {
+        let mut registry = HandlerRegistry::new();
+        registry
+            .register(TOPIC_NEW_MESSAGE, new_message)
+            .register(TOPIC_EDIT_MESSAGE, edit_message)
+            .register(TOPIC_DELETE_MESSAGE, delete_message)
+            .register(TOPIC_READ_MESSAGE, read_message)
+            .register(TOPIC_ADD_ROOM_USER, add_room_user)
+            .register(TOPIC_PRESENCE, presence);
+        registry
+    }
+}
+
+pub fn parse_payload<T: DeserializeOwned>(envelope: &MessageEnvelope) -> Result<T, IngestError> {
+    serde_json::from_value::<T>(envelope.payload.clone())
+        .map_err(|msg| IngestError::Poison(format!("Can't parse {} payload: {:?}", envelope.event_type, msg)))
+}
+
+// rejected requests (not found, forbidden) will never succeed, only server errors are retried
+fn service_error(err: ServiceError) -> IngestError {
+    if err.http_status.is_server_error() {
+        IngestError::Transient(err.body.message)
+    } else {
+        IngestError::Poison(err.body.message)
+    }
+}
+
+fn new_message(ctx: &HandlerContext, envelope: MessageEnvelope) -> HandlerFuture {
+    Box::pin(async move {
+        let msg: AddMessage = parse_payload(&envelope)?;
+        message_service::add_msg(msg, &ctx.pool, &ctx.redis_pool, &ctx.kafka_producer).await
+            .map(|_| ())
+            .map_err(service_error)
+    })
+}
+
+fn edit_message(ctx: &HandlerContext, envelope: MessageEnvelope) -> HandlerFuture {
+    Box::pin(async move {
+        let msg: EditMessage = parse_payload(&envelope)?;
+        message_service::edit_msg(msg, &ctx.pool, &ctx.redis_pool, &ctx.kafka_producer, ctx.message_edit_window_secs).await
+            .map(|_| ())
+            .map_err(service_error)
+    })
+}
+
+fn delete_message(ctx: &HandlerContext, envelope: MessageEnvelope) -> HandlerFuture {
+    Box::pin(async move {
+        let msg: DeleteMessage = parse_payload(&envelope)?;
+        message_service::delete_msg(msg, &ctx.pool, &ctx.redis_pool, &ctx.kafka_producer).await
+            .map(|_| ())
+            .map_err(service_error)
+    })
+}
+
+fn read_message(ctx: &HandlerContext, envelope: MessageEnvelope) -> HandlerFuture {
+    Box::pin(async move {
+        let msg: ReadMessage = parse_payload(&envelope)?;
+        message_service::mark_read(msg, &ctx.pool, &ctx.redis_pool).await
+            .map(|_| ())
+            .map_err(service_error)
+    })
+}
+
+fn add_room_user(ctx: &HandlerContext, envelope: MessageEnvelope) -> HandlerFuture {
+    Box::pin(async move {
+        let room_user: ADDChatRoomUser = parse_payload(&envelope)?;
+        chat_rooms_service::add_room_user(room_user, &ctx.pool, &ctx.redis_pool, &ctx.kafka_producer).await
+            .map(|_| ())
+            .map_err(service_error)
+    })
+}
+
+fn presence(ctx: &HandlerContext, envelope: MessageEnvelope) -> HandlerFuture {
+    Box::pin(async move {
+        let update: PresenceUpdate = parse_payload(&envelope)?;
+        let event = PresenceEvent::from_payload(&update.event)
+            .ok_or(IngestError::Poison(format!("Unknown presence event {:?}", update.event)))?;
+        Presence::update(&ctx.redis_pool, update.app_id, &update.user_id, &update.device_id, event)
+            .map(|_| ())
+            .map_err(IngestError::Transient)
+    })
+}
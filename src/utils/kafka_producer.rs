@@ -0,0 +1,177 @@
+use futures::executor;
+use rdkafka::config::ClientConfig;
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord};
+use std::collections::HashMap;
+use std::env;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use uuid::Uuid;
+
+// event types, the topic of each can be overridden with KAFKA_TOPIC_<EVENT_TYPE>
+pub const EVENT_ROOM_CREATED: &str = "room_created";
+pub const EVENT_ROOM_DELETED: &str = "room_deleted";
+pub const EVENT_ROOM_UPDATED: &str = "room_updated";
+pub const EVENT_ROOM_USER_ADDED: &str = "room_user_added";
+pub const EVENT_ROOM_USER_REMOVED: &str = "room_user_removed";
+pub const EVENT_MESSAGE_CREATED: &str = "message_created";
+pub const EVENT_MESSAGE_EDITED: &str = "message_edited";
+pub const EVENT_MESSAGE_DELETED: &str = "message_deleted";
+pub const EVENT_TYPES: [&str; 8] = [EVENT_ROOM_CREATED, EVENT_ROOM_DELETED, EVENT_ROOM_UPDATED, EVENT_ROOM_USER_ADDED, EVENT_ROOM_USER_REMOVED, EVENT_MESSAGE_CREATED, EVENT_MESSAGE_EDITED, EVENT_MESSAGE_DELETED];
+
+// delivery reports waiting to be logged, reports past this are dropped unlogged
+const DELIVERY_REPORT_QUEUE_SIZE: usize = 10000;
+
+// Untagged, so the fields of a variant must not be accepted by an earlier one. Unknown fields
+// are denied to keep e.g. a MessageDeleted from reading as a RoomDeleted.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum ChatEvent {
+    RoomCreated { app_id: i64, room_id: String, room_owner: String, room_type: i8, users: Vec<String> },
+    RoomDeleted { app_id: i64, room_id: String },
+    // fields left out of the update are None, a cleared banner or about is ""
+    RoomUpdated { app_id: i64, room_id: String, room_name: Option<String>, banner: Option<String>, about: Option<String> },
+    RoomUserAdded { app_id: i64, room_id: String, users: Vec<String> },
+    RoomUserRemoved { app_id: i64, room_id: String, user_id: String, removed_by: String },
+    MessageCreated { app_id: i64, room_id: String, msg_id: Uuid, msg_time: Uuid, msg_owner: String, content: String, message_type: i8, send_at: i64 },
+    MessageEdited { app_id: i64, room_id: String, msg_id: Uuid, msg_time: Uuid, content: String, edited_at: i64 },
+    MessageDeleted { app_id: i64, room_id: String, msg_id: Uuid, msg_time: Uuid },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EventEnvelope {
+    pub event_type: String,
+    pub created_at: i64,
+    pub data: ChatEvent,
+}
+
+impl ChatEvent {
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            ChatEvent::RoomCreated { .. } => EVENT_ROOM_CREATED,
+            ChatEvent::RoomDeleted { .. } => EVENT_ROOM_DELETED,
+            ChatEvent::RoomUpdated { .. } => EVENT_ROOM_UPDATED,
+            ChatEvent::RoomUserAdded { .. } => EVENT_ROOM_USER_ADDED,
+            ChatEvent::RoomUserRemoved { .. } => EVENT_ROOM_USER_REMOVED,
+            ChatEvent::MessageCreated { .. } => EVENT_MESSAGE_CREATED,
+            ChatEvent::MessageEdited { .. } => EVENT_MESSAGE_EDITED,
+            ChatEvent::MessageDeleted { .. } => EVENT_MESSAGE_DELETED,
+        }
+    }
+    // events of a room share a key so they keep their order within a partition
+    pub fn key(&self) -> String {
+        match self {
+            ChatEvent::RoomCreated { room_id, .. } => room_id.clone(),
+            ChatEvent::RoomDeleted { room_id, .. } => room_id.clone(),
+            ChatEvent::RoomUpdated { room_id, .. } => room_id.clone(),
+            ChatEvent::RoomUserAdded { room_id, .. } => room_id.clone(),
+            ChatEvent::RoomUserRemoved { room_id, .. } => room_id.clone(),
+            ChatEvent::MessageCreated { room_id, .. } => room_id.clone(),
+            ChatEvent::MessageEdited { room_id, .. } => room_id.clone(),
+            ChatEvent::MessageDeleted { room_id, .. } => room_id.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EventProducer {
+    producer: FutureProducer,
+    topics: HashMap<String, String>,
+    delivery_reports: SyncSender<(&'static str, DeliveryFuture)>,
+}
+
+impl EventProducer {
+    pub fn new(brokers: &str, default_topic: &str) -> EventProducer {
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", "5000")
+            .create()
+            .expect("Producer creation failed");
+        let mut topics: HashMap<String, String> = HashMap::new();
+        for event_type in EVENT_TYPES.iter() {
+            let topic = env::var(format!("KAFKA_TOPIC_{}", event_type.to_uppercase())).unwrap_or(default_topic.to_string());
+            topics.insert(event_type.to_string(), topic);
+        }
+        // emit only enqueues, failed deliveries are logged from here
+        let (delivery_reports, reports) = mpsc::sync_channel::<(&'static str, DeliveryFuture)>(DELIVERY_REPORT_QUEUE_SIZE);
+        thread::spawn(move || {
+            for (event_type, delivery) in reports {
+                match executor::block_on(delivery) {
+                    Ok(Ok(_)) => {},
+                    Ok(Err((err, _))) => error!("Can't deliver {} event: {:?}", event_type, err),
+                    Err(_) => error!("Can't deliver {} event: delivery cancelled", event_type),
+                }
+            }
+        });
+        EventProducer { producer, topics, delivery_reports }
+    }
+
+    pub fn topic(&self, event_type: &str) -> &str {
+        &self.topics[event_type]
+    }
+
+    // Fire and forget, the event is handed to the producer queue without waiting for the
+    // broker. Events of a room are enqueued in call order.
+    pub fn emit(&self, event: ChatEvent) {
+        let event_type = event.event_type();
+        let key = event.key();
+        let envelope = EventEnvelope {
+            event_type: event_type.to_string(),
+            created_at: chrono::Utc::now().timestamp_millis(),
+            data: event,
+        };
+        let payload = match serde_json::to_string(&envelope) {
+            Ok(payload) => payload,
+            Err(msg) => {
+                println!("serde_json::to_string::<EventEnvelope> Error {:?}", msg);
+                return;
+            }
+        };
+        let headers = OwnedHeaders::new().add("event_type", event_type);
+        let record = FutureRecord::to(self.topic(event_type))
+            .key(key.as_bytes())
+            .payload(payload.as_bytes())
+            .headers(headers);
+        let delivery = self.producer.send(record, 0);
+        if let Err(TrySendError::Full(_)) = self.delivery_reports.try_send((event_type, delivery)) {
+            error!("Delivery report queue full, not waiting for {} event", event_type);
+        }
+    }
+
+    pub async fn send(&self, topic: &str, key: &[u8], payload: &[u8], headers: OwnedHeaders) -> Result<(), String> {
+        let record = FutureRecord::to(topic)
+            .key(key)
+            .payload(payload)
+            .headers(headers);
+        match self.producer.send(record, 0).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err((err, _))) => Err(format!("{:?}", err)),
+            Err(_) => Err("delivery cancelled".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_read_back_as_their_own_variant() {
+        let (room_id, msg_id) = ("r1".to_string(), Uuid::new_v4());
+        let events = vec![
+            ChatEvent::RoomCreated { app_id: 1, room_id: room_id.clone(), room_owner: "u1".to_string(), room_type: 0, users: vec!["u1".to_string()] },
+            ChatEvent::RoomDeleted { app_id: 1, room_id: room_id.clone() },
+            ChatEvent::RoomUpdated { app_id: 1, room_id: room_id.clone(), room_name: None, banner: None, about: None },
+            ChatEvent::RoomUserAdded { app_id: 1, room_id: room_id.clone(), users: vec![] },
+            ChatEvent::RoomUserRemoved { app_id: 1, room_id: room_id.clone(), user_id: "u2".to_string(), removed_by: "u1".to_string() },
+            ChatEvent::MessageCreated { app_id: 1, room_id: room_id.clone(), msg_id, msg_time: msg_id, msg_owner: "u1".to_string(), content: "hi".to_string(), message_type: 1, send_at: 0 },
+            ChatEvent::MessageEdited { app_id: 1, room_id: room_id.clone(), msg_id, msg_time: msg_id, content: "hi".to_string(), edited_at: 0 },
+            ChatEvent::MessageDeleted { app_id: 1, room_id: room_id.clone(), msg_id, msg_time: msg_id },
+        ];
+        for event in events {
+            let json = serde_json::to_string(&event).unwrap();
+            let read = serde_json::from_str::<ChatEvent>(&json).unwrap();
+            assert_eq!(read.event_type(), event.event_type(), "{}", json);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use actix_web::web::Bytes;
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
+use std::io::Cursor;
+
+// longest side of generated thumbnails
+pub const THUMBNAIL_MAX_SIZE: u32 = 320;
+pub const THUMBNAIL_CONTENT_TYPE: &str = "image/jpeg";
+const THUMBNAIL_JPEG_QUALITY: u8 = 80;
+const JPEG_QUALITY: u8 = 90;
+// blurhash is computed from a tiny copy, its components don't need more detail
+const BLURHASH_SOURCE_SIZE: u32 = 32;
+const BLURHASH_COMPONENTS_X: u32 = 4;
+const BLURHASH_COMPONENTS_Y: u32 = 3;
+// points in a voice message waveform, each 0..=WAVEFORM_MAX_LEVEL
+pub const WAVEFORM_SAMPLES: usize = 64;
+pub const WAVEFORM_MAX_LEVEL: i32 = 100;
+pub const VOICE_MAX_DURATION_MS: i64 = 15 * 60 * 1000;
+// granule positions of Opus streams always count 48 kHz samples
+const OPUS_GRANULE_RATE: u64 = 48000;
+
+pub struct ProcessedImage {
+    // re-encoded without EXIF/GPS metadata, may change content type (webp is stored as png)
+    pub data: Bytes,
+    pub content_type: String,
+    pub width: i32,
+    pub height: i32,
+    pub thumbnail: Bytes,
+    pub blurhash: String,
+}
+
+pub struct VoiceInfo {
+    pub duration_ms: i64,
+    pub waveform: Vec<i32>,
+}
+
+pub fn is_voice(content_type: &str) -> bool {
+    content_type == "audio/ogg" || content_type == "audio/opus"
+}
+
+pub fn is_image(content_type: &str) -> bool {
+    image_format(content_type).is_some()
+}
+
+fn image_format(content_type: &str) -> Option<ImageFormat> {
+    match content_type {
+        "image/jpeg" => Some(ImageFormat::Jpeg),
+        "image/png" => Some(ImageFormat::Png),
+        "image/gif" => Some(ImageFormat::Gif),
+        "image/webp" => Some(ImageFormat::WebP),
+        _ => None,
+    }
+}
+
+// EXIF orientation, 1 (upright) when missing. Only JPEG carries it in practice.
+fn exif_orientation(data: &[u8]) -> u32 {
+    exif::Reader::new().read_from_container(&mut Cursor::new(data)).ok()
+        .and_then(|exif| exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY).and_then(|field| field.value.get_uint(0)))
+        .unwrap_or(1)
+}
+
+fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+fn encode(img: &DynamicImage, format: ImageOutputFormat) -> Result<Bytes, String> {
+    let mut out: Vec<u8> = vec![];
+    img.write_to(&mut out, format).map_err(|err| format!("can't encode image, error - {:?}", err))?;
+    Ok(Bytes::from(out))
+}
+
+// Decodes the upload, turns it upright and re-encodes it, which drops EXIF/GPS and any other
+// metadata. GIFs are kept byte for byte so animations survive, the format has no EXIF.
+pub fn process_image(content_type: &str, data: Bytes) -> Result<ProcessedImage, String> {
+    let format = image_format(content_type).ok_or(format!("{} is not an image", content_type))?;
+    let img = image::load_from_memory_with_format(&data, format).map_err(|err| format!("can't decode image, error - {:?}", err))?;
+    let img = apply_orientation(img, exif_orientation(&data));
+    let (data, content_type) = match format {
+        ImageFormat::Jpeg => (encode(&DynamicImage::ImageRgb8(img.to_rgb8()), ImageOutputFormat::Jpeg(JPEG_QUALITY))?, "image/jpeg"),
+        ImageFormat::Gif => (data, "image/gif"),
+        _ => (encode(&img, ImageOutputFormat::Png)?, "image/png"),
+    };
+    let thumbnail = img.thumbnail(THUMBNAIL_MAX_SIZE, THUMBNAIL_MAX_SIZE);
+    let small = thumbnail.thumbnail(BLURHASH_SOURCE_SIZE, BLURHASH_SOURCE_SIZE).to_rgba8();
+    let blurhash = blurhash::encode(BLURHASH_COMPONENTS_X, BLURHASH_COMPONENTS_Y, small.width(), small.height(), &small.into_raw())
+        .map_err(|err| format!("can't compute blurhash, error - {:?}", err))?;
+    Ok(ProcessedImage {
+        data,
+        content_type: content_type.to_string(),
+        width: img.width() as i32,
+        height: img.height() as i32,
+        thumbnail: encode(&DynamicImage::ImageRgb8(thumbnail.to_rgb8()), ImageOutputFormat::Jpeg(THUMBNAIL_JPEG_QUALITY))?,
+        blurhash,
+    })
+}
+
+// Checks the upload is an Ogg Opus stream and reads its duration from the last granule
+// position. The waveform isn't decoded audio: with VBR the size of an Opus packet follows
+// the loudness of its frame closely enough for a scrubber, and silent (DTX) frames are tiny.
+pub fn process_voice(data: &[u8]) -> Result<VoiceInfo, String> {
+    let mut reader = ogg::PacketReader::new(Cursor::new(data));
+    let head = reader.read_packet().map_err(|err| format!("not an ogg stream, error - {:?}", err))?
+        .ok_or("empty ogg stream".to_string())?;
+    if head.data.len() < 19 || &head.data[0..8] != b"OpusHead" {
+        return Err("ogg stream is not opus".to_string())
+    }
+    let serial = head.stream_serial();
+    let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as u64;
+    let mut packet_sizes: Vec<usize> = vec![];
+    let mut last_granule: u64 = 0;
+    // the OpusTags packet follows the header
+    let mut tags_seen = false;
+    while let Some(packet) = reader.read_packet().map_err(|err| format!("broken ogg stream, error - {:?}", err))? {
+        if packet.stream_serial() != serial {
+            continue;
+        }
+        if !tags_seen {
+            tags_seen = true;
+            continue;
+        }
+        packet_sizes.push(packet.data.len());
+        last_granule = packet.absgp_page();
+    }
+    if packet_sizes.is_empty() {
+        return Err("opus stream has no audio".to_string())
+    }
+    let duration_ms = (last_granule.saturating_sub(pre_skip) * 1000 / OPUS_GRANULE_RATE) as i64;
+    if duration_ms > VOICE_MAX_DURATION_MS {
+        return Err(format!("voice message is longer than {} ms", VOICE_MAX_DURATION_MS))
+    }
+    Ok(VoiceInfo {
+        duration_ms,
+        waveform: waveform(&packet_sizes),
+    })
+}
+
+fn waveform(packet_sizes: &[usize]) -> Vec<i32> {
+    let samples = std::cmp::min(WAVEFORM_SAMPLES, packet_sizes.len());
+    let averages: Vec<f64> = (0..samples).map(|i| {
+        let bin = &packet_sizes[i * packet_sizes.len() / samples..(i + 1) * packet_sizes.len() / samples];
+        bin.iter().sum::<usize>() as f64 / bin.len() as f64
+    }).collect();
+    let max = averages.iter().cloned().fold(1.0, f64::max);
+    averages.iter().map(|average| (average / max * WAVEFORM_MAX_LEVEL as f64).round() as i32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+
+    // mono Opus stream with 20 ms packets of the given sizes
+    fn opus_stream(pre_skip: u16, packet_sizes: &[usize]) -> Vec<u8> {
+        let mut writer = PacketWriter::new(Vec::new());
+        let mut head = b"OpusHead".to_vec();
+        head.extend_from_slice(&[1, 1]);
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&48000u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        writer.write_packet(head.into_boxed_slice(), 1, PacketWriteEndInfo::EndPage, 0).unwrap();
+        writer.write_packet(b"OpusTags".to_vec().into_boxed_slice(), 1, PacketWriteEndInfo::EndPage, 0).unwrap();
+        for (i, size) in packet_sizes.iter().enumerate() {
+            let end = if i + 1 == packet_sizes.len() { PacketWriteEndInfo::EndStream } else { PacketWriteEndInfo::EndPage };
+            let granule = pre_skip as u64 + (i as u64 + 1) * 960;
+            writer.write_packet(vec![0u8; *size].into_boxed_slice(), 1, end, granule).unwrap();
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn reads_voice_duration_without_pre_skip() {
+        let voice = process_voice(&opus_stream(312, &[40; 50])).unwrap();
+        assert_eq!(voice.duration_ms, 1000);
+        assert_eq!(voice.waveform, vec![WAVEFORM_MAX_LEVEL; 50]);
+    }
+
+    #[test]
+    fn rejects_streams_that_are_not_opus_voice() {
+        assert!(process_voice(b"not ogg at all").is_err());
+        assert!(process_voice(&opus_stream(312, &[])).is_err());
+        // 15 minutes and 20 ms
+        assert!(process_voice(&opus_stream(0, &vec![1; 45_001])).is_err());
+    }
+
+    #[test]
+    fn waveform_scales_to_the_loudest_sample() {
+        assert_eq!(waveform(&[10, 20, 5]), vec![50, 100, 25]);
+        assert_eq!(waveform(&[0, 0]), vec![0, 0]);
+        // more packets than samples are averaged per bin
+        let sizes: Vec<usize> = (0..WAVEFORM_SAMPLES * 2).map(|i| if i < WAVEFORM_SAMPLES { 10 } else { 20 }).collect();
+        let levels = waveform(&sizes);
+        assert_eq!(levels.len(), WAVEFORM_SAMPLES);
+        assert_eq!(levels[0], WAVEFORM_MAX_LEVEL / 2);
+        assert_eq!(levels[WAVEFORM_SAMPLES - 1], WAVEFORM_MAX_LEVEL);
+    }
+}
@@ -1,2 +1,12 @@
 pub mod token_utils;
+pub mod blob_store;
+pub mod envelope;
+pub mod ingest;
 pub mod kafka_consumer;
+pub mod kafka_handlers;
+pub mod kafka_producer;
+pub mod media;
+pub mod mqtt_presence;
+pub mod redis_stream_consumer;
+pub mod search_index;
+pub mod time_uuid;
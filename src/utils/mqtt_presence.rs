@@ -0,0 +1,68 @@
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+use std::time::Duration;
+
+use crate::{
+    config::db::RedisPool,
+    models::presence::{Presence, PresenceEvent},
+};
+
+// Clients publish "connected" / "disconnected" to {topic}/{app_id}/{user_id}/{device_id}
+// and register "lwt" on the same topic as their last will, so the broker reports
+// dropped connections for them.
+pub fn start(
+    host: String,
+    port: u16,
+    client_id: String,
+    presence_topic: String,
+    redis_pool: RedisPool
+) {
+    let mut mqtt_options = MqttOptions::new(client_id, host.clone(), port);
+    mqtt_options.set_keep_alive(Duration::from_secs(30));
+    let (mut client, mut connection) = Client::new(mqtt_options, 10);
+    let subscribe_topic = format!("{}/+/+/+", presence_topic);
+
+    println!(" mqtt presence starting {:?} topic {:?}", host, subscribe_topic);
+    for notification in connection.iter() {
+        match notification {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                // subscriptions are lost with a clean session, so renew them on every (re)connect
+                if let Err(e) = client.try_subscribe(subscribe_topic.clone(), QoS::AtLeastOnce) {
+                    error!("Can't subscribe to presence topic: {:?}", e);
+                }
+            },
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let payload = String::from_utf8_lossy(&publish.payload);
+                handle_presence(&redis_pool, &presence_topic, &publish.topic, &payload);
+            },
+            Ok(_) => {},
+            Err(e) => {
+                error!("Mqtt error: {:?}", e);
+                std::thread::sleep(Duration::from_secs(5));
+            }
+        }
+    }
+}
+
+fn handle_presence(redis_pool: &RedisPool, presence_topic: &str, topic: &str, payload: &str) {
+    let parts: Vec<&str> = topic[presence_topic.len()..].trim_start_matches('/').split('/').collect();
+    if parts.len() != 3 {
+        println!("Unknown presence topic {:?}", topic);
+        return;
+    }
+    let app_id = match parts[0].parse::<i64>() {
+        Ok(app_id) => app_id,
+        Err(_) => {
+            println!("Invalid app id in presence topic {:?}", topic);
+            return;
+        }
+    };
+    match PresenceEvent::from_payload(payload) {
+        Some(event) => {
+            match Presence::update(redis_pool, app_id, parts[1], parts[2], event) {
+                Ok(presence) => info!("Presence {:?}", presence),
+                Err(msg) => println!("Presence::update Error {:?}", msg),
+            }
+        },
+        None => println!("Unknown presence payload {:?} on {:?}", payload, topic),
+    }
+}
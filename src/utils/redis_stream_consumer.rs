@@ -0,0 +1,149 @@
+use r2d2_redis::redis::{self, Value};
+
+use crate::{
+    config::db::RedisPool,
+    utils::ingest::{dead_letter_headers, IngestMessage, IngestSource, SourceFuture, INGEST_POLL_TIMEOUT_MS},
+};
+
+#[derive(Clone, Debug)]
+pub struct RedisStreamConfig {
+    pub stream: String,
+    pub group: String,
+    pub consumer: String,
+    pub dead_letter_stream: String,
+}
+
+// Reads stream entries through a consumer group. Each entry carries the message envelope json in
+// its "payload" field, an optional "key" and any other fields as headers.
+pub struct RedisStreamSource {
+    redis_pool: RedisPool,
+    config: RedisStreamConfig,
+    // entries delivered to this consumer but never acked are read again after a restart,
+    // this is the id the pending read continues after until they are drained
+    pending_after: Option<String>,
+}
+
+impl RedisStreamSource {
+    pub fn new(redis_pool: RedisPool, config: RedisStreamConfig) -> RedisStreamSource {
+        let mut redis_conn = redis_pool.get().expect("Can't get redis connection");
+        let created: redis::RedisResult<String> = redis::cmd("XGROUP")
+            .arg("CREATE").arg(&config.stream).arg(&config.group).arg("$").arg("MKSTREAM")
+            .query(&mut *redis_conn);
+        if let Err(e) = created {
+            // BUSYGROUP, the group already exists
+            info!("XGROUP CREATE {}: {:?}", config.stream, e);
+        }
+        println!(" redis stream consumer starting {:?} group {:?}", config.stream, config.group);
+        RedisStreamSource { redis_pool, config, pending_after: Some("0".to_string()) }
+    }
+}
+
+fn bulk(value: &Value) -> Option<&Vec<Value>> {
+    match value {
+        Value::Bulk(values) => Some(values),
+        _ => None,
+    }
+}
+
+fn data(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Data(bytes) => Some(bytes.clone()),
+        Value::Status(status) => Some(status.as_bytes().to_vec()),
+        _ => None,
+    }
+}
+
+impl IngestSource for RedisStreamSource {
+    fn name(&self) -> String {
+        format!("redis stream {}", self.config.stream)
+    }
+
+    fn poll(&mut self) -> Option<Result<IngestMessage, String>> {
+        let mut redis_conn = match self.redis_pool.get() {
+            Ok(redis_conn) => redis_conn,
+            Err(e) => return Some(Err(e.to_string())),
+        };
+        let start_id = self.pending_after.clone().unwrap_or(">".to_string());
+        let reply: Value = match redis::cmd("XREADGROUP")
+            .arg("GROUP").arg(&self.config.group).arg(&self.config.consumer)
+            .arg("COUNT").arg(1)
+            .arg("BLOCK").arg(INGEST_POLL_TIMEOUT_MS)
+            .arg("STREAMS").arg(&self.config.stream).arg(&start_id)
+            .query(&mut *redis_conn) {
+            Ok(reply) => reply,
+            Err(e) => return Some(Err(e.to_string())),
+        };
+
+        // [[stream, [[id, [field, value, ...]]]]], or nil when the block timed out
+        let entry = bulk(&reply)
+            .and_then(|streams| streams.get(0))
+            .and_then(bulk)
+            .and_then(|stream| stream.get(1))
+            .and_then(bulk)
+            .and_then(|entries| entries.get(0));
+        let entry = match entry.and_then(bulk) {
+            Some(entry) => entry,
+            None => {
+                // pending entries are drained, continue with new ones
+                self.pending_after = None;
+                return None;
+            }
+        };
+        let id = match entry.get(0).and_then(data) {
+            Some(id) => String::from_utf8_lossy(&id).to_string(),
+            None => return Some(Err(format!("Stream entry without id {:?}", entry))),
+        };
+        if self.pending_after.is_some() {
+            self.pending_after = Some(id.clone());
+        }
+
+        let mut msg = IngestMessage {
+            source: self.config.stream.clone(),
+            partition: 0,
+            offset: id,
+            key: None,
+            payload: vec![],
+            headers: vec![],
+        };
+        // fields are nil for pending entries that were deleted from the stream, they end up dead lettered
+        if let Some(fields) = entry.get(1).and_then(bulk) {
+            for pair in fields.chunks(2) {
+                if let (Some(name), Some(value)) = (pair.get(0).and_then(data), pair.get(1).and_then(data)) {
+                    match String::from_utf8_lossy(&name).as_ref() {
+                        "payload" => msg.payload = value,
+                        "key" => msg.key = Some(value),
+                        name => msg.headers.push((name.to_string(), value)),
+                    }
+                }
+            }
+        }
+        Some(Ok(msg))
+    }
+
+    fn ack(&mut self, msg: &IngestMessage) -> Result<(), String> {
+        let mut redis_conn = self.redis_pool.get().map_err(|e| e.to_string())?;
+        redis::cmd("XACK")
+            .arg(&self.config.stream).arg(&self.config.group).arg(&msg.offset)
+            .query::<i64>(&mut *redis_conn)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn dead_letter<'a>(&'a mut self, msg: &'a IngestMessage, error: &'a str, attempts: u32) -> SourceFuture<'a> {
+        let result = self.redis_pool.get().map_err(|e| e.to_string()).and_then(|mut redis_conn| {
+            let mut xadd = redis::cmd("XADD");
+            xadd.arg(&self.config.dead_letter_stream).arg("*")
+                .arg("payload").arg(&msg.payload[..]);
+            if let Some(key) = &msg.key {
+                xadd.arg("key").arg(&key[..]);
+            }
+            for (name, value) in dead_letter_headers(msg, error, attempts) {
+                xadd.arg(name).arg(value);
+            }
+            xadd.query::<String>(&mut *redis_conn)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        });
+        Box::pin(async move { result })
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    env, fs,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tantivy::{
+    collector::{Count, TopDocs},
+    directory::{error::LockError, MmapDirectory},
+    doc,
+    query::{BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery},
+    schema::{Field, IndexRecordOption, Schema, Value, FAST, INDEXED, STORED, STRING, TEXT},
+    Index, IndexReader, IndexWriter, ReloadPolicy, SnippetGenerator, TantivyDocument, Term,
+};
+
+// writer heap shared by the indexing threads
+const WRITER_HEAP_BYTES: usize = 50_000_000;
+// adds and deletes become searchable on the next commit
+const COMMIT_INTERVAL_MS: u64 = 1000;
+const SNIPPET_MAX_CHARS: usize = 160;
+
+lazy_static::lazy_static! {
+    static ref SEARCH_INDEX: Result<SearchIndex, String> = SearchIndex::open(&env::var("SEARCH_INDEX_DIR").unwrap_or("./search_index".to_string()));
+}
+
+pub struct SearchFields {
+    msg_id: Field,
+    app_id: Field,
+    room_id: Field,
+    msg_owner: Field,
+    msg_time: Field,
+    content: Field,
+    created_at: Field,
+}
+
+// One document per message, keyed by msg_id. Only the content is tokenized, the other fields
+// are exact-match filters.
+pub struct SearchIndex {
+    index: Index,
+    reader: IndexReader,
+    writer: Mutex<IndexWriter>,
+    fields: SearchFields,
+    dirty: AtomicBool,
+}
+
+pub struct IndexedMessage {
+    pub app_id: i64,
+    pub room_id: String,
+    pub msg_owner: String,
+    pub msg_id: String,
+    pub msg_time: String,
+    pub content: String,
+    pub created_at: i64,
+}
+
+pub struct SearchFilter {
+    pub app_id: i64,
+    // rooms the caller may see, at least one
+    pub room_ids: Vec<String>,
+    pub sender: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub room_id: String,
+    pub msg_id: String,
+    pub msg_time: String,
+    pub msg_owner: String,
+    pub created_at: i64,
+    // matched terms wrapped in <b></b>
+    pub snippet: String,
+    pub score: f32,
+}
+
+fn schema() -> (Schema, SearchFields) {
+    let mut builder = Schema::builder();
+    let fields = SearchFields {
+        msg_id: builder.add_text_field("msg_id", STRING | STORED),
+        app_id: builder.add_i64_field("app_id", INDEXED),
+        room_id: builder.add_text_field("room_id", STRING | STORED),
+        msg_owner: builder.add_text_field("msg_owner", STRING | STORED),
+        msg_time: builder.add_text_field("msg_time", STORED),
+        content: builder.add_text_field("content", TEXT | STORED),
+        created_at: builder.add_i64_field("created_at", INDEXED | STORED | FAST),
+    };
+    (builder.build(), fields)
+}
+
+fn search_error(err: tantivy::TantivyError) -> String {
+    format!("search index error - {:?}", err)
+}
+
+impl SearchIndex {
+    pub fn global() -> &'static SearchIndex {
+        Self::try_global().expect("can't open search index")
+    }
+
+    pub fn try_global() -> Result<&'static SearchIndex, String> {
+        SEARCH_INDEX.as_ref().map_err(|err| err.clone())
+    }
+
+    // Opens the index and starts the background committer. Call once at startup so a bad
+    // SEARCH_INDEX_DIR fails fast instead of on the first message.
+    pub fn start() {
+        Self::global();
+        thread::spawn(|| loop {
+            thread::sleep(Duration::from_millis(COMMIT_INTERVAL_MS));
+            if let Err(err) = Self::global().commit_if_dirty() {
+                println!("can't commit search index, error - {:?}", &err);
+            }
+        });
+    }
+
+    fn open(dir: &str) -> Result<SearchIndex, String> {
+        fs::create_dir_all(dir).map_err(|err| format!("can't create {}, error - {:?}", dir, err))?;
+        let (schema, fields) = schema();
+        let directory = MmapDirectory::open(dir).map_err(|err| format!("can't open {}, error - {:?}", dir, err))?;
+        let index = Index::open_or_create(directory, schema).map_err(search_error)?;
+        let reader = index.reader_builder().reload_policy(ReloadPolicy::OnCommitWithDelay).try_into().map_err(search_error)?;
+        // only one process can hold the writer, reindex-search can't run next to the server
+        let writer = index.writer(WRITER_HEAP_BYTES).map_err(|err| match err {
+            tantivy::TantivyError::LockFailure(LockError::LockBusy, _) => format!("search index {} is locked by another process, stop the server first", dir),
+            err => search_error(err),
+        })?;
+        Ok(SearchIndex { index, reader, writer: Mutex::new(writer), fields, dirty: AtomicBool::new(false) })
+    }
+
+    // Makes pending writes searchable now instead of at the next background commit.
+    pub fn commit_if_dirty(&self) -> Result<(), String> {
+        if self.dirty.swap(false, Ordering::SeqCst) {
+            self.writer.lock().unwrap().commit().map_err(search_error)?;
+        }
+        Ok(())
+    }
+
+    // Adds the message or replaces an earlier version of it.
+    pub fn index_message(&self, msg: IndexedMessage) -> Result<(), String> {
+        let writer = self.writer.lock().unwrap();
+        writer.delete_term(Term::from_field_text(self.fields.msg_id, &msg.msg_id));
+        writer.add_document(doc!(
+            self.fields.msg_id => msg.msg_id,
+            self.fields.app_id => msg.app_id,
+            self.fields.room_id => msg.room_id,
+            self.fields.msg_owner => msg.msg_owner,
+            self.fields.msg_time => msg.msg_time,
+            self.fields.content => msg.content,
+            self.fields.created_at => msg.created_at,
+        )).map_err(search_error)?;
+        self.dirty.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Drops every document, readers keep seeing them until the next commit.
+    pub fn remove_all(&self) -> Result<(), String> {
+        self.writer.lock().unwrap().delete_all_documents().map_err(search_error)?;
+        self.dirty.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn remove_message(&self, msg_id: &str) {
+        self.writer.lock().unwrap().delete_term(Term::from_field_text(self.fields.msg_id, msg_id));
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    // Best matches first, returns the page and the total number of matches.
+    pub fn search(&self, text: &str, filter: SearchFilter, limit: usize, offset: usize) -> Result<(Vec<SearchHit>, usize), String> {
+        let searcher = self.reader.searcher();
+        let text_query = QueryParser::for_index(&self.index, vec![self.fields.content])
+            .parse_query(text)
+            .map_err(|err| format!("invalid search query - {:?}", err))?;
+        let room_queries: Vec<(Occur, Box<dyn Query>)> = filter.room_ids.iter()
+            .map(|room_id| (Occur::Should, Box::new(TermQuery::new(Term::from_field_text(self.fields.room_id, room_id), IndexRecordOption::Basic)) as Box<dyn Query>))
+            .collect();
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![
+            (Occur::Must, text_query.box_clone()),
+            (Occur::Must, Box::new(TermQuery::new(Term::from_field_i64(self.fields.app_id, filter.app_id), IndexRecordOption::Basic))),
+            (Occur::Must, Box::new(BooleanQuery::new(room_queries))),
+        ];
+        if let Some(sender) = filter.sender {
+            clauses.push((Occur::Must, Box::new(TermQuery::new(Term::from_field_text(self.fields.msg_owner, &sender), IndexRecordOption::Basic))));
+        }
+        if filter.from.is_some() || filter.to.is_some() {
+            let lower = filter.from.map_or(Bound::Unbounded, Bound::Included);
+            let upper = filter.to.map_or(Bound::Unbounded, Bound::Excluded);
+            clauses.push((Occur::Must, Box::new(RangeQuery::new_i64_bounds("created_at".to_string(), lower, upper))));
+        }
+        let query = BooleanQuery::new(clauses);
+        let (top_docs, total) = searcher.search(&query, &(TopDocs::with_limit(limit).and_offset(offset), Count)).map_err(search_error)?;
+        let mut snippet_generator = SnippetGenerator::create(&searcher, &*text_query, self.fields.content).map_err(search_error)?;
+        snippet_generator.set_max_num_chars(SNIPPET_MAX_CHARS);
+        let mut hits: Vec<SearchHit> = vec![];
+        for (score, address) in top_docs {
+            let doc: TantivyDocument = searcher.doc(address).map_err(search_error)?;
+            let text_of = |field: Field| doc.get_first(field).and_then(|value| value.as_str()).unwrap_or("").to_string();
+            hits.push(SearchHit {
+                room_id: text_of(self.fields.room_id),
+                msg_id: text_of(self.fields.msg_id),
+                msg_time: text_of(self.fields.msg_time),
+                msg_owner: text_of(self.fields.msg_owner),
+                created_at: doc.get_first(self.fields.created_at).and_then(|value| value.as_i64()).unwrap_or(0),
+                snippet: snippet_generator.snippet_from_doc(&doc).to_html(),
+                score,
+            });
+        }
+        Ok((hits, total))
+    }
+}
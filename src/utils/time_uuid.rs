@@ -0,0 +1,91 @@
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::{v1::Context, Uuid};
+
+// 100ns ticks between the uuid epoch 1582-10-15 and the unix epoch
+const UUID_TICKS_BETWEEN_EPOCHS: u64 = 0x01B2_1DD2_1381_4000;
+
+// a client send_at further than this from the server clock is replaced by the server time
+pub const MAX_CLIENT_CLOCK_SKEW_MS: i64 = 5 * 60 * 1000;
+
+lazy_static::lazy_static! {
+    static ref CONTEXT: Context = Context::new(0);
+    // node part of the generated ids, random per process
+    static ref NODE_ID: [u8; 6] = {
+        let bytes = *Uuid::new_v4().as_bytes();
+        [bytes[10], bytes[11], bytes[12], bytes[13], bytes[14], bytes[15]]
+    };
+    // last issued timestamp, ids keep increasing when the system clock steps back
+    static ref LAST_TICKS: Mutex<u64> = Mutex::new(0);
+}
+
+// Version 1 uuid of the current server time, Cassandra orders timeuuid columns by it.
+pub fn now() -> Uuid {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut last_ticks = LAST_TICKS.lock().unwrap();
+    let ticks = std::cmp::max(now.as_secs() * 10_000_000 + u64::from(now.subsec_nanos() / 100), *last_ticks + 1);
+    *last_ticks = ticks;
+    Uuid::new_v1(&*CONTEXT, ticks / 10_000_000, ((ticks % 10_000_000) * 100) as u32, &NODE_ID[..]).expect("6 byte node id")
+}
+
+// Version 1 uuid at a past time, the same millis and seed always give the same uuid.
+pub fn at_millis(millis: i64, seed: &Uuid) -> Uuid {
+    let bytes = seed.as_bytes();
+    let context = Context::new(u16::from(bytes[8]) << 8 | u16::from(bytes[9]));
+    let millis = std::cmp::max(millis, 0) as u64;
+    Uuid::new_v1(&context, millis / 1000, ((millis % 1000) * 1_000_000) as u32, &bytes[10..16]).expect("6 byte node id")
+}
+
+// 100ns ticks of a time uuid, the order Cassandra sorts timeuuid columns in
+pub fn ticks(id: &Uuid) -> u64 {
+    id.to_timestamp().map(|(ticks, _)| ticks).unwrap_or(0)
+}
+
+// unix millis of a time uuid, 0 for other uuid versions
+pub fn timestamp_millis(id: &Uuid) -> i64 {
+    id.to_timestamp()
+        .map(|(ticks, _)| (ticks.saturating_sub(UUID_TICKS_BETWEEN_EPOCHS) / 10_000) as i64)
+        .unwrap_or(0)
+}
+
+// The client send_at is only kept as a display hint, a skewed client clock falls back to the server time.
+pub fn send_at_hint(client_send_at: i64, msg_time: &Uuid) -> i64 {
+    let server_send_at = timestamp_millis(msg_time);
+    if (client_send_at - server_send_at).abs() > MAX_CLIENT_CLOCK_SKEW_MS {
+        server_send_at
+    } else {
+        client_send_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn now_keeps_increasing() {
+        let mut last = ticks(&now());
+        for _ in 0..1000 {
+            let next = ticks(&now());
+            assert!(next > last);
+            last = next;
+        }
+    }
+
+    #[test]
+    fn at_millis_is_stable_for_the_same_seed() {
+        let seed = Uuid::new_v4();
+        assert_eq!(at_millis(1_500_000_000_123, &seed), at_millis(1_500_000_000_123, &seed));
+        assert_eq!(timestamp_millis(&at_millis(1_500_000_000_123, &seed)), 1_500_000_000_123);
+    }
+
+    #[test]
+    fn send_at_hint_falls_back_to_server_time_on_skew() {
+        let msg_time = now();
+        let server_send_at = timestamp_millis(&msg_time);
+        assert_eq!(send_at_hint(server_send_at - 1000, &msg_time), server_send_at - 1000);
+        assert_eq!(send_at_hint(server_send_at + MAX_CLIENT_CLOCK_SKEW_MS, &msg_time), server_send_at + MAX_CLIENT_CLOCK_SKEW_MS);
+        assert_eq!(send_at_hint(server_send_at + MAX_CLIENT_CLOCK_SKEW_MS + 1, &msg_time), server_send_at);
+        assert_eq!(send_at_hint(0, &msg_time), server_send_at);
+    }
+}